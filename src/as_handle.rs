@@ -1,5 +1,9 @@
-use windows_sys::Win32::Foundation::HANDLE;
+#[cfg(windows)]
+pub type RawHandle = windows_sys::Win32::Foundation::HANDLE;
+
+#[cfg(target_os = "linux")]
+pub type RawHandle = std::os::fd::RawFd;
 
 pub trait AsHandle {
-    fn as_handle(&self) -> HANDLE;
+    fn as_handle(&self) -> RawHandle;
 }
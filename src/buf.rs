@@ -0,0 +1,522 @@
+use std::{
+    any::Any,
+    ops::{Bound, RangeBounds},
+    slice,
+};
+
+/// A buffer an operation can send from, owned by the operation until it completes.
+///
+/// The buffer is type erased while the operation is in flight and comes back as the
+/// same type from `Completion::into_buf`, slices included.
+///
+/// # Safety
+///
+/// `stable_ptr` must point at `bytes_total` bytes, of which the first `bytes_init` are
+/// initialized, and stay valid as long as the buffer is neither moved nor touched
+/// through its own methods.
+pub unsafe trait IoBuf: Send + 'static {
+    fn stable_ptr(&self) -> *const u8;
+
+    /// How many bytes are initialized, what a write sends.
+    fn bytes_init(&self) -> usize;
+
+    /// How many bytes fit, what a read may fill.
+    fn bytes_total(&self) -> usize;
+
+    /// Restrict operations to `range` of the buffer, `Slice::into_inner` gives it back.
+    ///
+    /// # Panics
+    ///
+    /// If the range ends past `bytes_total`.
+    fn slice(self, range: impl RangeBounds<usize>) -> Slice<Self>
+    where
+        Self: Sized,
+    {
+        let begin = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.bytes_total(),
+        };
+        assert!(begin <= end, "slice starts at {begin} but ends at {end}");
+        assert!(
+            end <= self.bytes_total(),
+            "slice ends at {end} but the buffer holds {}",
+            self.bytes_total()
+        );
+
+        Slice {
+            buf: self,
+            begin,
+            end,
+        }
+    }
+}
+
+/// A buffer an operation can receive into.
+///
+/// # Safety
+///
+/// `stable_mut_ptr` must be `stable_ptr` and writable up to `bytes_total`.
+pub unsafe trait IoBufMut: IoBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8;
+
+    /// A read filled the first `pos` bytes, grow what `bytes_init` reports to that.
+    ///
+    /// # Safety
+    ///
+    /// The first `pos` bytes must be initialized.
+    unsafe fn set_init(&mut self, pos: usize);
+}
+
+unsafe impl IoBuf for Vec<u8> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+}
+
+unsafe impl IoBufMut for Vec<u8> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        if pos > self.len() {
+            self.set_len(pos);
+        }
+    }
+}
+
+unsafe impl IoBuf for Box<[u8]> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.len()
+    }
+}
+
+unsafe impl IoBufMut for Box<[u8]> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {}
+}
+
+/// Arrays are boxed with the operation, they stay put while it is in flight.
+unsafe impl<const N: usize> IoBuf for [u8; N] {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        N
+    }
+
+    fn bytes_total(&self) -> usize {
+        N
+    }
+}
+
+unsafe impl<const N: usize> IoBufMut for [u8; N] {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {}
+}
+
+unsafe impl IoBuf for &'static [u8] {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBuf for bytes::Bytes {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBuf for bytes::BytesMut {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBufMut for bytes::BytesMut {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        if pos > self.len() {
+            self.set_len(pos);
+        }
+    }
+}
+
+/// The range `begin..end` of a buffer, see `IoBuf::slice`.
+pub struct Slice<T> {
+    buf: T,
+    begin: usize,
+    end: usize,
+}
+
+impl<T> Slice<T> {
+    /// Where the range starts in the buffer.
+    pub fn begin(&self) -> usize {
+        self.begin
+    }
+
+    /// Where the range ends in the buffer.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.buf
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.buf
+    }
+
+    /// The whole buffer.
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+}
+
+unsafe impl<T: IoBuf> IoBuf for Slice<T> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.buf.stable_ptr().wrapping_add(self.begin)
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.buf.bytes_init().clamp(self.begin, self.end) - self.begin
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.end - self.begin
+    }
+}
+
+unsafe impl<T: IoBufMut> IoBufMut for Slice<T> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.stable_mut_ptr().wrapping_add(self.begin)
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        self.buf.set_init(self.begin + pos);
+    }
+}
+
+/// The buffer of a Context, type erased.
+pub(crate) struct Buffer {
+    owner: Box<dyn Any + Send>,
+    /// What an operation reads from or writes to, inside `owner`.
+    ptr: *mut u8,
+    len: usize,
+    /// How many bytes from `ptr` on are initialized.
+    init: usize,
+    /// Passes `init` on to the owner after a read, `None` for buffers only sent from.
+    set_init: Option<unsafe fn(&mut (dyn Any + Send), usize)>,
+}
+
+// `ptr` points into `owner`, which is `Send`.
+unsafe impl Send for Buffer {}
+
+unsafe fn set_init<B: IoBufMut>(owner: &mut (dyn Any + Send), pos: usize) {
+    if let Some(buf) = owner.downcast_mut::<B>() {
+        buf.set_init(pos);
+    }
+}
+
+impl Buffer {
+    /// A buffer to receive into, up to `bytes_total`.
+    pub(crate) fn recv<B: IoBufMut>(buf: B) -> Self {
+        // Boxed first, so the pointer is taken where the buffer stays.
+        let mut owner = Box::new(buf);
+        let ptr = owner.stable_mut_ptr();
+        let (len, init) = (owner.bytes_total(), owner.bytes_init());
+
+        Self {
+            owner,
+            ptr,
+            len,
+            init,
+            set_init: Some(set_init::<B>),
+        }
+    }
+
+    /// A buffer to send from, its `bytes_init` bytes.
+    pub(crate) fn send<B: IoBuf>(buf: B) -> Self {
+        let owner = Box::new(buf);
+        let ptr = owner.stable_ptr() as *mut u8;
+        let len = owner.bytes_init();
+
+        Self {
+            owner,
+            ptr,
+            len,
+            init: len,
+            set_init: None,
+        }
+    }
+
+    /// No buffer, for operations that do not transfer bytes through the Context's own.
+    pub(crate) fn empty() -> Self {
+        Self::send(&[][..])
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// How many bytes an operation transfers at most, a single call takes a `u32`.
+    pub(crate) fn len(&self) -> u32 {
+        self.len.min(u32::MAX as usize) as u32
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.init) }
+    }
+
+    /// Empty for buffers only sent from, those may be shared.
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self.set_init {
+            Some(_) => unsafe { slice::from_raw_parts_mut(self.ptr, self.init) },
+            None => &mut [],
+        }
+    }
+
+    /// A read filled the first `pos` bytes.
+    pub(crate) fn set_init(&mut self, pos: usize) {
+        let pos = pos.min(self.len);
+        if let Some(set_init) = self.set_init {
+            if pos > self.init {
+                unsafe { set_init(&mut *self.owner, pos) };
+                self.init = pos;
+            }
+        }
+    }
+
+    pub(crate) fn downcast<B: 'static>(self) -> Result<B, Self> {
+        if self.owner.is::<B>() {
+            Ok(*self.owner.downcast::<B>().unwrap())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// The buffer as a vector, copied unless it is one.
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        self.downcast::<Vec<u8>>()
+            .unwrap_or_else(|buffer| buffer.as_slice().to_vec())
+    }
+}
+
+/// One entry of the scatter/gather array the kernel takes, `iovec` or `WSABUF`.
+#[cfg(target_os = "linux")]
+pub(crate) type RawSlice = libc::iovec;
+#[cfg(windows)]
+pub(crate) type RawSlice = windows_sys::Win32::Networking::WinSock::WSABUF;
+
+#[cfg(target_os = "linux")]
+fn raw_slice(buffer: &mut Buffer) -> RawSlice {
+    libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len() as usize,
+    }
+}
+
+#[cfg(windows)]
+fn raw_slice(buffer: &mut Buffer) -> RawSlice {
+    RawSlice {
+        len: buffer.len(),
+        buf: buffer.as_mut_ptr(),
+    }
+}
+
+/// The buffers of a vectored operation and the scatter/gather array pointing into them,
+/// which stays put with the Context until the operation completes.
+pub(crate) struct Vectored {
+    buffers: Vec<Buffer>,
+    raw: Vec<RawSlice>,
+}
+
+// The array points into `buffers`.
+unsafe impl Send for Vectored {}
+
+impl Vectored {
+    pub(crate) fn new(mut buffers: Vec<Buffer>) -> Self {
+        let raw = buffers.iter_mut().map(raw_slice).collect();
+        Self { buffers, raw }
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut RawSlice {
+        self.raw.as_mut_ptr()
+    }
+
+    /// How many buffers there are, the kernel takes a `u32`.
+    pub(crate) fn len(&self) -> u32 {
+        self.raw.len().min(u32::MAX as usize) as u32
+    }
+
+    /// Every buffer as pointer and length, in order.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (*mut u8, usize)> + '_ {
+        self.buffers
+            .iter_mut()
+            .map(|buffer| (buffer.as_mut_ptr(), buffer.len() as usize))
+    }
+
+    /// A read filled `n` bytes, the buffers in order.
+    pub(crate) fn set_init(&mut self, mut n: usize) {
+        for buffer in &mut self.buffers {
+            let len = buffer.len() as usize;
+            buffer.set_init(n.min(len));
+            n = n.saturating_sub(len);
+        }
+    }
+
+    pub(crate) fn downcast<B: 'static>(self) -> Result<Vec<B>, Self> {
+        if !self.buffers.iter().all(|buffer| buffer.owner.is::<B>()) {
+            return Err(self);
+        }
+
+        Ok(self
+            .buffers
+            .into_iter()
+            .map(|buffer| buffer.downcast().ok().unwrap())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fs::FileExt, net::TcpStreamExt, sim::SimPort, CompletionPort};
+
+    use super::{IoBuf, Slice};
+
+    #[test]
+    fn slices_come_back() {
+        let sim = SimPort::new(1);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        // Only the middle of the buffer is read into and only the tail of the other sent.
+        a.read(vec![b'-'; 8].slice(2..6)).unwrap();
+        TcpStreamExt::write(&b, *b"xxping").unwrap();
+        TcpStreamExt::write(&b, (*b"__ping").slice(2..)).unwrap();
+
+        let mut read = None;
+        while read.is_none() {
+            let completion = cmp.get(None).ok().unwrap();
+            if completion.token() == 1 {
+                read = Some(completion);
+            }
+        }
+        let read = read.unwrap();
+        assert_eq!(read.data(), b"xxpi");
+        let buf = read.into_buf::<Slice<Vec<u8>>>().ok().unwrap();
+        assert_eq!((buf.begin(), buf.end()), (2, 6));
+        assert_eq!(buf.into_inner(), b"--xxpi--");
+    }
+
+    #[test]
+    fn vectored_in_order() {
+        let sim = SimPort::new(3);
+        sim.set_partial_transfers(true);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        let sent = b"header|payload";
+        b.write_vectored(vec![&sent[..7], &sent[7..]]).unwrap();
+        let n = cmp.get(None).ok().unwrap().bytes_used() as usize;
+
+        a.read_vectored(vec![Vec::with_capacity(4), Vec::with_capacity(32)])
+            .unwrap();
+        let completion = cmp.get(None).ok().unwrap();
+        let m = completion.bytes_used() as usize;
+        assert!(completion.data().is_empty());
+        let bufs = completion.into_bufs::<Vec<u8>>().ok().unwrap();
+        // Filled in order, only as far as the bytes received.
+        assert_eq!(bufs[0].len(), m.min(4));
+        assert_eq!(bufs.concat(), &sent[..m.min(n)]);
+    }
+
+    #[test]
+    fn reads_fill_spare_capacity() {
+        let sim = SimPort::new(2);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let mut file = sim.file(b"hello".to_vec());
+        cmp.add(1, &file).unwrap();
+
+        file.read_at(Vec::<u8>::with_capacity(16), 0).unwrap();
+        let buf = cmp
+            .get(None)
+            .ok()
+            .unwrap()
+            .into_buf::<Vec<u8>>()
+            .ok()
+            .unwrap();
+        assert_eq!(buf, b"hello");
+
+        file.read_at(Box::<[u8]>::from([0; 3]), 1).unwrap();
+        let completion = cmp.get(None).ok().unwrap();
+        // Only ever the type it was submitted as.
+        let completion = completion.into_buf::<Vec<u8>>().err().unwrap();
+        let buf = completion.into_buf::<Box<[u8]>>().ok().unwrap();
+        assert_eq!(&*buf, b"ell");
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use crate::{IoBuf, IoBufMut};
+
+/// What a `BufferPool` has handed out and kept so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The bytes of every buffer the pool allocated and did not free, in use or idle.
+    pub allocated_bytes: usize,
+    /// The bytes of the buffers waiting to be handed out again.
+    pub idle_bytes: usize,
+    /// Buffers handed out right now.
+    pub in_use: usize,
+    /// `get` calls served with an idle buffer.
+    pub hits: u64,
+    /// `get` calls that allocated.
+    pub misses: u64,
+    /// `get` calls that found no size class or would have gone over the cap.
+    pub refused: u64,
+}
+
+struct Class {
+    size: usize,
+    idle: Vec<Box<[u8]>>,
+}
+
+struct State {
+    classes: Vec<Class>,
+    max_bytes: usize,
+    stats: PoolStats,
+}
+
+impl State {
+    /// Free idle buffers of other classes until `size` more bytes fit under the cap.
+    fn make_room(&mut self, size: usize, keep: usize) -> bool {
+        for (index, class) in self.classes.iter_mut().enumerate() {
+            if index == keep {
+                continue;
+            }
+            while self.stats.allocated_bytes + size > self.max_bytes {
+                match class.idle.pop() {
+                    Some(buf) => {
+                        self.stats.allocated_bytes -= buf.len();
+                        self.stats.idle_bytes -= buf.len();
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        self.stats.allocated_bytes + size <= self.max_bytes
+    }
+}
+
+/// Fixed-size buffers for reads, handed back to the pool when they are dropped, usually
+/// along with the Context of the operation they were read into.
+///
+/// Every buffer comes from the smallest size class that holds what was asked for. The
+/// pool allocates up to `max_bytes` in total, once there it frees idle buffers of other
+/// classes to make room and refuses if that is not enough.
+///
+/// ```
+/// use iocp_rs::{net::TcpStreamExt, sim::SimPort, BufferPool, CompletionPort, PooledBuf};
+///
+/// let sim = SimPort::new(0);
+/// let cmp = CompletionPort::with_driver(sim.clone());
+/// let (mut a, b) = sim.socket_pair();
+/// cmp.add(1, &a).unwrap();
+/// cmp.add(2, &b).unwrap();
+///
+/// let pool = BufferPool::new(&[512, 4096], 1 << 20);
+/// a.read(pool.get(100).unwrap()).unwrap();
+/// b.write(b"hello".to_vec()).unwrap();
+///
+/// let completion = loop {
+///     let completion = cmp.get(None).ok().unwrap();
+///     if completion.token() == 1 {
+///         break completion;
+///     }
+/// };
+/// assert_eq!(completion.data(), b"hello");
+/// assert_eq!(pool.stats().in_use, 1);
+///
+/// // Dropping the completion gives the buffer back.
+/// drop(completion);
+/// assert_eq!(pool.stats().in_use, 0);
+/// assert_eq!(pool.stats().idle_bytes, 512);
+/// ```
+#[derive(Clone)]
+pub struct BufferPool {
+    state: Arc<Mutex<State>>,
+}
+
+impl BufferPool {
+    /// A pool with a size class for each of `sizes`, allocating at most `max_bytes`.
+    pub fn new(sizes: &[usize], max_bytes: usize) -> Self {
+        let mut sizes = sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        let classes = sizes
+            .into_iter()
+            .map(|size| Class {
+                size,
+                idle: Vec::new(),
+            })
+            .collect();
+
+        Self {
+            state: Arc::new(Mutex::new(State {
+                classes,
+                max_bytes,
+                stats: PoolStats::default(),
+            })),
+        }
+    }
+
+    /// An empty buffer of at least `size` bytes capacity, `None` if no size class is that
+    /// large or the pool is at its cap.
+    pub fn get(&self, size: usize) -> Option<PooledBuf> {
+        let mut state = self.state.lock().unwrap();
+        let class = match state.classes.iter().position(|class| class.size >= size) {
+            Some(class) => class,
+            None => {
+                state.stats.refused += 1;
+                return None;
+            }
+        };
+
+        let buf = match state.classes[class].idle.pop() {
+            Some(buf) => {
+                state.stats.idle_bytes -= buf.len();
+                state.stats.hits += 1;
+                buf
+            }
+            None => {
+                let size = state.classes[class].size;
+                if !state.make_room(size, class) {
+                    state.stats.refused += 1;
+                    return None;
+                }
+                state.stats.allocated_bytes += size;
+                state.stats.misses += 1;
+                vec![0; size].into_boxed_slice()
+            }
+        };
+        state.stats.in_use += 1;
+
+        Some(PooledBuf {
+            buf,
+            len: 0,
+            class,
+            pool: self.state.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Free every idle buffer.
+    pub fn shrink(&self) {
+        let mut state = self.state.lock().unwrap();
+        let freed = state
+            .classes
+            .iter_mut()
+            .flat_map(|class| class.idle.drain(..))
+            .map(|buf| buf.len())
+            .sum::<usize>();
+        state.stats.allocated_bytes -= freed;
+        state.stats.idle_bytes -= freed;
+    }
+}
+
+/// A buffer from a `BufferPool`, it goes back to the pool when dropped.
+///
+/// It derefs to the bytes filled so far, by a read or by `extend_from_slice`.
+pub struct PooledBuf {
+    buf: Box<[u8]>,
+    len: usize,
+    class: usize,
+    pool: Arc<Mutex<State>>,
+}
+
+impl PooledBuf {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size of its class.
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Append as much of `data` as fits, returns how much that was.
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        n
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf")
+            .field("len", &self.len)
+            .field("capacity", &self.buf.len())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        let mut state = self.pool.lock().unwrap();
+        state.stats.in_use -= 1;
+        state.stats.idle_bytes += buf.len();
+        state.classes[self.class].idle.push(buf);
+    }
+}
+
+// The boxed slice stays put when the PooledBuf moves, all of it is initialized.
+unsafe impl IoBuf for PooledBuf {
+    fn stable_ptr(&self) -> *const u8 {
+        self.buf.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+unsafe impl IoBufMut for PooledBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        self.len = self.len.max(pos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fs::FileExt, sim::SimPort, CompletionPort};
+
+    use super::{BufferPool, PoolStats, PooledBuf};
+
+    #[test]
+    fn size_classes_and_reuse() {
+        let pool = BufferPool::new(&[1024, 64, 256], 4096);
+        assert_eq!(pool.get(1).unwrap().capacity(), 64);
+        assert_eq!(pool.get(65).unwrap().capacity(), 256);
+        assert!(pool.get(1025).is_none());
+
+        // The 64 byte buffer dropped above is handed out again.
+        let buf = pool.get(64).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                allocated_bytes: 320,
+                idle_bytes: 256,
+                in_use: 1,
+                hits: 1,
+                misses: 2,
+                refused: 1,
+            }
+        );
+
+        drop(buf);
+        pool.shrink();
+        assert_eq!(pool.stats().allocated_bytes, 0);
+    }
+
+    #[test]
+    fn cap_frees_idle_buffers() {
+        let pool = BufferPool::new(&[100, 200], 300);
+        let small = [pool.get(100).unwrap(), pool.get(100).unwrap()];
+        let big = pool.get(200);
+        assert!(big.is_none());
+
+        // Idle small buffers make room for a big one, buffers in use do not.
+        drop(small);
+        let big = pool.get(200).unwrap();
+        assert_eq!(pool.stats().allocated_bytes, 300);
+        assert!(pool.get(200).is_none());
+        assert_eq!(pool.get(100).unwrap().capacity(), 100);
+        drop(big);
+    }
+
+    #[test]
+    fn reclaimed_with_the_completion() {
+        let sim = SimPort::new(1);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let mut file = sim.file(b"pooled".to_vec());
+        cmp.add(1, &file).unwrap();
+        let pool = BufferPool::new(&[16], 64);
+
+        file.read_at(pool.get(16).unwrap(), 0).unwrap();
+        let completion = cmp.get(None).ok().unwrap();
+        assert_eq!(pool.stats().in_use, 1);
+        let mut buf = completion.into_buf::<PooledBuf>().ok().unwrap();
+        assert_eq!(&buf[..], b"pooled");
+
+        // Writing from it works the same.
+        buf.clear();
+        buf.extend_from_slice(b"POOL");
+        file.write_at(buf, 0).unwrap();
+        drop(cmp.get(None).ok().unwrap());
+        assert_eq!(file.contents(), b"POOLed");
+        assert_eq!(pool.stats().in_use, 0);
+        assert_eq!(pool.stats().hits + pool.stats().misses, 1);
+    }
+}
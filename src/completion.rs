@@ -0,0 +1,280 @@
+use std::{any::Any, io::Result, net::SocketAddr};
+
+use crate::{
+    context::{OpState, OperationId},
+    net::{Accepted, Ancillary},
+    Context, OperationalResult, TimerId,
+};
+
+enum Payload {
+    Operation(Box<Context>),
+    Message(Box<dyn Any + Send>),
+    Timer(TimerId),
+    Posted,
+}
+
+/// A completed operation, returned by `CompletionPort::get` and `CompletionPort::get_many`.
+///
+/// It owns the Context the operation was submitted with, so the buffer, `IOType` and
+/// offset come back with the result and no token bookkeeping is needed to find them.
+/// A message sent with `CompletionPort::post_message` or a `Notifier` comes back as a
+/// completion that carries the value instead, an expired timer carries its `TimerId` and
+/// posted results carry neither.
+pub struct Completion {
+    result: OperationalResult,
+    payload: Payload,
+}
+
+impl Completion {
+    pub(crate) fn new(mut result: OperationalResult, context: Option<Box<Context>>) -> Self {
+        let payload = match context {
+            Some(mut context) => {
+                context.complete(&mut result);
+                Payload::Operation(context)
+            }
+            None => Payload::Posted,
+        };
+
+        Self { result, payload }
+    }
+
+    pub(crate) fn with_message(result: OperationalResult, message: Box<dyn Any + Send>) -> Self {
+        Self {
+            result,
+            payload: Payload::Message(message),
+        }
+    }
+
+    pub(crate) fn with_timer(token: usize, timer: TimerId) -> Self {
+        Self {
+            result: OperationalResult::new(token, 0, 0, 0),
+            payload: Payload::Timer(timer),
+        }
+    }
+
+    pub fn token(&self) -> usize {
+        self.result.token()
+    }
+
+    pub fn bytes_used(&self) -> u32 {
+        self.result.bytes_used()
+    }
+
+    /// The operation that completed, `None` for messages, timers and posted results.
+    pub fn id(&self) -> Option<OperationId> {
+        self.context().map(Context::id)
+    }
+
+    pub fn result(&self) -> &OperationalResult {
+        &self.result
+    }
+
+    /// The bytes the operation transferred, or the error it failed with.
+    pub fn status(&self) -> Result<u32> {
+        self.result.result()
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        match &self.payload {
+            Payload::Operation(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    pub fn context_mut(&mut self) -> Option<&mut Context> {
+        match &mut self.payload {
+            Payload::Operation(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The bytes the operation transferred, the start of its buffer.
+    /// Empty for vectored operations, their bytes are in the buffers of `into_bufs`.
+    pub fn data(&self) -> &[u8] {
+        match self.context() {
+            Some(context) => {
+                let len = (self.bytes_used() as usize).min(context.get_buff().len());
+                &context.get_buff()[..len]
+            }
+            None => &[],
+        }
+    }
+
+    pub fn into_context(self) -> Option<Context> {
+        match self.payload {
+            Payload::Operation(context) => Some(*context),
+            _ => None,
+        }
+    }
+
+    pub fn into_parts(self) -> (OperationalResult, Option<Context>) {
+        match self.payload {
+            Payload::Operation(context) => (self.result, Some(*context)),
+            _ => (self.result, None),
+        }
+    }
+
+    /// Take the buffer of the operation out of this completion as the type it was
+    /// submitted as, or get the completion back if it is not a `B` or not an operation.
+    pub fn into_buf<B: 'static>(self) -> std::result::Result<B, Self> {
+        match self.payload {
+            Payload::Operation(context) => match context.into_buf() {
+                Ok(buf) => Ok(buf),
+                Err(context) => Err(Self {
+                    result: self.result,
+                    payload: Payload::Operation(context),
+                }),
+            },
+            payload => Err(Self {
+                result: self.result,
+                payload,
+            }),
+        }
+    }
+
+    /// The connection an accept completed with, `None` for other operations and for
+    /// accepts that failed.
+    pub fn accepted(&self) -> Option<&Accepted> {
+        match &self.context()?.state {
+            OpState::Accepted(accepted) => Some(accepted),
+            _ => None,
+        }
+    }
+
+    /// The address the datagram of a `recv_from` or `recv_msg` came from, `None` for other
+    /// operations and for receives that failed.
+    pub fn source_addr(&self) -> Option<SocketAddr> {
+        match &self.context()?.state {
+            OpState::Source(addr) => Some(*addr),
+            OpState::Message(message) => message.source,
+            _ => None,
+        }
+    }
+
+    /// The control messages the datagram of a `recv_msg` came with, `None` for other
+    /// operations and for receives that failed.
+    pub fn ancillary(&self) -> Option<&Ancillary> {
+        match &self.context()?.state {
+            OpState::Message(message) => Some(&message.ancillary),
+            _ => None,
+        }
+    }
+
+    /// Take the connection out of the completion of an accept, or get the completion back
+    /// if it did not accept one.
+    pub fn into_accepted(self) -> std::result::Result<Accepted, Self> {
+        match self.payload {
+            Payload::Operation(context) => match context.into_accepted() {
+                Ok(accepted) => Ok(accepted),
+                Err(context) => Err(Self {
+                    result: self.result,
+                    payload: Payload::Operation(context),
+                }),
+            },
+            payload => Err(Self {
+                result: self.result,
+                payload,
+            }),
+        }
+    }
+
+    /// Like `into_buf`, for the buffers of a vectored operation.
+    pub fn into_bufs<B: 'static>(self) -> std::result::Result<Vec<B>, Self> {
+        match self.payload {
+            Payload::Operation(context) => match context.into_bufs() {
+                Ok(bufs) => Ok(bufs),
+                Err(context) => Err(Self {
+                    result: self.result,
+                    payload: Payload::Operation(context),
+                }),
+            },
+            payload => Err(Self {
+                result: self.result,
+                payload,
+            }),
+        }
+    }
+
+    /// Whether this completion carries a message rather than an operation.
+    pub fn is_message(&self) -> bool {
+        matches!(self.payload, Payload::Message(_))
+    }
+
+    /// The timer that expired, `None` unless this completion is a timer.
+    pub fn timer(&self) -> Option<TimerId> {
+        match self.payload {
+            Payload::Timer(timer) => Some(timer),
+            _ => None,
+        }
+    }
+
+    /// Whether this completion is an expired timer.
+    pub fn is_timer(&self) -> bool {
+        matches!(self.payload, Payload::Timer(_))
+    }
+
+    /// The message this completion carries, if it is a `T`.
+    pub fn message<T: 'static>(&self) -> Option<&T> {
+        match &self.payload {
+            Payload::Message(message) => message.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Take the message out of this completion, or get the completion back if it does not
+    /// carry a `T`.
+    pub fn into_message<T: 'static>(self) -> std::result::Result<T, Self> {
+        match self.payload {
+            Payload::Message(message) => match message.downcast() {
+                Ok(message) => Ok(*message),
+                Err(message) => Err(Self {
+                    result: self.result,
+                    payload: Payload::Message(message),
+                }),
+            },
+            payload => Err(Self {
+                result: self.result,
+                payload,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{context::IOType, net::TcpStreamExt, sim::SimPort, CompletionPort};
+
+    #[test]
+    fn read_and_write_on_one_socket() {
+        let sim = SimPort::new(5);
+        sim.set_reordering(true);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        let read = a.read(vec![0; 4]).unwrap();
+        let write = TcpStreamExt::write(&a, b"pong".to_vec()).unwrap();
+        TcpStreamExt::write(&b, b"ping".to_vec()).unwrap();
+
+        let mut seen = 0;
+        while seen < 3 {
+            for completion in cmp.get_many(3, None).unwrap() {
+                seen += 1;
+                if completion.id() == Some(read) {
+                    assert_eq!(completion.token(), 1);
+                    assert!(matches!(
+                        completion.context().unwrap().io_type(),
+                        IOType::Read
+                    ));
+                    assert_eq!(completion.data(), b"ping");
+                } else if completion.id() == Some(write) {
+                    assert_eq!(completion.token(), 1);
+                    let context = completion.into_context().unwrap();
+                    assert!(matches!(context.io_type(), IOType::Write));
+                    assert_eq!(context.into_buff(), b"pong");
+                }
+            }
+        }
+    }
+}
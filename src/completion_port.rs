@@ -1,162 +1,930 @@
-use crate::{
-    cvt,
-    utils::{dur_to_ms, len},
-    AsHandle, OperationalResult,
-};
 use std::{
-    io::{Error, Result},
-    mem::zeroed,
-    ptr::null_mut,
-    time::Duration,
-};
-use windows_sys::Win32::{
-    Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
-    System::IO::{
-        CreateIoCompletionPort, GetQueuedCompletionStatus, GetQueuedCompletionStatusEx,
-        PostQueuedCompletionStatus, OVERLAPPED_ENTRY,
+    any::Any,
+    collections::{HashMap, HashSet},
+    io::{Error, ErrorKind, Result},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, Weak,
     },
+    task::{Poll, Waker},
+    time::{Duration, Instant},
 };
 
-pub struct CompletionPort {
-    handle: HANDLE,
+use crate::{
+    buf::Buffer,
+    context::{IOType, OperationId},
+    driver::{Driver, Operation},
+    timer::TimerWheel,
+    AsHandle, Completion, Context, Notifier, OpFuture, OperationalResult, RawHandle, TimerId,
+};
+
+/// How long a dropped CompletionPort waits for a completion before it gives up on its orphans.
+const ORPHAN_GRACE: Duration = Duration::from_secs(1);
+
+/// What an entry of the timer wheel is for.
+#[derive(Clone, Copy)]
+enum Expiry {
+    /// A timer from `set_timer` or `set_interval`, it completes to the token.
+    Token(usize),
+    /// The deadline of an operation, which is cancelled and reported as timed out.
+    Deadline(OperationId),
 }
 
-impl CompletionPort {
-    /// Create a CompletionPort with specify then concurrent
-    pub fn new(num_threads: u32) -> Result<Self> {
-        let ret = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0, 0, num_threads) };
+/// Where the future of an awaited operation stands.
+enum Awaited {
+    Waiting(Option<Waker>),
+    Done(Completion),
+}
 
-        if ret == 0 {
-            Err(Error::last_os_error())
-        } else {
-            Ok(Self { handle: ret })
+/// A result taken out of `InFlight` along with what it completes, to be settled without
+/// holding its lock.
+enum Reaped {
+    Done(Completion),
+    /// An abandoned operation, dropped once its Context is settled.
+    Orphan(OperationalResult, Box<Context>),
+    /// An operation and its Context, with its id if a future waits for it.
+    Operation(OperationalResult, Option<Box<Context>>, Option<OperationId>),
+}
+
+/// The Contexts of submitted operations, which stay at their heap address until the
+/// kernel is done with them.
+#[derive(Default)]
+struct InFlight {
+    pending: HashMap<OperationId, NonNull<Context>>,
+    /// Abandoned operations, freed when their completion is reaped instead of returned.
+    orphans: HashMap<OperationId, NonNull<Context>>,
+    /// Values sent by `post_message`, picked up when their posted result is reaped.
+    messages: HashMap<OperationId, Box<dyn Any + Send>>,
+    /// Results posted to wake a waiter up for an earlier timer, never returned.
+    wakes: HashSet<OperationId>,
+    /// The deadline timers of pending operations.
+    deadlines: HashMap<OperationId, TimerId>,
+    /// Operations cancelled by their deadline, whose cancellation is reported as a timeout.
+    timed_out: HashSet<OperationId>,
+    /// Operations a future waits for, their completions go to the future instead of `get`.
+    awaited: HashMap<OperationId, Awaited>,
+}
+
+unsafe impl Send for InFlight {}
+
+impl InFlight {
+    /// Take the Context of the operation `id` back, `None` if it was abandoned.
+    /// An abandoned one is settled with `result` before it is dropped, so a connection it
+    /// accepted is closed.
+    fn take(&mut self, id: OperationId, result: &mut OperationalResult) -> Option<Box<Context>> {
+        if let Some(context) = self.orphans.remove(&id) {
+            unsafe { Box::from_raw(context.as_ptr()) }.complete(result);
+            return None;
         }
+
+        self.pending
+            .remove(&id)
+            .map(|context| unsafe { Box::from_raw(context.as_ptr()) })
     }
+}
 
-    /// Register a handle and token with CompletionPort.
-    /// The same token and handle cannot be registered in CompletionPort again unless the handle has been closed.
-    /// 
-    /// ```
-    /// 
-    /// ```
-    pub fn add<A: AsHandle>(&self, token: usize, io_object: &A) -> Result<()> {
-        let handle = io_object.as_handle();
+pub(crate) struct Inner {
+    driver: Box<dyn Driver>,
+    in_flight: Mutex<InFlight>,
+    next_id: AtomicU64,
+    timers: Mutex<TimerWheel<Expiry>>,
+    /// How many threads are in `wait`, which a new earliest timer has to wake up.
+    waiters: AtomicUsize,
+}
+
+impl Inner {
+    fn next_id(&self) -> OperationId {
+        OperationId::from_raw(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Send `message` through the driver as a posted result completing to `token`.
+    pub(crate) fn post_message(&self, token: usize, message: Box<dyn Any + Send>) -> Result<()> {
+        let id = self.next_id();
+        self.in_flight.lock().unwrap().messages.insert(id, message);
+
+        let result = OperationalResult::new(token, 0, 0, 0).with_id(id);
+        self.driver.post(result).inspect_err(|_| {
+            self.in_flight.lock().unwrap().messages.remove(&id);
+        })
+    }
+
+    /// Schedule a timer and wake the waiters up if it is due before whatever they wait for.
+    fn set_timer(&self, expiry: Expiry, after: Duration, period: Option<Duration>) -> TimerId {
+        let (timer, earliest) = {
+            let mut timers = self.timers.lock().unwrap();
+            let before = timers.next_deadline();
+            let timer = timers.insert(Instant::now(), expiry, after, period);
+            (timer, before != timers.next_deadline())
+        };
 
-        let ret = unsafe { CreateIoCompletionPort(handle, self.handle, token, 0) };
+        if earliest && self.waiters.load(Ordering::SeqCst) > 0 {
+            let id = self.next_id();
+            self.in_flight.lock().unwrap().wakes.insert(id);
+
+            // Without the wake the waiter still fires the timer, only late.
+            let result = OperationalResult::new(0, 0, 0, 0).with_id(id);
+            if self.driver.post(result).is_err() {
+                self.in_flight.lock().unwrap().wakes.remove(&id);
+            }
+        }
+
+        timer
+    }
+
+    fn submit(
+        &self,
+        token: usize,
+        op: Operation,
+        mut context: Box<Context>,
+        deadline: Option<Instant>,
+        awaited: bool,
+    ) -> Result<OperationId> {
+        let id = self.next_id();
+        context.set_id(id);
+        context.set_offset(op.offset());
+
+        // Tracked before the driver sees it, the operation may complete on another thread
+        // before `submit` returns.
+        let context = NonNull::from(Box::leak(context));
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.pending.insert(id, context);
+            if awaited {
+                in_flight.awaited.insert(id, Awaited::Waiting(None));
+            }
+        }
 
-        if ret == 0 {
-            Err(Error::last_os_error())
+        match self
+            .driver
+            .submit(token, op, unsafe { &mut *context.as_ptr() })
+        {
+            Ok(()) => {
+                if let Some(deadline) = deadline {
+                    self.set_deadline(id, deadline);
+                }
+                Ok(id)
+            }
+            Err(e) => {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                in_flight.pending.remove(&id);
+                in_flight.awaited.remove(&id);
+                drop(unsafe { Box::from_raw(context.as_ptr()) });
+                Err(e)
+            }
+        }
+    }
+
+    /// Arm the deadline timer of a submitted operation.
+    fn set_deadline(&self, id: OperationId, deadline: Instant) {
+        let after = deadline.saturating_duration_since(Instant::now());
+        let timer = self.set_timer(Expiry::Deadline(id), after, None);
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // The operation may have completed already, then its timer goes right away.
+        if in_flight.pending.contains_key(&id) {
+            in_flight.deadlines.insert(id, timer);
         } else {
-            Ok(())
+            drop(in_flight);
+            self.timers.lock().unwrap().remove(timer);
+        }
+    }
+
+    /// Cancel an operation whose deadline passed, its completion reports the timeout.
+    fn time_out(&self, id: OperationId) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.deadlines.remove(&id);
+
+        if let Some(context) = in_flight.pending.get(&id).copied() {
+            in_flight.timed_out.insert(id);
+            let _ = self.driver.cancel(unsafe { context.as_ref() });
+        }
+    }
+
+    /// Hand the completion of an awaited operation to its future once it is there.
+    pub(crate) fn poll_awaited(&self, id: OperationId, waker: &Waker) -> Poll<Completion> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        match in_flight.awaited.remove(&id) {
+            Some(Awaited::Done(completion)) => Poll::Ready(completion),
+            Some(Awaited::Waiting(_)) => {
+                in_flight
+                    .awaited
+                    .insert(id, Awaited::Waiting(Some(waker.clone())));
+                Poll::Pending
+            }
+            None => panic!("operation future polled after completion"),
+        }
+    }
+
+    /// The future of `id` is gone: abandon the operation, or drop its unclaimed completion.
+    pub(crate) fn forget_awaited(&self, id: OperationId) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        // Both under one lock, so the completion cannot slip out through `get` in between.
+        if let Some(Awaited::Waiting(_)) = in_flight.awaited.remove(&id) {
+            let _ = self.abandon(&mut in_flight, id);
+        }
+    }
+
+    /// Move a pending operation to the orphans and cancel it.
+    fn abandon(&self, in_flight: &mut InFlight, id: OperationId) -> Result<()> {
+        if let Some(timer) = in_flight.deadlines.remove(&id) {
+            self.timers.lock().unwrap().remove(timer);
+        }
+        in_flight.timed_out.remove(&id);
+
+        match in_flight.pending.remove(&id) {
+            Some(context) => {
+                in_flight.orphans.insert(id, context);
+                self.driver.cancel(unsafe { context.as_ref() })
+            }
+            None => Ok(()),
         }
     }
 
-    pub fn get(&self, timeout: Option<Duration>) -> Result<OperationalResult> {
-        let mut ptr = null_mut();
-        let mut bytes_used = 0;
-        let mut token = 0;
-        let timeout = dur_to_ms(timeout);
+    /// Pair each result with the Context of its operation, dropping abandoned ones.
+    /// Completions of awaited operations go to their futures, the second value tells
+    /// whether there were any.
+    fn complete(&self, results: Vec<OperationalResult>) -> (Vec<Completion>, bool) {
+        let mut disarmed = Vec::new();
+        let mut woken = Vec::new();
+
+        // The Contexts are settled outside the lock, settling a connect sends its payload.
+        let reaped = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            results
+                .into_iter()
+                .filter_map(|mut result| match result.take_id() {
+                    Some(id) => {
+                        if in_flight.wakes.remove(&id) {
+                            return None;
+                        }
+                        if let Some(message) = in_flight.messages.remove(&id) {
+                            return Some(Reaped::Done(Completion::with_message(result, message)));
+                        }
 
-        let ret = unsafe {
-            GetQueuedCompletionStatus(self.handle, &mut bytes_used, &mut token, &mut ptr, timeout)
+                        disarmed.extend(in_flight.deadlines.remove(&id));
+                        // An operation that beat its deadline keeps its own result.
+                        if in_flight.timed_out.remove(&id) && result.is_cancelled() {
+                            result.set_timed_out();
+                        }
+
+                        if let Some(context) = in_flight.orphans.remove(&id) {
+                            let context = unsafe { Box::from_raw(context.as_ptr()) };
+                            return Some(Reaped::Orphan(result, context));
+                        }
+                        let context = in_flight
+                            .pending
+                            .remove(&id)
+                            .map(|context| unsafe { Box::from_raw(context.as_ptr()) });
+                        let awaited = in_flight.awaited.contains_key(&id).then_some(id);
+                        Some(Reaped::Operation(result, context, awaited))
+                    }
+                    None => Some(Reaped::Done(Completion::new(result, None))),
+                })
+                .collect::<Vec<_>>()
         };
 
-        if ret == 0 {
-            Err(Error::last_os_error())
-        } else {
-            let entry = OVERLAPPED_ENTRY {
-                lpCompletionKey: token,
-                Internal: 0,
-                lpOverlapped: ptr,
-                dwNumberOfBytesTransferred: bytes_used,
-            };
+        let mut completions = Vec::new();
+        let mut finished = Vec::new();
+        for reaped in reaped {
+            match reaped {
+                Reaped::Done(completion) => completions.push(completion),
+                // Settled before it is dropped, so a connection it accepted is closed.
+                Reaped::Orphan(mut result, mut context) => context.complete(&mut result),
+                Reaped::Operation(result, context, awaited) => {
+                    let completion = Completion::new(result, context);
+                    match awaited {
+                        Some(id) => finished.push((id, completion)),
+                        None => completions.push(completion),
+                    }
+                }
+            }
+        }
 
-            Ok(OperationalResult::new(entry))
+        let awaited = !finished.is_empty();
+        if awaited {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            for (id, completion) in finished {
+                // A future dropped in the meantime takes its completion with it.
+                if let Some(state) = in_flight.awaited.get_mut(&id) {
+                    if let Awaited::Waiting(Some(waker)) =
+                        std::mem::replace(state, Awaited::Done(completion))
+                    {
+                        woken.push(waker);
+                    }
+                }
+            }
+        }
+
+        for waker in woken {
+            waker.wake();
+        }
+
+        if !disarmed.is_empty() {
+            let mut timers = self.timers.lock().unwrap();
+            for timer in disarmed {
+                timers.remove(timer);
+            }
         }
+
+        (completions, awaited)
     }
 
-    /// Get many result by Context lists, and return OperationalResult lists.
-    pub fn get_many(
+    /// Wait for at least one completion that is not an abandoned operation, expired timers
+    /// included. The driver waits no longer than until the earliest timer.
+    /// With `until_awaited` it also returns, possibly empty handed, once the completion of
+    /// an awaited operation was handed to its future.
+    pub(crate) fn wait(
         &self,
         size: usize,
         timeout: Option<Duration>,
-    ) -> Result<Vec<OperationalResult>> {
-        let mut entries = vec![unsafe { zeroed::<OVERLAPPED_ENTRY>() }; size];
-
-        let mut removed = 0;
-        let timeout = dur_to_ms(timeout);
-        let len = len(&entries);
-
-        let ret = unsafe {
-            GetQueuedCompletionStatusEx(
-                self.handle,
-                entries.as_mut_ptr(),
-                len,
-                &mut removed,
-                timeout,
-                0,
-            )
-        };
+        until_awaited: bool,
+    ) -> Result<Vec<Completion>> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
 
-        if ret == 0 {
-            Err(Error::last_os_error())
-        } else {
-            let removed = removed as usize;
+        loop {
+            // Counted before looking at the timers, so a timer set after this is woken for.
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            let now = Instant::now();
+            let (expired, next_timer) = {
+                let mut timers = self.timers.lock().unwrap();
+                (timers.poll(now, size), timers.next_deadline())
+            };
 
-            unsafe {
-                entries.set_len(removed);
+            let mut completions = Vec::new();
+            for (timer, expiry) in expired {
+                match expiry {
+                    Expiry::Token(token) => completions.push(Completion::with_timer(token, timer)),
+                    Expiry::Deadline(id) => self.time_out(id),
+                }
             }
 
-            Ok(entries
-                .drain(..removed)
-                .map(OperationalResult::new)
-                .collect())
+            if !completions.is_empty() {
+                self.waiters.fetch_sub(1, Ordering::SeqCst);
+                return Ok(completions);
+            }
+
+            let timer_first = match (next_timer, deadline) {
+                (Some(next_timer), Some(deadline)) => next_timer < deadline,
+                (next_timer, _) => next_timer.is_some(),
+            };
+            let wait_until = if timer_first { next_timer } else { deadline };
+            let timeout = wait_until.map(|wait_until| wait_until.saturating_duration_since(now));
+
+            let results = self.driver.wait_many(size, timeout);
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+            match results {
+                Ok(results) => {
+                    let (completions, awaited) = self.complete(results);
+                    if !completions.is_empty() || (awaited && until_awaited) {
+                        return Ok(completions);
+                    }
+                }
+                // The timer is due, the next turn fires it.
+                Err(e) if timer_first && is_wait_timeout(&e) => {}
+                Err(e) => return Err(e),
+            }
         }
     }
+}
 
-    pub fn post(&self, result: OperationalResult) -> Result<()> {
-        let ret = unsafe {
-            PostQueuedCompletionStatus(
-                self.handle,
-                result.bytes_used(),
-                result.token(),
-                result.over_lapped_ptr(),
-            )
-        };
+/// Whether `wait_many` failed only because its timeout ran out: `WAIT_TIMEOUT` from IOCP,
+/// `ETIME` from the Linux drivers, `ErrorKind::TimedOut` from drivers plugged in.
+fn is_wait_timeout(e: &Error) -> bool {
+    #[cfg(windows)]
+    let code = windows_sys::Win32::Foundation::WAIT_TIMEOUT as i32;
+    #[cfg(target_os = "linux")]
+    let code = libc::ETIME;
+
+    e.raw_os_error() == Some(code) || e.kind() == ErrorKind::TimedOut
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let in_flight = self.in_flight.get_mut().unwrap();
+
+        for (id, context) in in_flight.pending.drain() {
+            let _ = self.driver.cancel(unsafe { context.as_ref() });
+            in_flight.orphans.insert(id, context);
+        }
 
-        cvt(ret).map(|_| ())
+        // The kernel may write into an orphan until its completion is reaped.
+        let mut last_progress = Instant::now();
+        while !in_flight.orphans.is_empty() && last_progress.elapsed() < ORPHAN_GRACE {
+            let results = self
+                .driver
+                .wait_many(in_flight.orphans.len(), Some(ORPHAN_GRACE / 10))
+                .unwrap_or_default();
+
+            for mut result in results {
+                match result.id() {
+                    Some(id) if in_flight.orphans.contains_key(&id) => {
+                        in_flight.take(id, &mut result);
+                        last_progress = Instant::now();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Operations the driver never completed are leaked rather than freed under the kernel.
+        in_flight.orphans.clear();
+    }
+}
+
+pub struct CompletionPort {
+    inner: Arc<Inner>,
+}
+
+impl CompletionPort {
+    /// Create a CompletionPort with specify then concurrent.
+    #[cfg(windows)]
+    pub fn new(num_threads: u32) -> Result<Self> {
+        Ok(Self::with_driver(crate::driver::IocpDriver::new(
+            num_threads,
+        )?))
+    }
+
+    /// Create a CompletionPort with specify then concurrent.
+    /// On Linux this is backed by io_uring, falling back to the epoll emulation
+    /// when io_uring is unavailable, for example disabled by seccomp in a container.
+    #[cfg(target_os = "linux")]
+    pub fn new(num_threads: u32) -> Result<Self> {
+        use crate::driver::UringDriver;
+
+        match UringDriver::new(UringDriver::DEFAULT_ENTRIES) {
+            Ok(driver) => Ok(Self::with_driver(driver)),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                Self::with_epoll(num_threads)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a CompletionPort that emulates completions over epoll readiness.
+    /// File operations are performed by `num_threads` helper threads, since epoll cannot drive regular files.
+    /// Sockets added to it are made nonblocking.
+    #[cfg(target_os = "linux")]
+    pub fn with_epoll(num_threads: u32) -> Result<Self> {
+        Ok(Self::with_driver(crate::driver::EpollDriver::new(
+            num_threads,
+        )?))
+    }
+
+    /// Create a CompletionPort that dispatches to `driver`.
+    pub fn with_driver<D: Driver + 'static>(driver: D) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                driver: Box::new(driver),
+                in_flight: Mutex::new(InFlight::default()),
+                next_id: AtomicU64::new(1),
+                timers: Mutex::new(TimerWheel::new()),
+                waiters: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Register a handle and token with CompletionPort.
+    /// The same token and handle cannot be registered in CompletionPort again unless the handle has been closed.
+    /// Take it out with `remove` before closing it.
+    pub fn add<A: AsHandle>(&self, token: usize, io_object: &A) -> Result<()> {
+        let handle = io_object.as_handle();
+
+        self.inner.driver.register(handle, token)?;
+        registry()
+            .lock()
+            .unwrap()
+            .insert(handle, (Arc::downgrade(&self.inner), token));
+
+        Ok(())
+    }
+
+    pub(crate) fn inner(&self) -> &Arc<Inner> {
+        &self.inner
+    }
+
+    /// Forget the token `io_object` was added with, before it is closed and its handle
+    /// reused by the next one opened. Operations on it can no longer be issued until it is
+    /// added again. Operations already in flight still complete, those the epoll emulation
+    /// has not performed yet as cancelled.
+    pub fn remove<A: AsHandle>(&self, io_object: &A) -> Result<()> {
+        self.remove_handle(io_object.as_handle())
+    }
+
+    /// Whether a handle is added to this CompletionPort under `token`.
+    pub(crate) fn has_token(&self, token: usize) -> bool {
+        let inner = Arc::downgrade(&self.inner);
+        registry()
+            .lock()
+            .unwrap()
+            .values()
+            .any(|(port, added)| *added == token && Weak::ptr_eq(port, &inner))
+    }
+
+    pub(crate) fn remove_handle(&self, handle: RawHandle) -> Result<()> {
+        {
+            let mut registry = registry().lock().unwrap();
+            match registry.get(&handle) {
+                Some((inner, _)) if Weak::ptr_eq(inner, &Arc::downgrade(&self.inner)) => {
+                    registry.remove(&handle);
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        self.inner.driver.deregister(handle)
+    }
+
+    /// Get one completion, which owns the Context of its operation.
+    /// An error means the wait itself failed or timed out, a failed operation is returned
+    /// as a completion whose `status` is the error.
+    pub fn get(&self, timeout: Option<Duration>) -> Result<Completion> {
+        Ok(self.inner.wait(1, timeout, false)?.remove(0))
+    }
+
+    /// Get at most `size` completions, each owns the Context of its operation.
+    pub fn get_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<Completion>> {
+        self.inner.wait(size, timeout, false)
+    }
+
+    /// Schedule a timer that expires once after `after`, as a completion for `token` from
+    /// `get` or `get_many`, see `Completion::timer`.
+    ///
+    /// ```
+    /// use iocp_rs::{sim::SimPort, CompletionPort};
+    /// use std::time::Duration;
+    ///
+    /// let cmp = CompletionPort::with_driver(SimPort::new(0));
+    /// let timer = cmp.set_timer(7, Duration::from_millis(5));
+    ///
+    /// let completion = cmp.get(None).unwrap();
+    /// assert_eq!(completion.token(), 7);
+    /// assert_eq!(completion.timer(), Some(timer));
+    /// ```
+    pub fn set_timer(&self, token: usize, after: Duration) -> TimerId {
+        self.inner.set_timer(Expiry::Token(token), after, None)
+    }
+
+    /// Schedule a timer that expires every `period` until it is cancelled. Periods missed
+    /// while no thread was waiting expire once.
+    pub fn set_interval(&self, token: usize, period: Duration) -> TimerId {
+        self.inner.set_timer(Expiry::Token(token), period, Some(period))
+    }
+
+    /// Cancel a timer, an expiry that was not returned yet is dropped as well.
+    /// Returns `false` if the timer already expired for the last time or was cancelled.
+    pub fn cancel_timer(&self, timer: TimerId) -> bool {
+        self.inner.timers.lock().unwrap().remove(timer)
+    }
+
+    pub fn post(&self, mut result: OperationalResult) -> Result<()> {
+        // A posted result must not claim the Context of a pending operation.
+        result.take_id();
+        self.inner.driver.post(result)
+    }
+
+    /// Send `message` to whichever thread gets the next completion, it comes back as a
+    /// completion for `token` that carries the value, see `Completion::into_message`.
+    pub fn post_message<T: Send + 'static>(&self, token: usize, message: T) -> Result<()> {
+        self.inner.post_message(token, Box::new(message))
+    }
+
+    /// A handle any thread can send `T` values through this CompletionPort with.
+    pub fn notifier<T: Send + 'static>(&self, token: usize) -> Notifier<T> {
+        Notifier::new(Arc::downgrade(&self.inner), token)
+    }
+
+    /// Ask the operation `id` to complete early, it still completes through `get`.
+    /// There is no guarantee that underlying drivers correctly support cancellation.
+    ///
+    /// ```ignore-linux
+    /// use std::net::{TcpStream, TcpListener};
+    /// use iocp_rs::{CompletionPort, net::TcpStreamExt, AsHandle};
+    /// use std::thread::{spawn, sleep};
+    /// use std::os::windows::io::{AsRawSocket, RawSocket};
+    /// use std::io::Write;
+    /// use std::time::Duration;
+    /// use windows_sys::Win32::Foundation::HANDLE;
+    ///
+    /// struct MyTcpStream {
+    ///     inner: TcpStream
+    /// }
+    ///
+    /// impl AsRawSocket for MyTcpStream {
+    ///     fn as_raw_socket(&self) -> RawSocket {
+    ///         self.inner.as_raw_socket()
+    ///     }
+    /// }
+    ///
+    /// impl AsHandle for MyTcpStream {
+    ///     fn as_handle(&self) -> HANDLE {
+    ///         self.inner.as_raw_socket() as HANDLE
+    ///     }
+    /// }
+    ///
+    /// impl TcpStreamExt for MyTcpStream {}
+    ///
+    /// fn main() {
+    ///     let cmp = CompletionPort::new(1).unwrap();
+    ///     let join = spawn(|| {
+    ///             let listener = TcpListener::bind("127.0.0.1:999").unwrap();
+    ///             let (mut stream, _) = listener.accept().unwrap();
+    ///
+    ///             sleep(Duration::from_secs(5));
+    ///             let ret = stream.write(b"123");
+    ///             dbg!(ret);
+    ///     });
+    ///
+    ///     let stream = TcpStream::connect("127.0.0.1:999").unwrap();
+    ///     let mut stream1 = MyTcpStream {inner: stream};
+    ///     cmp.add(1, &stream1).unwrap();
+    ///
+    ///     let id = stream1.read(vec![0; 3]).unwrap();
+    ///     cmp.cancel(id).unwrap();
+    ///     let completion = cmp.get(None).unwrap();
+    ///     assert!(completion.result().is_cancelled());
+    /// }
+    /// ```
+    pub fn cancel(&self, id: OperationId) -> Result<()> {
+        let in_flight = self.inner.in_flight.lock().unwrap();
+
+        // The lock keeps the Context alive until the driver is done with it.
+        match in_flight.pending.get(&id) {
+            Some(context) => self.inner.driver.cancel(unsafe { context.as_ref() }),
+            None => Ok(()),
+        }
+    }
+
+    /// Give up on the operation `id`: it is cancelled and its Context, buffer included,
+    /// stays with the CompletionPort until the kernel completion is reaped, then it is
+    /// dropped instead of being returned by `get`.
+    /// Dropping the CompletionPort abandons every operation still in flight the same way.
+    pub fn abandon(&self, id: OperationId) -> Result<()> {
+        let mut in_flight = self.inner.in_flight.lock().unwrap();
+        self.inner.abandon(&mut in_flight, id)
     }
 }
 
 impl Drop for CompletionPort {
     fn drop(&mut self) {
-        let ret = unsafe { CloseHandle(self.handle) };
-        if ret == 0 {
-            panic!("error {:?}", Error::last_os_error());
-        }
+        let inner = Arc::downgrade(&self.inner);
+
+        registry()
+            .lock()
+            .unwrap()
+            .retain(|_, (registered, _)| !Weak::ptr_eq(registered, &inner));
     }
 }
 
-unsafe impl Send for CompletionPort {}
-unsafe impl Sync for CompletionPort {}
+/// Which CompletionPort and token each registered handle completes to.
+type Registry = Mutex<HashMap<RawHandle, (Weak<Inner>, usize)>>;
 
-#[cfg(test)]
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The CompletionPort `handle` was added to and its token.
+fn registered(handle: RawHandle) -> Result<(Arc<Inner>, usize)> {
+    registry()
+        .lock()
+        .unwrap()
+        .get(&handle)
+        .and_then(|(inner, token)| inner.upgrade().map(|inner| (inner, *token)))
+        .ok_or(Error::new(
+            ErrorKind::NotFound,
+            "the handle is not registered with a CompletionPort",
+        ))
+}
+
+/// Forget `handle` on whichever CompletionPort it was added to, like
+/// `CompletionPort::remove`.
+pub(crate) fn deregister(handle: RawHandle) -> Result<()> {
+    let removed = registry().lock().unwrap().remove(&handle);
+
+    match removed.and_then(|(inner, _)| inner.upgrade()) {
+        Some(inner) => inner.driver.deregister(handle),
+        None => Ok(()),
+    }
+}
+
+/// Issue an operation on `handle` through the CompletionPort it was registered with,
+/// which owns the Context until the operation completes.
+/// Past `deadline` the operation is cancelled and completes as timed out.
+pub(crate) fn submit(
+    handle: RawHandle,
+    buff: Buffer,
+    io_type: IOType,
+    op: Operation,
+    deadline: Option<Instant>,
+) -> Result<OperationId> {
+    submit_context(Context::with_buffer(handle, buff, io_type), op, deadline)
+}
+
+/// Issue `op` with a Context built by the caller, on the port its handle was added to.
+pub(crate) fn submit_context(
+    context: Context,
+    op: Operation,
+    deadline: Option<Instant>,
+) -> Result<OperationId> {
+    let (inner, token) = registered(context.handle())?;
+
+    inner.submit(token, op, Box::new(context), deadline, false)
+}
+
+/// Issue an operation like `submit` whose completion resolves the returned future
+/// rather than coming out of `get`.
+pub(crate) fn submit_awaited(
+    handle: RawHandle,
+    buff: Buffer,
+    io_type: IOType,
+    op: Operation,
+) -> OpFuture {
+    submit_context_awaited(Context::with_buffer(handle, buff, io_type), op)
+}
+
+/// Like `submit_context`, resolving the returned future like `submit_awaited`.
+pub(crate) fn submit_context_awaited(context: Context, op: Operation) -> OpFuture {
+    let submitted = registered(context.handle()).and_then(|(inner, token)| {
+        let id = inner.submit(token, op, Box::new(context), None, true)?;
+        Ok((inner, id))
+    });
+
+    OpFuture::new(submitted)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::{
+        io::{ErrorKind, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use crate::{net::TcpStreamExt, CompletionPort, OperationalResult};
+
+    #[test]
+    fn post_and_get() {
+        let cmp = CompletionPort::new(1).unwrap();
+
+        cmp.post(OperationalResult::new(7, 0, 3, 0)).unwrap();
+
+        let result = cmp.get(None).unwrap();
+        assert_eq!(result.token(), 7);
+        assert_eq!(result.bytes_used(), 3);
+
+        cmp.post(OperationalResult::new(8, 0, 0, libc::EPIPE))
+            .unwrap();
+        let result = cmp.get(None).unwrap();
+        assert_eq!(result.token(), 8);
+        assert_eq!(
+            result.status().unwrap_err().raw_os_error(),
+            Some(libc::EPIPE)
+        );
+    }
+
+    #[test]
+    fn get_timeout() {
+        let cmp = CompletionPort::new(1).unwrap();
+
+        let ret = cmp.get_many(1, Some(Duration::from_millis(10)));
+        assert_eq!(ret.err().and_then(|e| e.raw_os_error()), Some(libc::ETIME));
+    }
+
+    fn abandon_pending_recv(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        let abandoned = stream.read(vec![0; 16]).unwrap();
+        cmp.abandon(abandoned).unwrap();
+        let id = stream.read(vec![0; 16]).unwrap();
+        peer.write_all(b"hello").unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.data(), b"hello");
+
+        // Left pending, cancelled and reaped by the drop.
+        stream.read(vec![0; 16]).unwrap();
+        drop(cmp);
+        peer.write_all(b"world").unwrap();
+    }
+
+    fn cancelled_is_not_eof(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        let id = stream.read(vec![0; 16]).unwrap();
+        cmp.cancel(id).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert!(completion.result().is_cancelled());
+        assert!(completion.status().is_err());
+
+        drop(peer);
+        stream.read(vec![0; 16]).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert!(!completion.result().is_cancelled());
+        assert_eq!(completion.status().unwrap(), 0);
+    }
+
+    #[test]
+    fn cancelled_is_not_eof_uring() {
+        cancelled_is_not_eof(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn cancelled_is_not_eof_epoll() {
+        cancelled_is_not_eof(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    #[test]
+    fn abandon_uring() {
+        abandon_pending_recv(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn abandon_epoll() {
+        abandon_pending_recv(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    fn timer_and_io(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        let id = stream.read(vec![0; 16]).unwrap();
+        let timer = cmp.set_timer(2, Duration::from_millis(20));
+
+        // The wait is cut short by the timer even though the read never completes.
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.timer(), Some(timer));
+
+        peer.write_all(b"hello").unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.data(), b"hello");
+    }
+
+    fn read_deadline(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        let deadline = Some(Instant::now() + Duration::from_millis(20));
+        let id = stream.read_with_deadline(vec![0; 16], deadline).unwrap();
+        let completion = cmp.get(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert!(completion.result().is_timed_out());
+        assert_eq!(completion.status().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn read_deadline_uring() {
+        read_deadline(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn read_deadline_epoll() {
+        read_deadline(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    #[test]
+    fn timer_and_io_uring() {
+        timer_and_io(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn timer_and_io_epoll() {
+        timer_and_io(CompletionPort::with_epoll(1).unwrap());
+    }
+}
+
+#[cfg(all(test, windows))]
 mod tests {
     use std::{fs::OpenOptions, os::windows::prelude::OpenOptionsExt};
 
     use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
 
-    use crate::{CompletionPort, fs::FileExt};
+    use crate::{fs::FileExt, CompletionPort};
 
     #[test]
     fn repeat_add() {
         let mut cmp = CompletionPort::new(1).unwrap();
 
         let mut file = OpenOptions::new()
-                                            .custom_flags(FILE_FLAG_OVERLAPPED)
-                                            .read(true)
-                                            .open("..\\test.txt")
-                                            .unwrap();
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .read(true)
+            .open("..\\test.txt")
+            .unwrap();
 
         cmp.add(1, &file).unwrap();
 
@@ -168,12 +936,12 @@ mod tests {
         }
 
         drop(file);
-        
+
         let mut file = OpenOptions::new()
-                                            .custom_flags(FILE_FLAG_OVERLAPPED)
-                                            .read(true)
-                                            .open("..\\test.txt")
-                                            .unwrap();
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .read(true)
+            .open("..\\test.txt")
+            .unwrap();
 
         cmp.add(1, &file).unwrap();
 
@@ -185,8 +953,303 @@ mod tests {
             dbg!(result.token());
         }
 
-
         drop(file);
         drop(cmp);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod orphan_tests {
+    use std::{
+        io::{Error, ErrorKind, Result},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use crate::{
+        driver::{Driver, Operation},
+        fs::FileExt,
+        AsHandle, CompletionPort, Context, OperationalResult, RawHandle,
+    };
+
+    /// Writes into the buffer of every operation from another thread after a delay, like
+    /// a kernel would, and ignores cancellation.
+    #[derive(Default)]
+    struct SlowKernel {
+        shared: Arc<(Mutex<Vec<OperationalResult>>, Condvar)>,
+        /// How many buffers were written after their operation was submitted.
+        written: Arc<AtomicUsize>,
+    }
+
+    impl Driver for SlowKernel {
+        fn register(&self, _handle: RawHandle, _token: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn submit(&self, token: usize, _op: Operation, context: &mut Context) -> Result<()> {
+            let shared = self.shared.clone();
+            let written = self.written.clone();
+            let id = context.id();
+            let buff_ptr = context.buff.as_mut_ptr() as usize;
+            let buff_len = context.buff.len();
+
+            spawn(move || {
+                sleep(Duration::from_millis(50));
+                let buff = unsafe {
+                    std::slice::from_raw_parts_mut(buff_ptr as *mut u8, buff_len as usize)
+                };
+                buff.fill(b'k');
+                written.fetch_add(1, Ordering::SeqCst);
+
+                shared
+                    .0
+                    .lock()
+                    .unwrap()
+                    .push(OperationalResult::new(token, 0, buff_len, 0).with_id(id));
+                shared.1.notify_all();
+            });
+
+            Ok(())
+        }
+
+        fn wait_many(
+            &self,
+            size: usize,
+            timeout: Option<Duration>,
+        ) -> Result<Vec<OperationalResult>> {
+            let (ready, condvar) = &*self.shared;
+            let timeout = timeout.unwrap_or(Duration::from_secs(10));
+            let (mut ready, _) = condvar
+                .wait_timeout_while(ready.lock().unwrap(), timeout, |ready| ready.is_empty())
+                .unwrap();
+
+            if ready.is_empty() {
+                return Err(Error::from(ErrorKind::TimedOut));
+            }
+            let size = size.min(ready.len());
+            Ok(ready.drain(..size).collect())
+        }
+
+        fn post(&self, result: OperationalResult) -> Result<()> {
+            self.shared.0.lock().unwrap().push(result);
+            self.shared.1.notify_all();
+            Ok(())
+        }
+
+        fn cancel(&self, _context: &Context) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FakeFile(RawHandle);
+
+    impl AsHandle for FakeFile {
+        fn as_handle(&self) -> RawHandle {
+            self.0
+        }
+    }
+
+    impl FileExt for FakeFile {}
+
+    #[test]
+    fn abandon_keeps_buffer_until_reaped() {
+        let driver = SlowKernel::default();
+        let written = driver.written.clone();
+        let cmp = CompletionPort::with_driver(driver);
+        let mut file = FakeFile(5150 as RawHandle);
+        cmp.add(1, &file).unwrap();
+
+        let id = file.read(vec![0; 64]).unwrap();
+        cmp.abandon(id).unwrap();
+        assert_eq!(cmp.inner.in_flight.lock().unwrap().orphans.len(), 1);
+
+        // The orphan's completion is swallowed, only the posted result comes out.
+        cmp.post(OperationalResult::new(2, 0, 0, 0)).unwrap();
+        let completion = cmp.get(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(completion.token(), 2);
+        assert!(completion.context().is_none());
+
+        let completion = cmp.get(Some(Duration::from_millis(200)));
+        assert!(completion.is_err());
+        assert_eq!(written.load(Ordering::SeqCst), 1);
+        assert!(cmp.inner.in_flight.lock().unwrap().orphans.is_empty());
+    }
+
+    #[test]
+    fn drop_waits_for_kernel() {
+        let driver = SlowKernel::default();
+        let written = driver.written.clone();
+        let cmp = CompletionPort::with_driver(driver);
+        let mut file = FakeFile(5151 as RawHandle);
+        cmp.add(1, &file).unwrap();
+
+        for _ in 0..8 {
+            file.read(vec![0; 4096]).unwrap();
+        }
+        drop(cmp);
+
+        // The drop only returned once the kernel was done with every buffer.
+        assert_eq!(written.load(Ordering::SeqCst), 8);
+    }
+}
+
+#[cfg(test)]
+mod timer_tests {
+    use std::{
+        sync::Arc,
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    };
+
+    use std::io::{Error, ErrorKind, Result};
+
+    use crate::{
+        driver::{Driver, Operation},
+        net::TcpStreamExt,
+        sim::SimPort,
+        CompletionPort, Context, OperationalResult, RawHandle,
+    };
+
+    #[test]
+    fn timers_fire_in_order() {
+        let cmp = CompletionPort::with_driver(SimPort::new(1));
+        let start = Instant::now();
+        let late = cmp.set_timer(2, Duration::from_millis(60));
+        let early = cmp.set_timer(1, Duration::from_millis(20));
+        let cancelled = cmp.set_timer(3, Duration::from_millis(40));
+        assert!(cmp.cancel_timer(cancelled));
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.timer(), Some(early));
+        assert_eq!(completion.token(), 1);
+        assert!(completion.id().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.timer(), Some(late));
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(!cmp.cancel_timer(late));
+    }
+
+    #[test]
+    fn interval_between_completions() {
+        let cmp = CompletionPort::with_driver(SimPort::new(2));
+        let interval = cmp.set_interval(9, Duration::from_millis(10));
+        cmp.post(OperationalResult::new(4, 0, 0, 0)).unwrap();
+
+        let mut ticks = 0;
+        let mut posted = 0;
+        while ticks < 3 {
+            for completion in cmp.get_many(4, None).unwrap() {
+                if completion.is_timer() {
+                    assert_eq!(completion.timer(), Some(interval));
+                    ticks += 1;
+                } else {
+                    assert_eq!(completion.token(), 4);
+                    posted += 1;
+                }
+            }
+        }
+        assert_eq!(posted, 1);
+
+        assert!(cmp.cancel_timer(interval));
+        assert!(cmp.get(Some(Duration::from_millis(30))).is_err());
+    }
+
+    #[test]
+    fn deadline_times_out() {
+        let sim = SimPort::new(4);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        let deadline = Some(Instant::now() + Duration::from_millis(20));
+        let id = a.read_with_deadline(vec![0; 4], deadline).unwrap();
+        let completion = cmp.get(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert!(completion.result().is_timed_out());
+        assert_eq!(completion.status().unwrap_err().kind(), ErrorKind::TimedOut);
+
+        // Completing first disarms the deadline.
+        let deadline = Some(Instant::now() + Duration::from_secs(60));
+        let id = a.read_with_deadline(vec![0; 4], deadline).unwrap();
+        TcpStreamExt::write(&b, b"ping".to_vec()).unwrap();
+        let mut read = None;
+        while read.is_none() {
+            read = cmp
+                .get_many(2, None)
+                .unwrap()
+                .into_iter()
+                .find(|completion| completion.id() == Some(id));
+        }
+        assert_eq!(read.unwrap().data(), b"ping");
+        assert!(cmp.inner.in_flight.lock().unwrap().deadlines.is_empty());
+        assert_eq!(cmp.inner.timers.lock().unwrap().next_deadline(), None);
+    }
+
+    #[test]
+    fn earlier_timer_wakes_waiter() {
+        let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(3)));
+        let waiter = {
+            let cmp = cmp.clone();
+            spawn(move || {
+                let start = Instant::now();
+                let completion = cmp.get(Some(Duration::from_secs(10))).unwrap();
+                (completion.token(), start.elapsed())
+            })
+        };
+
+        sleep(Duration::from_millis(20));
+        cmp.set_timer(5, Duration::from_millis(10));
+
+        let (token, elapsed) = waiter.join().unwrap();
+        assert_eq!(token, 5);
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    /// Fails every wait with something other than a timeout.
+    struct BrokenWait;
+
+    impl Driver for BrokenWait {
+        fn register(&self, _handle: RawHandle, _token: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn submit(&self, _token: usize, _op: Operation, _context: &mut Context) -> Result<()> {
+            Ok(())
+        }
+
+        fn wait_many(
+            &self,
+            _size: usize,
+            _timeout: Option<Duration>,
+        ) -> Result<Vec<OperationalResult>> {
+            Err(Error::from(ErrorKind::PermissionDenied))
+        }
+
+        fn post(&self, _result: OperationalResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn cancel(&self, _context: &Context) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timer_does_not_hide_driver_errors() {
+        let cmp = CompletionPort::with_driver(BrokenWait);
+        cmp.set_timer(1, Duration::from_secs(60));
+
+        let ret = cmp.get(Some(Duration::from_secs(120)));
+        assert_eq!(
+            ret.err().map(|e| e.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+    }
+}
@@ -1,130 +1,250 @@
-use std::{
-    io::{Result, Error},
-    mem::zeroed
-};
+use std::net::SocketAddr;
 
-use windows_sys::Win32::{
-    Foundation::{HANDLE, ERROR_NOT_FOUND},
-    System::IO::{CancelIoEx, OVERLAPPED},
-};
+#[cfg(windows)]
+use std::mem::zeroed;
+#[cfg(windows)]
+use windows_sys::Win32::System::IO::OVERLAPPED;
 
-use crate::utils::cvt;
+use crate::{
+    buf::{Buffer, Vectored},
+    net::{
+        connect, disconnect,
+        msg::{Message, MsgState},
+        transmit::Transmit,
+        AcceptState, Accepted, RecvFromState,
+    },
+    OperationalResult, RawHandle,
+};
 
 pub enum IOType {
     Read,
     Write,
 }
 
+/// Identifies an operation from its submission until its `Completion` is returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OperationId(u64);
+
+impl OperationId {
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    pub fn as_raw(&self) -> u64 {
+        self.0
+    }
+}
+
+/// What an operation keeps besides its buffers, for the kernel to fill in while it is
+/// in flight and to be decoded once it completed.
+pub(crate) enum OpState {
+    None,
+    Accept(Box<AcceptState>),
+    Accepted(Box<Accepted>),
+    /// A connect, which sends the buffer of the Context along.
+    Connect,
+    /// A disconnect, which leaves the socket to be connected again if `reuse` is set.
+    Disconnect {
+        reuse: bool,
+    },
+    /// A transmit and how far it got.
+    Transmit(Box<Transmit>),
+    /// A receive-from, the kernel writes the sender address into it.
+    RecvFrom(Box<RecvFromState>),
+    /// The address the datagram of a receive-from came from.
+    Source(SocketAddr),
+    /// A receive of a datagram with its control messages, the kernel writes the sender
+    /// address and the control messages into it.
+    RecvMsg(Box<MsgState>),
+    /// What the datagram of a receive with control messages came with.
+    Message(Box<Message>),
+    /// A send of a datagram with control messages, which it holds along with the
+    /// destination address.
+    SendMsg(Box<MsgState>),
+}
+
+/// The state of one operation: its handle, its buffer and where it reads or writes.
+/// On Windows the `OVERLAPPED` comes first, so the pointer handed to the kernel
+/// leads back to the whole Context when the operation completes.
+#[repr(C)]
 pub struct Context {
+    #[cfg(windows)]
     pub(crate) over_lapped: OVERLAPPED,
-    pub(crate) buff: Vec<u8>,
-    handle: HANDLE,
+    pub(crate) buff: Buffer,
+    /// The buffers of a vectored operation, `buff` is empty then.
+    pub(crate) vectored: Option<Box<Vectored>>,
+    pub(crate) state: OpState,
+    handle: RawHandle,
     pub(crate) io_type: IOType,
+    offset: u64,
+    id: OperationId,
 }
 
 impl Context {
-    pub fn new(handle: HANDLE, buff: Vec<u8>, io_type: IOType) -> Self {
-        let over_lapped = unsafe { zeroed::<OVERLAPPED>() };
+    pub fn new(handle: RawHandle, buff: Vec<u8>, io_type: IOType) -> Self {
+        let buff = match io_type {
+            IOType::Read => Buffer::recv(buff),
+            IOType::Write => Buffer::send(buff),
+        };
+
+        Self::with_buffer(handle, buff, io_type)
+    }
+
+    pub(crate) fn with_buffer(handle: RawHandle, buff: Buffer, io_type: IOType) -> Self {
         Self {
+            #[cfg(windows)]
+            over_lapped: unsafe { zeroed::<OVERLAPPED>() },
             handle,
             buff,
+            vectored: None,
+            state: OpState::None,
             io_type,
-            over_lapped,
+            offset: 0,
+            id: OperationId(0),
         }
     }
 
-    pub fn set_offset(&mut self, offset: u64) {
-        let low_offset = (offset & (u32::MAX as u64)) as u32;
-        let high_offset = (offset >> 32) as u32;
+    pub(crate) fn with_vectored(handle: RawHandle, vectored: Vectored, io_type: IOType) -> Self {
+        Self {
+            vectored: Some(Box::new(vectored)),
+            ..Self::with_buffer(handle, Buffer::empty(), io_type)
+        }
+    }
 
-        self.over_lapped.Anonymous.Anonymous.Offset = low_offset;
-        self.over_lapped.Anonymous.Anonymous.OffsetHigh = high_offset;
+    pub(crate) fn with_state(handle: RawHandle, state: OpState, io_type: IOType) -> Self {
+        Self {
+            state,
+            ..Self::with_buffer(handle, Buffer::empty(), io_type)
+        }
+    }
+
+    /// Settle what the kernel filled in once the operation completed with `result`.
+    /// An accept that cannot be decoded, or a connect or disconnect whose socket cannot be
+    /// brought up to date turns into a failure.
+    pub(crate) fn complete(&mut self, result: &mut OperationalResult) {
+        let ret = match result.result() {
+            Ok(ret) => ret,
+            Err(_) => return,
+        };
+
+        match std::mem::replace(&mut self.state, OpState::None) {
+            OpState::Accept(accept) => match accept.finish(self.handle, ret) {
+                Ok(accepted) => {
+                    self.state = OpState::Accepted(Box::new(accepted));
+                    result.set_result(Ok(0));
+                }
+                Err(e) => result.set_result(Err(e)),
+            },
+            OpState::Connect => {
+                result.set_result(connect::finish(self.handle, self.buff.as_slice(), ret))
+            }
+            OpState::Disconnect { reuse } => {
+                result.set_result(disconnect::finish(self.handle, reuse, ret))
+            }
+            OpState::RecvFrom(from) => {
+                self.buff.set_init(ret as usize);
+                if let Some(addr) = from.finish() {
+                    self.state = OpState::Source(addr);
+                }
+            }
+            OpState::RecvMsg(msg) => {
+                self.buff.set_init(ret as usize);
+                self.state = OpState::Message(Box::new(msg.finish()));
+            }
+            state => {
+                self.state = state;
+                if let IOType::Read = self.io_type {
+                    match &mut self.vectored {
+                        Some(vectored) => vectored.set_init(ret as usize),
+                        None => self.buff.set_init(ret as usize),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
     }
 
     pub fn offset(&self) -> u64 {
-        let low_offset = unsafe { self.over_lapped.Anonymous.Anonymous.Offset as u64 };
-        let high_offset = unsafe { self.over_lapped.Anonymous.Anonymous.OffsetHigh as u64 };
+        self.offset
+    }
+
+    pub fn handle(&self) -> RawHandle {
+        self.handle
+    }
 
-        (high_offset << 32) | low_offset
+    /// The id the CompletionPort gave this operation on submission.
+    pub fn id(&self) -> OperationId {
+        self.id
     }
 
+    pub(crate) fn set_id(&mut self, id: OperationId) {
+        self.id = id;
+    }
+
+    /// The initialized part of the range the operation uses, for a slice only the slice.
     pub fn get_buff(&self) -> &[u8] {
-        &self.buff
+        self.buff.as_slice()
+    }
+
+    /// Like `get_buff`, but empty for buffers that can only be sent from, like `Bytes`.
+    pub fn get_buff_mut(&mut self) -> &mut [u8] {
+        self.buff.as_mut_slice()
+    }
+
+    /// Take the buffer back out of the Context, copied unless it is a `Vec<u8>`.
+    pub fn into_buff(self) -> Vec<u8> {
+        self.buff.into_vec()
+    }
+
+    /// Take the buffer back out of the Context as the type it was submitted as,
+    /// the Context is handed back if `B` is another type.
+    pub fn into_buf<B: 'static>(mut self) -> Result<B, Box<Self>> {
+        let buff = std::mem::replace(&mut self.buff, Buffer::empty());
+        buff.downcast::<B>().map_err(|buff| {
+            self.buff = buff;
+            Box::new(self)
+        })
+    }
+
+    /// Take the buffers of a vectored operation back out of the Context, in the order
+    /// they were submitted, the Context is handed back if they are not all `B`s.
+    pub fn into_bufs<B: 'static>(mut self) -> Result<Vec<B>, Box<Self>> {
+        match self.vectored.take().map(|vectored| vectored.downcast()) {
+            Some(Ok(bufs)) => Ok(bufs),
+            Some(Err(vectored)) => {
+                self.vectored = Some(Box::new(vectored));
+                Err(Box::new(self))
+            }
+            None => Err(Box::new(self)),
+        }
+    }
+
+    /// Take the connection an accept completed with out of the Context, the Context is
+    /// handed back for any other operation.
+    pub fn into_accepted(mut self) -> Result<Accepted, Box<Self>> {
+        match std::mem::replace(&mut self.state, OpState::None) {
+            OpState::Accepted(accepted) => Ok(*accepted),
+            state => {
+                self.state = state;
+                Err(Box::new(self))
+            }
+        }
     }
 
     pub fn io_type(&self) -> &IOType {
         &self.io_type
     }
 
+    #[cfg(windows)]
     pub fn over_lapped_ptr(&mut self) -> *mut OVERLAPPED {
         (&mut self.over_lapped) as *mut _
     }
-
-    /// 
-    /// Cancel this context with handle and overlapped.
-    /// There is no guarantee that underlying drivers correctly support cancellation.
-    /// ```
-    /// use std::net::{TcpStream, TcpListener};
-    /// use iocp_rs::{CompletionPort, net::TcpStreamExt, AsHandle};
-    /// use std::thread::{spawn, sleep};
-    /// use std::os::windows::io::{AsRawSocket, RawSocket};
-    /// use std::io::Write;
-    /// use std::time::Duration;
-    /// use windows_sys::Win32::Foundation::HANDLE;
-    /// 
-    /// struct MyTcpStream {
-    ///     inner: TcpStream
-    /// }
-    /// 
-    /// impl AsRawSocket for MyTcpStream {
-    ///     fn as_raw_socket(&self) -> RawSocket {
-    ///         self.inner.as_raw_socket()
-    ///     }
-    /// }
-    /// 
-    /// impl AsHandle for MyTcpStream {
-    ///     type Handle = HANDLE;
-    ///     fn as_handle(&self) -> Self::Handle {
-    ///         self.inner.as_raw_socket() as HANDLE
-    ///     }
-    /// }
-    /// 
-    /// impl TcpStreamExt for MyTcpStream {}
-    /// 
-    /// fn main() {
-    ///     let cmp = CompletionPort::new(1).unwrap();
-    ///     let join = spawn(|| {
-    ///             let listener = TcpListener::bind("127.0.0.1:999").unwrap();
-    ///             let (mut stream, _) = listener.accept().unwrap();
-    ///             
-    ///             sleep(Duration::from_secs(5));
-    ///             let ret = stream.write(b"123");
-    ///             dbg!(ret);
-    ///     });
-    /// 
-    ///     let stream = TcpStream::connect("127.0.0.1:999").unwrap();
-    ///     let mut stream1 = MyTcpStream {inner: stream};
-    ///     cmp.add(1, &stream1).unwrap();
-    /// 
-    ///     let context = stream1.read(vec![0; 3]).unwrap();
-    ///     context.cancel();
-    ///     let result = cmp.get(None).unwrap();
-    ///     assert_eq!(result.token(), 1);
-    /// }
-    /// 
-    /// ```
-    pub fn cancel(self) -> Result<()> {
-        let ret = unsafe { CancelIoEx(self.handle, &self.over_lapped as *const _) };
-
-        match cvt(ret) {
-            Ok(_) => Ok(()),
-            Err(e) if e.raw_os_error() == Some(ERROR_NOT_FOUND as i32) => Ok(()),
-            Err(e) => Err(e)
-        }
-    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, windows))]
 mod tests {
 
     use std::net::{TcpStream, TcpListener};
@@ -169,10 +289,11 @@ mod tests {
         let mut stream1 = MyTcpStream {inner: stream};
         cmp.add(1, &stream1).unwrap();
     
-        let context = stream1.read(vec![0; 3]).unwrap();
-        context.cancel().unwrap();
+        let id = stream1.read(vec![0; 3]).unwrap();
+        cmp.cancel(id).unwrap();
         sleep(Duration::from_secs(5));
-        let result = cmp.get(None).unwrap();
-        assert_eq!(result.token(), 2);
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 1);
+        assert!(completion.result().is_cancelled());
     }
 }
@@ -0,0 +1,179 @@
+use std::{io::Result, net::SocketAddr, time::Duration};
+
+use crate::{Context, OperationalResult, RawHandle};
+
+#[cfg(target_os = "linux")]
+pub use crate::linux::{epoll::EpollDriver, ring::UringDriver};
+#[cfg(windows)]
+pub use crate::windows::iocp::IocpDriver;
+
+/// An overlapped operation issued by the `TcpStreamExt`, `TcpListenerExt`, `UdpSocketExt` and
+/// `FileExt` traits.
+pub enum Operation {
+    /// Read a file at `offset`.
+    Read { offset: u64 },
+    /// Write a file at `offset`.
+    Write { offset: u64 },
+    /// Receive from a socket.
+    Recv,
+    /// Send on a connected socket.
+    Send,
+    /// Send a datagram to the address.
+    SendTo(SocketAddr),
+    /// Receive a datagram into the buffer of the Context and the address it came from into
+    /// its receive-from state.
+    RecvFrom,
+    /// Receive into the buffers of a vectored Context, filling them in order.
+    RecvVectored,
+    /// Send the buffers of a vectored Context on a connected socket.
+    SendVectored,
+    /// Send the buffers of a vectored Context as one datagram to the address.
+    SendToVectored(SocketAddr),
+    /// Accept a connection on a listening socket into the accept state of the Context.
+    Accept,
+    /// Connect a socket to the address, sending the buffer of the Context along.
+    Connect(SocketAddr),
+    /// Close the connection of a socket, leaving the socket open to be connected again if
+    /// `reuse` is set.
+    Disconnect { reuse: bool },
+    /// Send a range of a file starting at `offset` on a connected socket between a head and
+    /// a tail, all in the transmit state of the Context.
+    TransmitFile { offset: u64 },
+    /// Receive a datagram into the buffer of the Context, and the address it came from and
+    /// its control messages into its message state.
+    RecvMsg,
+    /// Send the buffer of the Context as one datagram to the address and with the control
+    /// messages in its message state.
+    SendMsg,
+}
+
+impl Operation {
+    pub fn offset(&self) -> u64 {
+        match *self {
+            Operation::Read { offset }
+            | Operation::Write { offset }
+            | Operation::TransmitFile { offset } => offset,
+            _ => 0,
+        }
+    }
+}
+
+/// The machinery behind a CompletionPort.
+///
+/// A driver associates handles with tokens, issues the operations of the `*Ext` traits
+/// and reports their completions, a CompletionPort dispatches every call to one.
+/// `IocpDriver` on Windows, `UringDriver` and `EpollDriver` on Linux are the drivers
+/// of this crate, others can be plugged in with [`CompletionPort::with_driver`](crate::CompletionPort::with_driver).
+pub trait Driver: Send + Sync {
+    /// Associate `handle` with `token`, operations on the handle complete with that token.
+    fn register(&self, handle: RawHandle, token: usize) -> Result<()>;
+
+    /// Issue `op` on the handle of `context`, which owns the buffer of the operation.
+    /// The context stays at this address until the driver returns a result marked with
+    /// [`OperationalResult::with_id`] and the id of the context from `wait_many`.
+    /// An operation that fails to submit must not complete later.
+    fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()>;
+
+    /// Wait until at least one operation completes and return at most `size` of them.
+    /// A wait that times out fails with `ETIME` on Linux, `WAIT_TIMEOUT` on Windows or
+    /// `ErrorKind::TimedOut`.
+    fn wait_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<OperationalResult>>;
+
+    /// Queue a completion that was not produced by an operation.
+    /// `wait_many` must return it with the same token and id.
+    fn post(&self, result: OperationalResult) -> Result<()>;
+
+    /// Ask for a pending operation to complete early, `context` is the one given to `submit`.
+    fn cancel(&self, context: &Context) -> Result<()>;
+
+    /// Forget `handle` before it is closed and its value reused. Operations the driver
+    /// still holds back on it should complete as cancelled.
+    fn deregister(&self, _handle: RawHandle) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        io::{Error, ErrorKind, Result},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use crate::{fs::FileExt, AsHandle, CompletionPort, Context, OperationalResult, RawHandle};
+
+    use super::{Driver, Operation};
+
+    /// Completes every operation as soon as it is submitted.
+    #[derive(Default)]
+    struct Immediate {
+        tokens: Mutex<Vec<(RawHandle, usize)>>,
+        ready: Mutex<VecDeque<OperationalResult>>,
+    }
+
+    impl Driver for Immediate {
+        fn register(&self, handle: RawHandle, token: usize) -> Result<()> {
+            self.tokens.lock().unwrap().push((handle, token));
+            Ok(())
+        }
+
+        fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()> {
+            context.get_buff_mut().fill(b'x');
+            let len = context.get_buff().len() as u32;
+            self.ready.lock().unwrap().push_back(
+                OperationalResult::new(token, op.offset(), len, 0).with_id(context.id()),
+            );
+            Ok(())
+        }
+
+        fn wait_many(
+            &self,
+            size: usize,
+            _timeout: Option<Duration>,
+        ) -> Result<Vec<OperationalResult>> {
+            let mut ready = self.ready.lock().unwrap();
+            if ready.is_empty() {
+                return Err(Error::from(ErrorKind::TimedOut));
+            }
+            let size = size.min(ready.len());
+            Ok(ready.drain(..size).collect())
+        }
+
+        fn post(&self, result: OperationalResult) -> Result<()> {
+            self.ready.lock().unwrap().push_back(result);
+            Ok(())
+        }
+
+        fn cancel(&self, _context: &Context) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FakeFile;
+
+    impl AsHandle for FakeFile {
+        fn as_handle(&self) -> RawHandle {
+            4242 as RawHandle
+        }
+    }
+
+    impl FileExt for FakeFile {}
+
+    #[test]
+    fn custom_driver() {
+        let cmp = CompletionPort::with_driver(Immediate::default());
+        let mut file = FakeFile;
+
+        cmp.add(3, &file).unwrap();
+        let id = file.read_at(vec![0; 4], 8).unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 3);
+        assert_eq!(completion.result().offset(), 8);
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.context().unwrap().offset(), 8);
+        assert_eq!(completion.data(), b"xxxx");
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    io::{Error, Result},
+    time::Duration,
+};
+
+use crate::{context::IOType, AsHandle, Completion, CompletionPort, RawHandle, TimerId};
+
+/// How many completions `EventLoop::run_once` dispatches at most.
+const BATCH: usize = 64;
+
+/// The low half of a token is the slot index, the high half its generation, so a token
+/// freed and handed out again never matches completions issued under the old one.
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// Callbacks for the completions of one registered object.
+///
+/// Each callback gets the `EventLoop` back, so a handler can issue its next operation,
+/// register new objects or deregister itself.
+pub trait Handler {
+    /// A read or receive completed successfully, `Completion::data` holds the bytes.
+    fn on_read(&mut self, event_loop: &mut EventLoop, completion: Completion);
+
+    /// A write or send completed successfully.
+    fn on_write(&mut self, event_loop: &mut EventLoop, completion: Completion);
+
+    /// An operation failed, `completion` still owns its buffer.
+    fn on_error(&mut self, event_loop: &mut EventLoop, error: Error, completion: Completion);
+
+    /// A timer set for this handler's token expired.
+    fn on_timer(&mut self, _event_loop: &mut EventLoop, _timer: TimerId) {}
+
+    /// A message or posted result for this handler's token arrived.
+    fn on_message(&mut self, _event_loop: &mut EventLoop, _completion: Completion) {}
+}
+
+enum Slot {
+    Occupied {
+        generation: usize,
+        handle: RawHandle,
+        /// Taken out while one of its callbacks runs.
+        handler: Option<Box<dyn Handler>>,
+    },
+    Vacant {
+        generation: usize,
+        next_free: Option<usize>,
+    },
+}
+
+/// The handlers by token, slots are reused through a free list.
+#[derive(Default)]
+struct Slab {
+    slots: Vec<Slot>,
+    free: Option<usize>,
+    len: usize,
+}
+
+impl Slab {
+    fn split(token: usize) -> (usize, usize) {
+        (token & INDEX_MASK, token >> INDEX_BITS)
+    }
+
+    /// Reserve a slot for `handle`, the handler is filled in once it is registered.
+    fn reserve(&mut self, handle: RawHandle) -> usize {
+        let (index, generation) = match self.free {
+            Some(index) => match self.slots[index] {
+                Slot::Vacant {
+                    generation,
+                    next_free,
+                } => {
+                    self.free = next_free;
+                    (index, generation)
+                }
+                Slot::Occupied { .. } => unreachable!("free list points at an occupied slot"),
+            },
+            None => {
+                self.slots.push(Slot::Vacant {
+                    generation: 0,
+                    next_free: None,
+                });
+                (self.slots.len() - 1, 0)
+            }
+        };
+
+        self.slots[index] = Slot::Occupied {
+            generation,
+            handle,
+            handler: None,
+        };
+        self.len += 1;
+
+        (generation << INDEX_BITS) | index
+    }
+
+    fn slot(&mut self, token: usize) -> Option<&mut Option<Box<dyn Handler>>> {
+        let (index, token_generation) = Self::split(token);
+
+        match self.slots.get_mut(index)? {
+            Slot::Occupied {
+                generation,
+                handler,
+                ..
+            } if *generation == token_generation => Some(handler),
+            _ => None,
+        }
+    }
+
+    /// Free the slot of `token`, returns the handle it was reserved for.
+    fn remove(&mut self, token: usize) -> Option<RawHandle> {
+        let (index, token_generation) = Self::split(token);
+
+        let handle = match self.slots.get(index)? {
+            Slot::Occupied {
+                generation, handle, ..
+            } if *generation == token_generation => *handle,
+            _ => return None,
+        };
+
+        self.slots[index] = Slot::Vacant {
+            generation: (token_generation + 1) & (usize::MAX >> INDEX_BITS),
+            next_free: self.free,
+        };
+        self.free = Some(index);
+        self.len -= 1;
+
+        Some(handle)
+    }
+}
+
+/// A CompletionPort that hands out tokens itself and calls a `Handler` for every
+/// completion, instead of returning completions to the caller.
+///
+/// Tokens carry a generation, completions still queued for a token that was
+/// deregistered are dropped rather than delivered to whoever gets the slot next.
+///
+/// ```
+/// use iocp_rs::{sim::SimPort, Completion, CompletionPort, EventLoop, Handler};
+/// use iocp_rs::fs::FileExt;
+/// use std::io::Error;
+///
+/// struct Print;
+///
+/// impl Handler for Print {
+///     fn on_read(&mut self, event_loop: &mut EventLoop, completion: Completion) {
+///         assert_eq!(completion.data(), b"hello");
+///         event_loop.deregister(completion.token());
+///     }
+///
+///     fn on_write(&mut self, _: &mut EventLoop, _: Completion) {}
+///
+///     fn on_error(&mut self, _: &mut EventLoop, error: Error, _: Completion) {
+///         panic!("{error}");
+///     }
+/// }
+///
+/// let sim = SimPort::new(0);
+/// let mut event_loop = EventLoop::new(CompletionPort::with_driver(sim.clone()));
+/// let mut file = sim.file(b"hello".to_vec());
+/// event_loop.register(&file, Print).unwrap();
+///
+/// file.read(vec![0; 16]).unwrap();
+/// // Returns once the handler deregistered itself.
+/// event_loop.run().unwrap();
+/// ```
+pub struct EventLoop {
+    port: CompletionPort,
+    handlers: Slab,
+}
+
+impl EventLoop {
+    pub fn new(port: CompletionPort) -> Self {
+        Self {
+            port,
+            handlers: Slab::default(),
+        }
+    }
+
+    /// The CompletionPort, to set timers or post messages to a handler's token.
+    pub fn port(&self) -> &CompletionPort {
+        &self.port
+    }
+
+    /// Add `io_object` to the CompletionPort under a newly allocated token and let
+    /// `handler` take its completions.
+    pub fn register<A, H>(&mut self, io_object: &A, handler: H) -> Result<usize>
+    where
+        A: AsHandle,
+        H: Handler + 'static,
+    {
+        let token = self.handlers.reserve(io_object.as_handle());
+
+        if let Err(e) = self.port.add(token, io_object) {
+            self.handlers.remove(token);
+            return Err(e);
+        }
+        *self.handlers.slot(token).unwrap() = Some(Box::new(handler));
+
+        Ok(token)
+    }
+
+    /// Drop the handler of `token` and free the token. Operations still in flight on its
+    /// object complete without reaching any handler, including one that reuses the slot.
+    /// Returns `false` if `token` is not registered.
+    /// On Windows the object stays associated with the completion port until it is closed,
+    /// registering it again fails with `ERROR_INVALID_PARAMETER`.
+    pub fn deregister(&mut self, token: usize) -> bool {
+        match self.handlers.remove(token) {
+            Some(handle) => {
+                let _ = self.port.remove_handle(handle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many handlers are registered.
+    pub fn len(&self) -> usize {
+        self.handlers.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.len == 0
+    }
+
+    /// Wait for completions like `CompletionPort::get_many` and dispatch them, returns how
+    /// many reached a handler.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> Result<usize> {
+        let completions = self.port.get_many(BATCH, timeout)?;
+
+        let mut dispatched = 0;
+        for completion in completions {
+            if self.dispatch_one(completion) {
+                dispatched += 1;
+            }
+        }
+
+        Ok(dispatched)
+    }
+
+    /// Dispatch completions until every handler is deregistered.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.is_empty() {
+            self.run_once(None)?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_one(&mut self, completion: Completion) -> bool {
+        let token = completion.token();
+        let mut handler = match self.handlers.slot(token).and_then(Option::take) {
+            Some(handler) => handler,
+            None => return false,
+        };
+
+        if let Some(timer) = completion.timer() {
+            handler.on_timer(self, timer);
+        } else {
+            match (
+                completion.status(),
+                completion.context().map(|c| c.io_type()),
+            ) {
+                (Err(e), _) => handler.on_error(self, e, completion),
+                (Ok(_), Some(IOType::Read)) => handler.on_read(self, completion),
+                (Ok(_), Some(IOType::Write)) => handler.on_write(self, completion),
+                (Ok(_), None) => handler.on_message(self, completion),
+            }
+        }
+
+        // Put back unless the handler deregistered itself.
+        if let Some(slot) = self.handlers.slot(token) {
+            *slot = Some(handler);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Error,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{net::TcpStreamExt, sim::SimPort, Completion, CompletionPort, TimerId};
+
+    use super::{EventLoop, Handler};
+
+    /// Records what it was called with.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    impl Handler for Recorder {
+        fn on_read(&mut self, _: &mut EventLoop, completion: Completion) {
+            let data = String::from_utf8_lossy(completion.data()).into_owned();
+            self.0.lock().unwrap().push(format!("read {data}"));
+        }
+
+        fn on_write(&mut self, _: &mut EventLoop, completion: Completion) {
+            let bytes = completion.bytes_used();
+            self.0.lock().unwrap().push(format!("write {bytes}"));
+        }
+
+        fn on_error(&mut self, _: &mut EventLoop, _: Error, _: Completion) {
+            self.0.lock().unwrap().push("error".to_string());
+        }
+
+        fn on_timer(&mut self, _: &mut EventLoop, _: TimerId) {
+            self.0.lock().unwrap().push("timer".to_string());
+        }
+    }
+
+    #[test]
+    fn dispatch_by_io_type() {
+        let sim = SimPort::new(1);
+        let mut event_loop = EventLoop::new(CompletionPort::with_driver(sim.clone()));
+        let (mut a, b) = sim.socket_pair();
+        let recorder = Recorder::default();
+        let token_a = event_loop.register(&a, recorder.clone()).unwrap();
+        let token_b = event_loop.register(&b, recorder.clone()).unwrap();
+        assert_ne!(token_a, token_b);
+        assert_eq!(event_loop.len(), 2);
+
+        a.read(vec![0; 8]).unwrap();
+        TcpStreamExt::write(&b, b"ping".to_vec()).unwrap();
+        event_loop
+            .port()
+            .set_timer(token_a, Duration::from_millis(1));
+
+        let mut dispatched = 0;
+        while dispatched < 3 {
+            dispatched += event_loop.run_once(None).unwrap();
+        }
+
+        let mut calls = recorder.take();
+        calls.sort();
+        assert_eq!(calls, ["read ping", "timer", "write 4"]);
+    }
+
+    #[test]
+    fn stale_completions_are_dropped() {
+        let sim = SimPort::new(2);
+        let mut event_loop = EventLoop::new(CompletionPort::with_driver(sim.clone()));
+        let (mut a, b) = sim.socket_pair();
+        let old = Recorder::default();
+        let token = event_loop.register(&a, old.clone()).unwrap();
+        event_loop.register(&b, Recorder::default()).unwrap();
+
+        // Still pending when its token goes away.
+        a.read(vec![0; 8]).unwrap();
+        assert!(event_loop.deregister(token));
+        assert!(!event_loop.deregister(token));
+        assert!(a.read(vec![0; 8]).is_err());
+
+        let new = Recorder::default();
+        let reused = event_loop.register(&a, new.clone()).unwrap();
+        assert_ne!(reused, token);
+        assert_eq!(reused & super::INDEX_MASK, token & super::INDEX_MASK);
+
+        a.read(vec![0; 8]).unwrap();
+        TcpStreamExt::write(&b, b"one".to_vec()).unwrap();
+        TcpStreamExt::write(&b, b"two".to_vec()).unwrap();
+
+        while new.0.lock().unwrap().is_empty() {
+            event_loop.run_once(Some(Duration::from_secs(5))).unwrap();
+        }
+        assert!(old.take().is_empty());
+        assert_eq!(new.take(), ["read two"]);
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use std::{io::Error, net::UdpSocket};
+
+    use windows_sys::Win32::Foundation::ERROR_INVALID_PARAMETER;
+
+    use crate::{Completion, CompletionPort};
+
+    use super::{EventLoop, Handler};
+
+    struct Ignore;
+
+    impl Handler for Ignore {
+        fn on_read(&mut self, _: &mut EventLoop, _: Completion) {}
+
+        fn on_write(&mut self, _: &mut EventLoop, _: Completion) {}
+
+        fn on_error(&mut self, _: &mut EventLoop, _: Error, _: Completion) {}
+    }
+
+    #[test]
+    fn deregistered_socket_cannot_register_again() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new(CompletionPort::new(1).unwrap());
+        let token = event_loop.register(&socket, Ignore).unwrap();
+        assert!(event_loop.deregister(token));
+
+        // The socket keeps its association with the port until it is closed.
+        let e = event_loop.register(&socket, Ignore).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(ERROR_INVALID_PARAMETER as i32));
+        assert!(event_loop.is_empty());
+    }
+}
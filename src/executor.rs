@@ -0,0 +1,766 @@
+//! Executors that run futures over a CompletionPort.
+//!
+//! A thread with nothing to run parks in the port's wait, like `Reactor::turn`, so the
+//! completions of `*_async` operations and wakes from other threads, which `post` to the
+//! port, both get it going again.
+//!
+//! - `Executor` runs its tasks on the thread calling `block_on`.
+//! - `WorkSharing` runs tasks on several threads that share one port and one run queue.
+//! - `ThreadPerCore` gives every thread its own port and run queue, tasks stay on the
+//!   thread they were spawned on.
+//!
+//! Completions that do not belong to a future, posted results included, are dropped by
+//! the executor threads, so a port driven by an executor should only be used through the
+//! `*_async` methods.
+//!
+//! ```
+//! use iocp_rs::{executor::Executor, fs::FileExt, sim::SimPort, CompletionPort};
+//! use std::sync::Arc;
+//!
+//! let sim = SimPort::new(0);
+//! let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+//! let mut file = sim.file(b"hello".to_vec());
+//! executor.handle().port().add(1, &file).unwrap();
+//!
+//! let task = executor.spawn(async move {
+//!     let completion = file.read_at_async(vec![0; 16], 0).await.unwrap();
+//!     completion.data().to_vec()
+//! });
+//! let data = executor.block_on(task).unwrap();
+//! assert_eq!(data, b"hello");
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io::Result,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    pin::{pin, Pin},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+};
+
+use crate::{CompletionPort, OperationalResult, Reactor};
+
+/// The token of the results posted to wake a parked executor thread.
+const WAKE_TOKEN: usize = usize::MAX - 1;
+
+/// How many tasks a thread runs before it looks at the port again.
+const BUDGET: usize = 64;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+thread_local! {
+    /// The executor whose tasks the current thread runs.
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+
+    /// The executor whose completions the current thread dispatches in `Shared::park`.
+    static TURNING: Cell<*const Shared> = const { Cell::new(ptr::null()) };
+}
+
+struct Task {
+    id: u64,
+    /// `None` once the task finished or its executor shut down.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether the task is in the run queue, so waking it twice queues it once.
+    queued: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Task {
+    fn run(self: &Arc<Self>) {
+        // Cleared first, a wake while the task is polled queues it again.
+        self.queued.store(false, Ordering::SeqCst);
+
+        let mut future = self.future.lock().unwrap();
+        let done = match future.as_mut() {
+            Some(task) => {
+                let waker = Waker::from(self.clone());
+                task.as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_ready()
+            }
+            None => return,
+        };
+
+        if done {
+            *future = None;
+            drop(future);
+            self.shared.tasks.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            let shared = self.shared.clone();
+            shared.schedule(self);
+        }
+    }
+}
+
+/// The run queue and port of one executor, shared by its threads, tasks and handles.
+struct Shared {
+    reactor: Reactor,
+    queue: Mutex<VecDeque<Arc<Task>>>,
+    /// Every unfinished task, so shutting down can drop their futures.
+    tasks: Mutex<HashMap<u64, Arc<Task>>>,
+    next_task: AtomicU64,
+    /// How many threads wait in the port, a wake has to post to reach them.
+    parked: AtomicUsize,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn new(port: Arc<CompletionPort>) -> Arc<Self> {
+        Arc::new(Self {
+            reactor: Reactor::new(port),
+            queue: Mutex::new(VecDeque::new()),
+            tasks: Mutex::new(HashMap::new()),
+            next_task: AtomicU64::new(0),
+            parked: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+        })
+    }
+
+    fn spawn<F>(self: &Arc<Self>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let join = Arc::new(JoinState::default());
+        let task = Arc::new(Task {
+            id: self.next_task.fetch_add(1, Ordering::Relaxed),
+            future: Mutex::new(Some(Box::pin(Spawned {
+                future: Box::pin(future),
+                join: Some(join.clone()),
+            }))),
+            queued: AtomicBool::new(true),
+            shared: self.clone(),
+        });
+
+        if self.shutdown.load(Ordering::SeqCst) {
+            // Dropping the future reports the task as cancelled.
+            task.future.lock().unwrap().take();
+        } else {
+            self.tasks.lock().unwrap().insert(task.id, task.clone());
+            self.schedule(task);
+        }
+
+        JoinHandle { state: join }
+    }
+
+    fn schedule(&self, task: Arc<Task>) {
+        self.queue.lock().unwrap().push_back(task);
+        self.notify();
+    }
+
+    /// Get a parked thread out of the port. Wakes from the completions a thread dispatches
+    /// itself post nothing, it runs the queue once its turn returns.
+    fn notify(&self) {
+        let turning = TURNING.with(|turning| ptr::eq(turning.get(), self));
+        if self.parked.load(Ordering::SeqCst) > 0 && !turning {
+            let _ = self
+                .reactor
+                .port()
+                .post(OperationalResult::new(WAKE_TOKEN, 0, 0, 0));
+        }
+    }
+
+    /// Run queued tasks up to the budget, returns whether there were any.
+    fn run_queued(&self) -> bool {
+        for n in 0..BUDGET {
+            let task = self.queue.lock().unwrap().pop_front();
+            match task {
+                Some(task) => task.run(),
+                None => return n > 0,
+            }
+        }
+
+        true
+    }
+
+    /// Wait in the port until a completion or a wake, unless there is work already.
+    fn park(&self, ready: impl Fn() -> bool) {
+        // Counted before looking at the queue, so a wake after the look posts.
+        self.parked.fetch_add(1, Ordering::SeqCst);
+
+        let idle = self.queue.lock().unwrap().is_empty()
+            && !ready()
+            && !self.shutdown.load(Ordering::SeqCst);
+        if idle {
+            // What comes back are wakes and completions nobody awaits.
+            let previous = TURNING.with(|turning| turning.replace(self));
+            let _ = self.reactor.turn(None);
+            TURNING.with(|turning| turning.set(previous));
+        }
+
+        self.parked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Run tasks on this thread until the executor shuts down.
+    fn work(self: &Arc<Self>) {
+        let _current = enter(self);
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            if !self.run_queued() {
+                self.park(|| false);
+            }
+        }
+
+        // One turn may have taken the wakes meant for the other threads.
+        self.notify();
+    }
+
+    /// Stop and drop every unfinished task, which cancels the operations it awaits.
+    fn close(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks.into_values() {
+            let future = task.future.lock().unwrap().take();
+            drop(future);
+        }
+        // Cleared last, dropping a future may wake other tasks.
+        self.queue.lock().unwrap().clear();
+    }
+
+    /// Post one wake for each thread, which sees the shutdown flag on its way out.
+    fn wake_all(&self, threads: usize) {
+        for _ in 0..threads {
+            let _ = self
+                .reactor
+                .port()
+                .post(OperationalResult::new(WAKE_TOKEN, 0, 0, 0));
+        }
+    }
+}
+
+/// Make `shared` the current executor until the guard is dropped.
+fn enter(shared: &Arc<Shared>) -> impl Drop {
+    struct Restore(Option<Handle>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+
+    let handle = Handle {
+        shared: shared.clone(),
+    };
+    Restore(CURRENT.with(|current| current.borrow_mut().replace(handle)))
+}
+
+/// A spawned future, which reports its output or its panic to the `JoinHandle`.
+struct Spawned<F: Future> {
+    future: Pin<Box<F>>,
+    /// `None` once the output was reported.
+    join: Option<Arc<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let result = match catch_unwind(AssertUnwindSafe(|| self.future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(output)) => Ok(output),
+            Err(payload) => Err(payload),
+        };
+
+        if let Some(join) = self.join.take() {
+            join.finish(result);
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Drop for Spawned<F> {
+    fn drop(&mut self) {
+        if let Some(join) = self.join.take() {
+            join.finish(Err(Box::new("task dropped before it finished")));
+        }
+    }
+}
+
+struct JoinState<T> {
+    slot: Mutex<(Option<thread::Result<T>>, Option<Waker>)>,
+    done: Condvar,
+}
+
+impl<T> Default for JoinState<T> {
+    fn default() -> Self {
+        Self {
+            slot: Mutex::new((None, None)),
+            done: Condvar::new(),
+        }
+    }
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, result: thread::Result<T>) {
+        let waker = {
+            let mut slot = self.slot.lock().unwrap();
+            slot.0 = Some(result);
+            slot.1.take()
+        };
+
+        self.done.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the output of a spawned task, or to its panic like
+/// `std::thread::JoinHandle::join`. Dropping it lets the task run on detached.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Block the calling thread until the task finished, for callers outside an executor.
+    pub fn join(self) -> thread::Result<T> {
+        let mut slot = self.state.slot.lock().unwrap();
+        loop {
+            if let Some(result) = slot.0.take() {
+                return result;
+            }
+            slot = self.state.done.wait(slot).unwrap();
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.slot.lock().unwrap().0.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = thread::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.state.slot.lock().unwrap();
+
+        match slot.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Spawns tasks onto one executor, from any thread.
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// The executor running the current task, `None` outside of one.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// The CompletionPort this executor waits on, objects its tasks use are added to it.
+    pub fn port(&self) -> &Arc<CompletionPort> {
+        self.shared.reactor.port()
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.shared.spawn(future)
+    }
+}
+
+/// Spawn a task onto the executor running the current task.
+///
+/// # Panics
+///
+/// Outside of an executor.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Handle::current()
+        .expect("spawn called outside of an executor")
+        .spawn(future)
+}
+
+/// Wakes the thread in `Executor::block_on`.
+struct Unpark {
+    woken: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.shared.notify();
+    }
+}
+
+/// A single-threaded executor, its tasks run inside `block_on`.
+pub struct Executor {
+    shared: Arc<Shared>,
+}
+
+impl Executor {
+    pub fn new(port: Arc<CompletionPort>) -> Self {
+        Self {
+            shared: Shared::new(port),
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Spawn a task, it runs whenever a thread is in `block_on`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.shared.spawn(future)
+    }
+
+    /// Run `future` to completion on the calling thread, together with the spawned tasks.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let _current = enter(&self.shared);
+        let mut future = pin!(future);
+        let unpark = Arc::new(Unpark {
+            woken: AtomicBool::new(true),
+            shared: self.shared.clone(),
+        });
+        let waker = Waker::from(unpark.clone());
+
+        loop {
+            if unpark.woken.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker))
+                {
+                    return output;
+                }
+            }
+
+            if !self.shared.run_queued() {
+                self.shared.park(|| unpark.woken.load(Ordering::SeqCst));
+            }
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Worker threads that share one CompletionPort and one run queue, a task runs on
+/// whichever thread is free.
+pub struct WorkSharing {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkSharing {
+    pub fn new(port: Arc<CompletionPort>, num_threads: usize) -> Result<Self> {
+        let mut executor = Self {
+            shared: Shared::new(port),
+            threads: Vec::with_capacity(num_threads),
+        };
+
+        for n in 0..num_threads {
+            let shared = executor.shared.clone();
+            let thread = thread::Builder::new()
+                .name(format!("iocp-executor-{n}"))
+                .spawn(move || shared.work())?;
+            executor.threads.push(thread);
+        }
+
+        Ok(executor)
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.shared.spawn(future)
+    }
+
+    /// Run `future` as a task and block the calling thread until it finished.
+    /// A panic of the task is resumed on the calling thread.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn(future)
+            .join()
+            .unwrap_or_else(|payload| resume_unwind(payload))
+    }
+}
+
+impl Drop for WorkSharing {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.wake_all(self.threads.len());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        self.shared.close();
+    }
+}
+
+/// One thread per CompletionPort, each with its own run queue. A task stays on the
+/// thread it was spawned on, so the objects it uses go with that thread's port, see
+/// `Handle::current`.
+pub struct ThreadPerCore {
+    cores: Vec<Arc<Shared>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    next: AtomicUsize,
+}
+
+impl ThreadPerCore {
+    /// Start `num_threads` threads, each on a CompletionPort of its own.
+    pub fn new(num_threads: usize) -> Result<Self> {
+        let ports = (0..num_threads)
+            .map(|_| CompletionPort::new(1).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::with_ports(ports)
+    }
+
+    /// Start one thread on each of `ports`.
+    pub fn with_ports(ports: Vec<Arc<CompletionPort>>) -> Result<Self> {
+        let mut executor = Self {
+            cores: Vec::with_capacity(ports.len()),
+            threads: Vec::with_capacity(ports.len()),
+            next: AtomicUsize::new(0),
+        };
+
+        for (n, port) in ports.into_iter().enumerate() {
+            let shared = Shared::new(port);
+            executor.cores.push(shared.clone());
+            let thread = thread::Builder::new()
+                .name(format!("iocp-core-{n}"))
+                .spawn(move || shared.work())?;
+            executor.threads.push(thread);
+        }
+
+        Ok(executor)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cores.is_empty()
+    }
+
+    /// The executor of thread `index`.
+    pub fn handle(&self, index: usize) -> Handle {
+        Handle {
+            shared: self.cores[index].clone(),
+        }
+    }
+
+    /// Spawn a task on the threads in turn.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.cores.len();
+        self.cores[index].spawn(future)
+    }
+
+    /// Run `future` as a task and block the calling thread until it finished.
+    /// A panic of the task is resumed on the calling thread.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn(future)
+            .join()
+            .unwrap_or_else(|payload| resume_unwind(payload))
+    }
+}
+
+impl Drop for ThreadPerCore {
+    fn drop(&mut self) {
+        for core in &self.cores {
+            core.shutdown.store(true, Ordering::SeqCst);
+            core.wake_all(1);
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        for core in &self.cores {
+            core.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use crate::{fs::FileExt, net::TcpStreamExt, sim::SimPort, CompletionPort};
+
+    use super::{Executor, Handle, ThreadPerCore, WorkSharing};
+
+    fn panic_message(payload: &(dyn Any + Send)) -> &str {
+        payload.downcast_ref::<&str>().copied().unwrap_or("")
+    }
+
+    #[test]
+    fn block_on_with_tasks() {
+        let sim = SimPort::new(1);
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let (mut a, b) = sim.socket_pair();
+        executor.handle().port().add(1, &a).unwrap();
+        executor.handle().port().add(2, &b).unwrap();
+
+        let writer = executor.spawn(async move {
+            let completion = b.write_async(b"ping".to_vec()).await.unwrap();
+            completion.bytes_used()
+        });
+        let data = executor.block_on(async move {
+            let completion = a.read_async(vec![0; 8]).await.unwrap();
+            assert_eq!(writer.await.unwrap(), 4);
+            completion.data().to_vec()
+        });
+        assert_eq!(data, b"ping");
+    }
+
+    #[test]
+    fn no_wake_posted_from_own_turn() {
+        let sim = SimPort::new(1);
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let mut file = sim.file(b"hello".to_vec());
+        executor.handle().port().add(1, &file).unwrap();
+
+        let data = executor.block_on(async move {
+            let completion = file.read_at_async(vec![0; 8], 0).await.unwrap();
+            completion.data().to_vec()
+        });
+        assert_eq!(data, b"hello");
+        // The read woke the future from the thread's own turn, nothing was posted.
+        assert!(executor.handle().port().get(Some(Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn wake_from_another_thread() {
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(SimPort::new(2))));
+        let handle = executor.handle();
+
+        // Parked in the port when the task is spawned.
+        let spawner = spawn(move || {
+            sleep(Duration::from_millis(50));
+            handle.spawn(async { 7 })
+        });
+        let task = spawner.join().unwrap();
+        assert_eq!(executor.block_on(task).unwrap(), 7);
+    }
+
+    #[test]
+    fn panics_reach_the_join_handle() {
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(SimPort::new(3))));
+
+        let task = executor.spawn(async { panic!("task failed") });
+        let payload = executor.block_on(task).unwrap_err();
+        assert_eq!(panic_message(&*payload), "task failed");
+
+        // Tasks dropped with the executor report it.
+        let never = executor.spawn(std::future::pending::<()>());
+        drop(executor);
+        assert!(never.join().is_err());
+    }
+
+    #[test]
+    fn work_sharing() {
+        let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(4)));
+        let executor = WorkSharing::new(cmp, 4).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..100)
+            .map(|_| {
+                let count = count.clone();
+                executor.spawn(async move {
+                    // Spawning from inside a task goes to the same executor.
+                    super::spawn(async move { count.fetch_add(1, Ordering::SeqCst) })
+                        .await
+                        .unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        executor.block_on(async move {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn thread_per_core() {
+        let sims = [SimPort::new(5), SimPort::new(6)];
+        let ports = sims
+            .iter()
+            .map(|sim| Arc::new(CompletionPort::with_driver(sim.clone())))
+            .collect();
+        let executor = ThreadPerCore::with_ports(ports).unwrap();
+        assert_eq!(executor.len(), 2);
+
+        for (index, sim) in sims.iter().enumerate() {
+            let (mut a, b) = sim.socket_pair();
+            let port = executor.handle(index).port().clone();
+            let task = executor.handle(index).spawn(async move {
+                // The task runs on the thread of the port it was spawned for.
+                let current = Handle::current().unwrap();
+                assert!(Arc::ptr_eq(current.port(), &port));
+                port.add(1, &a).unwrap();
+                port.add(2, &b).unwrap();
+
+                let read = a.read_async(vec![0; 8]);
+                b.write_async(b"core".to_vec()).await.unwrap();
+                read.await.unwrap().data().to_vec()
+            });
+            assert_eq!(task.join().unwrap(), b"core");
+        }
+    }
+}
@@ -1,68 +1,57 @@
-use windows_sys::Win32::Foundation::ERROR_IO_PENDING;
-use windows_sys::Win32::Storage::FileSystem::{ReadFile, WriteFile};
-use windows_sys::Win32::{Foundation::HANDLE};
-
-
 use std::io::{Result};
+use std::time::Instant;
 
-
-use std::ptr::null_mut;
-
+use crate::buf::Buffer;
+use crate::completion_port::{submit, submit_awaited};
 use crate::context::IOType;
-
-use crate::utils::{cvt, len};
+use crate::driver::Operation;
 use crate::{
-    AsHandle, Context,
+    AsHandle, IoBuf, IoBufMut, OpFuture, OperationId,
 };
 
 /// Addtional method for the `File` type.
 pub trait FileExt: AsHandle {
 
     /// Execute an ovelapped read I/O on this file.
-    /// 
-    fn _read(&mut self, mut buff: Vec<u8>, offset: u64) -> Result<Context> {
-        let len = len(&buff);
-        let buff_ptr = buff.as_mut_ptr();
-        let handle = self.as_handle();
-        let mut context = Context::new(handle, buff, IOType::Read);
-        let over_lapped_ptr = context.over_lapped_ptr();
-        context.set_offset(offset);
-
-        let ret = unsafe { ReadFile(handle, buff_ptr as *mut _, len, null_mut(), over_lapped_ptr) };
-
-        match cvt(ret) {
-            Ok(_) => Ok(context),
-            Err(e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32) => Ok(context),
-            Err(e) => Err(e),
-        }
+    /// This issues `ReadFile` on Windows and `IORING_OP_READ` on Linux.
+    fn _read<B: IoBufMut>(&mut self, buff: B, offset: u64) -> Result<OperationId> {
+        self.read_at_with_deadline(buff, offset, None)
     }
 
     /// Execute an overlapped write I/O on this file.
-    /// 
-    fn _write(&self, buff: Vec<u8>, offset: u64) -> Result<Context> {
-        let len = len(&buff);
-        let buff_ptr = buff.as_ptr();
-        let handle = self.as_handle() as HANDLE;
-        let mut context = Context::new(handle, buff, IOType::Write);
-        let over_lapped_ptr = context.over_lapped_ptr();
-        context.set_offset(offset);
-
-        let ret = unsafe { WriteFile(handle, buff_ptr, len, null_mut(), over_lapped_ptr) };
-
-        match cvt(ret) {
-            Ok(_) => Ok(context),
-            Err(e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32) => Ok(context),
-            Err(e) => Err(e),
-        }
+    /// This issues `WriteFile` on Windows and `IORING_OP_WRITE` on Linux.
+    fn _write<B: IoBuf>(&self, buff: B, offset: u64) -> Result<OperationId> {
+        self.write_at_with_deadline(buff, offset, None)
+    }
+
+    /// Like `read_at`, as a future that resolves to the completion, see `OpFuture`.
+    fn read_at_async<B: IoBufMut>(&mut self, buff: B, offset: u64) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Read { offset })
+    }
+
+    /// Like `write_at`, as a future that resolves to the completion.
+    fn write_at_async<B: IoBuf>(&self, buff: B, offset: u64) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Write { offset })
+    }
+
+    /// Like `read_at`, but past `deadline` the read is cancelled and completes with
+    /// `ErrorKind::TimedOut`, see `OperationalResult::is_timed_out`.
+    fn read_at_with_deadline<B: IoBufMut>(&mut self, buff: B, offset: u64, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Read { offset }, deadline)
+    }
+
+    /// Like `write_at`, but past `deadline` the write is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn write_at_with_deadline<B: IoBuf>(&self, buff: B, offset: u64, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Write { offset }, deadline)
     }
 
     ///
-    /// ```
+    /// ```ignore-linux
     /// use iocp_rs::{CompletionPort, fs::FileExt, AsHandle};
     /// use std::io::Result;
     /// use std::path::Path;
     /// use std::fs::{OpenOptions, File};
-    /// use std::collections::HashMap;
     /// use std::os::windows::io::AsRawHandle;
     /// use std::os::windows::fs::OpenOptionsExt;
     /// use windows_sys::Win32::{Storage::FileSystem::FILE_FLAG_OVERLAPPED, Foundation::HANDLE};
@@ -72,8 +61,7 @@ pub trait FileExt: AsHandle {
     /// }
     /// 
     /// impl AsHandle for MyFile {
-    ///     type Handle = HANDLE;
-    ///     fn as_handle(&self) -> Self::Handle {
+    ///     fn as_handle(&self) -> HANDLE {
     ///         self.inner.as_raw_handle() as HANDLE
     ///     }
     /// }
@@ -91,32 +79,29 @@ pub trait FileExt: AsHandle {
     ///     cmp.add(1, &file)?;
     ///     let mut buff = vec![0; 10];
     ///     
-    ///     let context = FileExt::read(&mut file, buff)?;
-    ///     let mut map = HashMap::new();
-    ///     map.insert(1, context);
+    ///     let id = FileExt::read(&mut file, buff)?;
     ///
-    ///     let mut result_list = cmp.get_many(map.len(), None)?;
-    ///     let result = result_list.remove(0);
-    ///     let context = map.remove(&result.token()).unwrap();
-    ///     assert_eq!(&context.get_buff()[..result.bytes_used() as usize], b"123sdf");
-    ///     assert_eq!(result.bytes_used() as usize, 6usize);
+    ///     let mut completion_list = cmp.get_many(1, None)?;
+    ///     let completion = completion_list.remove(0);
+    ///     assert_eq!(completion.id(), Some(id));
+    ///     assert_eq!(completion.data(), b"123sdf");
+    ///     assert_eq!(completion.bytes_used() as usize, 6usize);
     ///     Ok(())
     /// }
     /// ```
-    fn read(&mut self, buff: Vec<u8>) -> Result<Context> {
+    fn read<B: IoBufMut>(&mut self, buff: B) -> Result<OperationId> {
         self._read(buff, 0)
     }
 
-    fn read_at(&mut self, buff: Vec<u8>, offset: u64) -> Result<Context> {
+    fn read_at<B: IoBufMut>(&mut self, buff: B, offset: u64) -> Result<OperationId> {
         self._read(buff, offset)
     }
 
     /// 
-    /// ```
+    /// ```ignore-linux
     /// use iocp_rs::{CompletionPort, fs::FileExt, AsHandle};
     /// use std::io::Result;
     /// use std::path::Path;
-    /// use std::collections::HashMap;
     /// use std::fs::{OpenOptions, File};
     /// use std::os::windows::io::AsRawHandle;
     /// use std::os::windows::fs::OpenOptionsExt;
@@ -127,8 +112,7 @@ pub trait FileExt: AsHandle {
     /// }
     /// 
     /// impl AsHandle for MyFile {
-    ///     type Handle = HANDLE;
-    ///     fn as_handle(&self) -> Self::Handle {
+    ///     fn as_handle(&self) -> HANDLE {
     ///         self.inner.as_raw_handle() as HANDLE
     ///     }
     /// }
@@ -146,29 +130,27 @@ pub trait FileExt: AsHandle {
     ///     cmp.add(1, &file)?;
     ///     let mut buff = b"123".to_vec();
     ///     
-    ///     let context = file.write(buff)?;
-    ///     let mut map = HashMap::new();
-    ///     map.insert(1, context);
+    ///     let id = file.write(buff)?;
     ///
-    ///     let mut result_list = cmp.get_many(map.len(), None)?;
-    ///     for result in result_list {
-    ///         if map.contains_key(&result.token()) {
-    ///             dbg!(result.bytes_used());
-    ///         }   
+    ///     let completion_list = cmp.get_many(1, None)?;
+    ///     for completion in completion_list {
+    ///         if completion.id() == Some(id) {
+    ///             dbg!(completion.bytes_used());
+    ///         }
     ///     }
     ///     Ok(())
     /// }
     /// ```
-    fn write(&self, buff: Vec<u8>) -> Result<Context> {
+    fn write<B: IoBuf>(&self, buff: B) -> Result<OperationId> {
         self._write(buff, 0)
     }
 
-    fn write_at(&self, buff: Vec<u8>, offset: u64) -> Result<Context> {
+    fn write_at<B: IoBuf>(&self, buff: B, offset: u64) -> Result<OperationId> {
         self._write(buff, offset)
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, windows))]
 mod tests {
     use windows_sys::Win32::Foundation::HANDLE;
     use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
@@ -202,13 +184,10 @@ mod tests {
         cmp.add(1, &file).unwrap();
         let buff = vec![0; 10];
 
-        let context = FileExt::read(&mut file, buff).unwrap();
+        FileExt::read(&mut file, buff).unwrap();
 
-        let result = cmp.get(None).unwrap();
-        assert_eq!(
-            &context.get_buff()[..result.bytes_used() as usize],
-            b"123".as_slice()
-        );
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.data(), b"123".as_slice());
     }
 
     #[test]
@@ -223,13 +202,56 @@ mod tests {
         cmp.add(1, &file).unwrap();
         let buff = b"123".to_vec();
 
-        let context = file.write(buff).unwrap();
+        file.write(buff).unwrap();
 
-        let result = cmp.get(None).unwrap();
-        assert_eq!(&context.get_buff()[..result.bytes_used() as usize], b"123");
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.data(), b"123");
         // let (mut result_list, list) = cmp.get_many(list, None).unwrap();
         // let (buff, size, _io_type) = result_list.remove(0).get();
         // assert_eq!(&buff, b"123");
         // assert_eq!(size, 3);
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    use crate::{fs::FileExt, AsHandle, CompletionPort, RawHandle};
+
+    impl AsHandle for File {
+        fn as_handle(&self) -> RawHandle {
+            self.as_raw_fd()
+        }
+    }
+
+    impl FileExt for File {}
+
+    #[test]
+    fn write_read_file() {
+        let path = std::env::temp_dir().join(format!("iocp-rs-{}-file.txt", std::process::id()));
+        let cmp = CompletionPort::new(1).unwrap();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        cmp.add(1, &file).unwrap();
+
+        let id = file.write_at(b"123sdf".to_vec(), 2).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.bytes_used(), 6);
+        assert_eq!(completion.result().offset(), 2);
+        assert_eq!(completion.context().unwrap().offset(), 2);
+
+        file.read_at(vec![0; 10], 5).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.data(), b"sdf");
+
+        drop(file);
+        std::fs::remove_file(path).unwrap();
+    }
+}
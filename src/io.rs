@@ -0,0 +1,458 @@
+//! Poll-based adapters over the completion-based `*Ext` traits.
+//!
+//! `AsyncStream` and `AsyncFile` own a buffer per direction, issue the `*_async`
+//! operations with it and copy between it and the caller's slice, so code written
+//! against `AsyncRead`/`AsyncWrite` runs on a CompletionPort. With the `futures-io`
+//! feature they implement the traits of `futures-io`, with the `tokio` feature those of
+//! tokio.
+//!
+//! Like every `*_async` operation, the ones issued here complete once a thread waits on
+//! the port, an executor from `executor` or a `Reactor` does that.
+//!
+//! A write is accepted as soon as it is copied and issued, its error if any comes out of
+//! the next write or flush. Dropping an adapter cancels what is still in flight, flush
+//! it first.
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind, Result},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use crate::{fs::FileExt, net::TcpStreamExt, Completion, OpFuture};
+
+/// The size of the buffers the adapters issue their operations with.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// The read and write side of an adapter, the object issues the operations.
+#[derive(Default)]
+struct Buffers {
+    /// Received bytes not yet copied out, from `consumed` on.
+    read: Vec<u8>,
+    consumed: usize,
+    reading: Option<OpFuture>,
+    /// The allocation of the last finished write, reused by the next.
+    spare: Vec<u8>,
+    writing: Option<(OpFuture, u64)>,
+}
+
+/// The buffer of a finished operation.
+fn into_buff(completion: Completion) -> Vec<u8> {
+    completion
+        .into_context()
+        .map(|context| context.into_buff())
+        .unwrap_or_default()
+}
+
+impl Buffers {
+    fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        offset: &mut u64,
+        mut read: impl FnMut(Vec<u8>, u64) -> OpFuture,
+    ) -> Poll<Result<usize>> {
+        if self.consumed == self.read.len() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let op = match &mut self.reading {
+                Some(op) => op,
+                None => {
+                    let mut buff = std::mem::take(&mut self.read);
+                    self.consumed = 0;
+                    buff.resize(BUFFER_SIZE, 0);
+                    self.reading.insert(read(buff, *offset))
+                }
+            };
+            let completion = ready!(Pin::new(op).poll(cx));
+            self.reading = None;
+
+            let completion = completion?;
+            let n = completion.status()? as usize;
+            self.read = into_buff(completion);
+            self.read.truncate(n);
+            self.consumed = 0;
+            *offset += n as u64;
+        }
+
+        let n = buf.len().min(self.read.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.read[self.consumed..self.consumed + n]);
+        self.consumed += n;
+
+        Poll::Ready(Ok(n))
+    }
+
+    /// How many bytes were read ahead but not copied out yet.
+    fn unread(&self) -> usize {
+        self.read.len() - self.consumed
+    }
+
+    /// Drop the bytes read ahead and the read in flight, returns how many bytes were
+    /// dropped that `poll_read` had counted as read.
+    fn discard_read(&mut self) -> usize {
+        let unread = self.unread();
+        self.reading = None;
+        self.read.clear();
+        self.consumed = 0;
+        unread
+    }
+
+    fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        offset: &mut u64,
+        mut write: impl FnMut(Vec<u8>, u64) -> OpFuture,
+    ) -> Poll<Result<usize>> {
+        ready!(self.poll_flush(cx, &mut write))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(BUFFER_SIZE);
+        let mut buff = std::mem::take(&mut self.spare);
+        buff.extend_from_slice(&buf[..n]);
+        self.writing = Some((write(buff, *offset), *offset));
+        *offset += n as u64;
+
+        Poll::Ready(Ok(n))
+    }
+
+    /// Wait for the write in flight, issuing the rest again after a short one.
+    fn poll_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut write: impl FnMut(Vec<u8>, u64) -> OpFuture,
+    ) -> Poll<Result<()>> {
+        while let Some((op, offset)) = &mut self.writing {
+            let offset = *offset;
+            let completion = ready!(Pin::new(op).poll(cx));
+            self.writing = None;
+
+            let completion = completion?;
+            let n = completion.status()? as usize;
+            let mut buff = into_buff(completion);
+            if n >= buff.len() {
+                buff.clear();
+                self.spare = buff;
+            } else if n == 0 {
+                return Poll::Ready(Err(Error::from(ErrorKind::WriteZero)));
+            } else {
+                buff.drain(..n);
+                self.writing = Some((write(buff, offset + n as u64), offset + n as u64));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// `AsyncRead` and `AsyncWrite` over a stream registered with a CompletionPort, through
+/// `TcpStreamExt::read_async` and `write_async`.
+///
+/// ```
+/// use iocp_rs::{executor::Executor, io::AsyncStream, sim::SimPort, CompletionPort};
+/// use std::{future::poll_fn, sync::Arc};
+///
+/// let sim = SimPort::new(0);
+/// let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+/// let (a, b) = sim.socket_pair();
+/// executor.handle().port().add(1, &a).unwrap();
+/// executor.handle().port().add(2, &b).unwrap();
+///
+/// let (mut a, mut b) = (AsyncStream::new(a), AsyncStream::new(b));
+/// executor.block_on(async {
+///     poll_fn(|cx| a.poll_write(cx, b"hello")).await.unwrap();
+///     poll_fn(|cx| a.poll_flush(cx)).await.unwrap();
+///
+///     let mut buf = [0; 16];
+///     let n = poll_fn(|cx| b.poll_read(cx, &mut buf)).await.unwrap();
+///     assert_eq!(&buf[..n], b"hello");
+/// });
+/// ```
+pub struct AsyncStream<T> {
+    io: T,
+    buffers: Buffers,
+}
+
+impl<T: TcpStreamExt> AsyncStream<T> {
+    /// Wrap `io`, which must already be added to a CompletionPort.
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            buffers: Buffers::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// The stream, anything received but not read yet is lost.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Copy received bytes into `buf`, receiving more first if none are left.
+    /// `Ok(0)` means the peer closed the stream.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let io = &mut self.io;
+        self.buffers
+            .poll_read(cx, buf, &mut 0, |buff, _| io.read_async(buff))
+    }
+
+    /// Wait for the previous write, then issue the start of `buf`, returns how much.
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let io = &self.io;
+        self.buffers
+            .poll_write(cx, buf, &mut 0, |buff, _| io.write_async(buff))
+    }
+
+    /// Wait until everything written was sent.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let io = &self.io;
+        self.buffers.poll_flush(cx, |buff, _| io.write_async(buff))
+    }
+}
+
+/// `AsyncRead` and `AsyncWrite` over a file registered with a CompletionPort, through
+/// `FileExt::read_at_async` and `write_at_async` at a position of its own.
+pub struct AsyncFile<T> {
+    io: T,
+    /// Where the next read is issued, past the bytes read ahead.
+    position: u64,
+    buffers: Buffers,
+}
+
+impl<T: FileExt> AsyncFile<T> {
+    /// Wrap `io`, which must already be added to a CompletionPort, starting at offset 0.
+    pub fn new(io: T) -> Self {
+        Self::with_position(io, 0)
+    }
+
+    pub fn with_position(io: T, position: u64) -> Self {
+        Self {
+            io,
+            position,
+            buffers: Buffers::default(),
+        }
+    }
+
+    /// The offset the next read or write starts at, bytes read ahead into the buffer
+    /// but not read yet are not counted.
+    pub fn position(&self) -> u64 {
+        self.position - self.buffers.unread() as u64
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Copy read bytes into `buf`, reading more first if none are left.
+    /// `Ok(0)` means the end of the file.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let io = &mut self.io;
+        self.buffers
+            .poll_read(cx, buf, &mut self.position, |buff, offset| {
+                io.read_at_async(buff, offset)
+            })
+    }
+
+    /// Wait for the previous write, then issue the start of `buf`, returns how much.
+    /// What was read ahead is dropped, the write starts where the reads stopped.
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.position -= self.buffers.discard_read() as u64;
+
+        let io = &self.io;
+        self.buffers
+            .poll_write(cx, buf, &mut self.position, |buff, offset| {
+                io.write_at_async(buff, offset)
+            })
+    }
+
+    /// Wait until everything written reached the file.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let io = &self.io;
+        self.buffers
+            .poll_flush(cx, |buff, offset| io.write_at_async(buff, offset))
+    }
+}
+
+/// `AsyncRead`/`AsyncWrite` impls that forward to the inherent `poll_*` methods.
+macro_rules! impl_async_io {
+    ($ty:ident, $ext:ident) => {
+        #[cfg(feature = "futures-io")]
+        impl<T: $ext + Unpin> futures_io::AsyncRead for $ty<T> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                self.get_mut().poll_read(cx, buf)
+            }
+        }
+
+        #[cfg(feature = "futures-io")]
+        impl<T: $ext + Unpin> futures_io::AsyncWrite for $ty<T> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                self.get_mut().poll_write(cx, buf)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                self.get_mut().poll_flush(cx)
+            }
+
+            /// Flushes, the object itself stays open until the adapter is dropped.
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                self.get_mut().poll_flush(cx)
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        impl<T: $ext + Unpin> tokio::io::AsyncRead for $ty<T> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut tokio::io::ReadBuf<'_>,
+            ) -> Poll<Result<()>> {
+                let n = ready!(self.get_mut().poll_read(cx, buf.initialize_unfilled()))?;
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        impl<T: $ext + Unpin> tokio::io::AsyncWrite for $ty<T> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                self.get_mut().poll_write(cx, buf)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                self.get_mut().poll_flush(cx)
+            }
+
+            /// Flushes, the object itself stays open until the adapter is dropped.
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                self.get_mut().poll_flush(cx)
+            }
+        }
+    };
+}
+
+impl_async_io!(AsyncStream, TcpStreamExt);
+impl_async_io!(AsyncFile, FileExt);
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, sync::Arc};
+
+    use crate::{executor::Executor, sim::SimPort, CompletionPort};
+
+    use super::{AsyncFile, AsyncStream, BUFFER_SIZE};
+
+    #[test]
+    fn stream_round_trip() {
+        let sim = SimPort::new(1);
+        sim.set_partial_transfers(true);
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let (a, b) = sim.socket_pair();
+        executor.handle().port().add(1, &a).unwrap();
+        executor.handle().port().add(2, &b).unwrap();
+        let (mut a, mut b) = (AsyncStream::new(a), AsyncStream::new(b));
+
+        let sent = (0..3 * BUFFER_SIZE).map(|n| n as u8).collect::<Vec<_>>();
+        let received = executor.block_on(async {
+            let mut written = 0;
+            while written < sent.len() {
+                written += poll_fn(|cx| a.poll_write(cx, &sent[written..]))
+                    .await
+                    .unwrap();
+            }
+            poll_fn(|cx| a.poll_flush(cx)).await.unwrap();
+            drop(a);
+
+            // Small reads are served from what was received before.
+            let mut received = Vec::new();
+            let mut buf = [0; 100];
+            loop {
+                match poll_fn(|cx| b.poll_read(cx, &mut buf)).await.unwrap() {
+                    0 => break received,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn file_position() {
+        let sim = SimPort::new(2);
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let file = sim.file(b"hello world".to_vec());
+        executor.handle().port().add(1, &file).unwrap();
+        let mut file = AsyncFile::with_position(file, 6);
+
+        executor.block_on(async {
+            let mut buf = [0; 3];
+            let n = poll_fn(|cx| file.poll_read(cx, &mut buf)).await.unwrap();
+            assert_eq!(&buf[..n], b"wor");
+            // The rest was read ahead, but not read.
+            assert_eq!(file.position(), 9);
+            let n = poll_fn(|cx| file.poll_read(cx, &mut buf)).await.unwrap();
+            assert_eq!(&buf[..n], b"ld");
+            assert_eq!(poll_fn(|cx| file.poll_read(cx, &mut buf)).await.unwrap(), 0);
+
+            poll_fn(|cx| file.poll_write(cx, b"!")).await.unwrap();
+            poll_fn(|cx| file.poll_flush(cx)).await.unwrap();
+        });
+        assert_eq!(file.position(), 12);
+        assert_eq!(file.get_ref().contents(), b"hello world!");
+    }
+
+    #[test]
+    fn write_after_read_ahead() {
+        let sim = SimPort::new(2);
+        let executor = Executor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let file = sim.file(b"hello world".to_vec());
+        executor.handle().port().add(1, &file).unwrap();
+        let mut file = AsyncFile::new(file);
+
+        executor.block_on(async {
+            let mut buf = [0; 3];
+            let n = poll_fn(|cx| file.poll_read(cx, &mut buf)).await.unwrap();
+            assert_eq!(&buf[..n], b"hel");
+
+            poll_fn(|cx| file.poll_write(cx, b"LO")).await.unwrap();
+            poll_fn(|cx| file.poll_flush(cx)).await.unwrap();
+            assert_eq!(file.position(), 5);
+
+            // Reading goes on after the write, not from the dropped read-ahead.
+            let n = poll_fn(|cx| file.poll_read(cx, &mut buf)).await.unwrap();
+            assert_eq!(&buf[..n], b" wo");
+        });
+        assert_eq!(file.get_ref().contents(), b"helLO world");
+    }
+}
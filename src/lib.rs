@@ -1,13 +1,38 @@
 mod as_handle;
+mod buf;
+mod buffer_pool;
+mod completion;
 mod completion_port;
 mod context;
+pub mod driver;
+pub mod executor;
+mod event_loop;
 pub mod fs;
+pub mod io;
+#[cfg(target_os = "linux")]
+mod linux;
 pub mod net;
+mod notifier;
 mod operational_result;
+mod reactor;
+pub mod sim;
+mod timer;
 mod utils;
+mod worker_pool;
+#[cfg(windows)]
+mod windows;
 
-pub use as_handle::AsHandle;
+pub use as_handle::{AsHandle, RawHandle};
+pub use buf::{IoBuf, IoBufMut, Slice};
+pub use buffer_pool::{BufferPool, PoolStats, PooledBuf};
+pub use completion::Completion;
 pub use completion_port::CompletionPort;
-pub use context::Context;
+pub use context::{Context, OperationId};
+pub use driver::{Driver, Operation};
+pub use event_loop::{EventLoop, Handler};
+pub use notifier::Notifier;
 pub use operational_result::OperationalResult;
+pub use reactor::{OpFuture, Reactor};
+pub use timer::TimerId;
+pub use worker_pool::WorkerPool;
 pub(crate) use utils::*;
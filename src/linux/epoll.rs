@@ -0,0 +1,802 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    os::fd::RawFd,
+    ptr::null_mut,
+    sync::{Arc, Condvar, Mutex},
+    thread::{Builder, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    context::{OpState, OperationId},
+    cvt,
+    driver::{Driver, Operation},
+    net::{transmit::Transmit, SocketAddrCRepr},
+    set_socket_nonblocking, Context, OperationalResult, RawHandle,
+};
+
+/// `epoll_event.u64` of the wakeup eventfd, every other event carries its file descriptor.
+const WAKE_DATA: u64 = u64::MAX;
+
+/// How many readiness events one `epoll_wait` can return.
+const EVENTS_CAPACITY: usize = 64;
+
+/// An operation that has not been performed yet.
+struct Pending {
+    id: OperationId,
+    fd: RawFd,
+    token: usize,
+    /// Points into the buffer owned by the operation's Context.
+    buff_ptr: *mut u8,
+    buff_len: u32,
+    /// The scatter/gather array of a vectored Context.
+    iov_ptr: *mut libc::iovec,
+    iov_len: u32,
+    /// Where an accept or receive-from writes the peer address, in the state of the
+    /// Context.
+    name: *mut libc::sockaddr,
+    name_len: *mut libc::socklen_t,
+    /// Whether a connect is under way and only its outcome is left to pick up.
+    connecting: Cell<bool>,
+    /// The transmit state of the Context, null for other operations.
+    transmit: *mut Transmit,
+    /// The `msghdr` in the message state of the Context, null for other operations.
+    message: *mut libc::msghdr,
+    op: Operation,
+}
+
+unsafe impl Send for Pending {}
+
+impl Pending {
+    /// A `msghdr` over the buffers of a vectored operation.
+    fn msg(&self) -> libc::msghdr {
+        let mut msg = unsafe { zeroed::<libc::msghdr>() };
+        msg.msg_iov = self.iov_ptr;
+        msg.msg_iovlen = self.iov_len as _;
+        msg
+    }
+
+    /// Start a connect without blocking, or pick up its outcome once it is under way.
+    fn connect(&self, addr: &SocketAddr) -> Option<i32> {
+        if self.connecting.get() {
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll_fd, 1, 0) } == 0 {
+                return None;
+            }
+
+            let mut error: libc::c_int = 0;
+            let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    self.fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_ERROR,
+                    &mut error as *mut _ as *mut _,
+                    &mut len,
+                )
+            };
+            return Some(match ret {
+                0 => -error,
+                _ => -Error::last_os_error().raw_os_error().unwrap_or(libc::EIO),
+            });
+        }
+
+        // The socket was made nonblocking when it was registered, the kernel carries on
+        // with the connect after the call.
+        let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(addr);
+        let ret = match unsafe { libc::connect(self.fd, addr.as_ptr(), addr_len) } {
+            0 => 0,
+            _ => -Error::last_os_error().raw_os_error().unwrap_or(libc::EIO),
+        };
+
+        match -ret {
+            libc::EINPROGRESS | libc::EINTR => {
+                self.connecting.set(true);
+                None
+            }
+            _ => Some(ret),
+        }
+    }
+
+    /// Perform the operation without blocking, `None` means it has to wait for readiness.
+    fn perform(&self) -> Option<i32> {
+        if let Operation::Connect(ref addr) = self.op {
+            return self.connect(addr);
+        }
+        if let Operation::TransmitFile { .. } = self.op {
+            return unsafe { (*self.transmit).perform(self.fd) };
+        }
+
+        let buff_ptr = self.buff_ptr as *mut libc::c_void;
+        let buff_len = self.buff_len as usize;
+
+        loop {
+            let ret = unsafe {
+                match self.op {
+                    Operation::Read { offset } => {
+                        libc::pread(self.fd, buff_ptr, buff_len, offset as libc::off_t)
+                    }
+                    Operation::Write { offset } => {
+                        libc::pwrite(self.fd, buff_ptr, buff_len, offset as libc::off_t)
+                    }
+                    Operation::Recv => libc::recv(self.fd, buff_ptr, buff_len, libc::MSG_DONTWAIT),
+                    Operation::Send => libc::send(
+                        self.fd,
+                        buff_ptr,
+                        buff_len,
+                        libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                    ),
+                    Operation::SendTo(ref addr) => {
+                        let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(addr);
+                        libc::sendto(
+                            self.fd,
+                            buff_ptr,
+                            buff_len,
+                            libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                            addr.as_ptr(),
+                            addr_len,
+                        )
+                    }
+                    Operation::RecvFrom => libc::recvfrom(
+                        self.fd,
+                        buff_ptr,
+                        buff_len,
+                        libc::MSG_DONTWAIT,
+                        self.name,
+                        self.name_len,
+                    ),
+                    Operation::RecvVectored => {
+                        libc::recvmsg(self.fd, &mut self.msg(), libc::MSG_DONTWAIT)
+                    }
+                    Operation::SendVectored => libc::sendmsg(
+                        self.fd,
+                        &self.msg(),
+                        libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                    ),
+                    Operation::SendToVectored(ref addr) => {
+                        let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(addr);
+                        let mut msg = self.msg();
+                        msg.msg_name = addr.as_ptr() as *mut libc::c_void;
+                        msg.msg_namelen = addr_len;
+                        libc::sendmsg(self.fd, &msg, libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL)
+                    }
+                    // The listener was made nonblocking when it was registered, a connection
+                    // someone else took first leaves the accept parked.
+                    Operation::Accept => {
+                        libc::accept4(self.fd, self.name, self.name_len, libc::SOCK_CLOEXEC)
+                            as isize
+                    }
+                    Operation::RecvMsg => libc::recvmsg(self.fd, self.message, libc::MSG_DONTWAIT),
+                    Operation::SendMsg => libc::sendmsg(
+                        self.fd,
+                        self.message,
+                        libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                    ),
+                    Operation::Disconnect { .. } => {
+                        libc::shutdown(self.fd, libc::SHUT_RDWR) as isize
+                    }
+                    Operation::Connect(_) => unreachable!("connects are performed on their own"),
+                    Operation::TransmitFile { .. } => {
+                        unreachable!("transmits are performed on their own")
+                    }
+                }
+            };
+
+            if ret >= 0 {
+                return Some(ret.min(i32::MAX as isize) as i32);
+            }
+
+            match Error::last_os_error().raw_os_error().unwrap_or(libc::EIO) {
+                libc::EINTR => continue,
+                libc::EAGAIN => return None,
+                errno => return Some(-errno),
+            }
+        }
+    }
+
+    /// The completion of the operation, from a byte count or a negated errno.
+    fn result(&self, ret: i32) -> OperationalResult {
+        let status = if ret < 0 { -ret } else { 0 };
+        OperationalResult::new(self.token, self.op.offset(), ret.max(0) as u32, status)
+            .with_id(self.id)
+    }
+
+    /// Socket operations wait for readiness, everything else goes to the helper threads.
+    fn readiness(&self) -> Option<u32> {
+        match self.op {
+            Operation::Recv
+            | Operation::RecvFrom
+            | Operation::RecvVectored
+            | Operation::RecvMsg
+            | Operation::Accept => Some(libc::EPOLLIN as u32),
+            Operation::Send
+            | Operation::SendTo(_)
+            | Operation::SendVectored
+            | Operation::SendToVectored(_)
+            | Operation::SendMsg
+            | Operation::Connect(_)
+            | Operation::Disconnect { .. }
+            | Operation::TransmitFile { .. } => Some(libc::EPOLLOUT as u32),
+            Operation::Read { .. } | Operation::Write { .. } => None,
+        }
+    }
+}
+
+/// The operations parked on one file descriptor, in submission order.
+#[derive(Default)]
+struct Parked {
+    reads: VecDeque<OperationId>,
+    writes: VecDeque<OperationId>,
+    /// The events the file descriptor is registered with in the epoll set, 0 when it is not.
+    interest: u32,
+}
+
+#[derive(Default)]
+struct State {
+    pending: HashMap<OperationId, Pending>,
+    parked: HashMap<RawFd, Parked>,
+    ready: VecDeque<OperationalResult>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    queue: VecDeque<Pending>,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    jobs: Mutex<Jobs>,
+    job_ready: Condvar,
+    event_fd: RawFd,
+}
+
+impl Shared {
+    fn complete(&self, result: OperationalResult) {
+        self.state.lock().unwrap().ready.push_back(result);
+        self.wake();
+    }
+
+    /// Interrupt an `epoll_wait` so the waiting thread notices new completions.
+    fn wake(&self) {
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.event_fd,
+                &one as *const _ as *const _,
+                size_of::<u64>(),
+            )
+        };
+    }
+
+    fn work(&self) {
+        loop {
+            let pending = {
+                let mut jobs = self.jobs.lock().unwrap();
+                loop {
+                    if jobs.shutdown {
+                        return;
+                    }
+                    match jobs.queue.pop_front() {
+                        Some(job) => break job,
+                        None => jobs = self.job_ready.wait(jobs).unwrap(),
+                    }
+                }
+            };
+
+            let ret = pending.perform().unwrap_or(-libc::EAGAIN);
+            self.complete(pending.result(ret));
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.event_fd) };
+    }
+}
+
+/// Completion semantics emulated over epoll readiness.
+/// Socket operations are parked until their file descriptor is ready and then performed
+/// without blocking, file operations are performed by a pool of helper threads.
+pub struct EpollDriver {
+    shared: Arc<Shared>,
+    epoll_fd: RawFd,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl EpollDriver {
+    /// Create an epoll instance with `num_threads` helper threads for file operations.
+    pub fn new(num_threads: u32) -> Result<Self> {
+        let epoll_fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let event_fd =
+            match cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) }) {
+                Ok(event_fd) => event_fd,
+                Err(e) => {
+                    unsafe { libc::close(epoll_fd) };
+                    return Err(e);
+                }
+            };
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            jobs: Mutex::new(Jobs::default()),
+            job_ready: Condvar::new(),
+            event_fd,
+        });
+        let mut epoll = Self {
+            shared,
+            epoll_fd,
+            workers: Vec::new(),
+        };
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: WAKE_DATA,
+        };
+        cvt(unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, event_fd, &mut event) })?;
+
+        for _ in 0..num_threads.max(1) {
+            let shared = epoll.shared.clone();
+            let worker = Builder::new()
+                .name("iocp-rs-file".into())
+                .spawn(move || shared.work())?;
+            epoll.workers.push(worker);
+        }
+
+        Ok(epoll)
+    }
+
+    /// Bring the epoll registration of `fd` in line with its parked operations.
+    fn update_interest(&self, state: &mut State, fd: RawFd, rearm: bool) -> Result<()> {
+        let parked = match state.parked.get_mut(&fd) {
+            Some(parked) => parked,
+            None => return Ok(()),
+        };
+
+        let mut events = 0;
+        if !parked.reads.is_empty() {
+            events |= libc::EPOLLIN as u32;
+        }
+        if !parked.writes.is_empty() {
+            events |= libc::EPOLLOUT as u32;
+        }
+
+        if events == 0 {
+            if parked.interest != 0 {
+                // The file descriptor may have been closed already, which removed it from the set.
+                unsafe {
+                    libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut())
+                };
+            }
+            state.parked.remove(&fd);
+            return Ok(());
+        }
+
+        if events == parked.interest && !rearm {
+            return Ok(());
+        }
+
+        let ctl = |op| {
+            let mut event = libc::epoll_event {
+                events,
+                u64: fd as u64,
+            };
+            cvt(unsafe { libc::epoll_ctl(self.epoll_fd, op, fd, &mut event) })
+        };
+
+        let ret = if parked.interest == 0 {
+            ctl(libc::EPOLL_CTL_ADD).or_else(|e| match e.raw_os_error() {
+                Some(libc::EEXIST) => ctl(libc::EPOLL_CTL_MOD),
+                _ => Err(e),
+            })
+        } else {
+            ctl(libc::EPOLL_CTL_MOD).or_else(|e| match e.raw_os_error() {
+                Some(libc::ENOENT) => ctl(libc::EPOLL_CTL_ADD),
+                _ => Err(e),
+            })
+        };
+
+        ret.map(|_| parked.interest = events)
+    }
+
+    /// Take a parked operation out of the queues of `fd`.
+    fn unpark(&self, state: &mut State, fd: RawFd, id: OperationId) -> Option<Pending> {
+        let pending = state.pending.remove(&id)?;
+
+        if let Some(parked) = state.parked.get_mut(&fd) {
+            parked.reads.retain(|parked_id| *parked_id != id);
+            parked.writes.retain(|parked_id| *parked_id != id);
+        }
+        let _ = self.update_interest(state, fd, false);
+
+        Some(pending)
+    }
+
+    /// Run the parked operations of a file descriptor epoll reported as ready.
+    fn drive(&self, fd: RawFd, events: u32) {
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        let failed = events & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0;
+
+        if let Some(parked) = state.parked.get_mut(&fd) {
+            let queues = [
+                (libc::EPOLLIN as u32, &mut parked.reads),
+                (libc::EPOLLOUT as u32, &mut parked.writes),
+            ];
+
+            for (readiness, queue) in queues {
+                if events & readiness == 0 && !failed {
+                    continue;
+                }
+
+                while let Some(&id) = queue.front() {
+                    let pending = &state.pending[&id];
+                    match pending.perform() {
+                        Some(ret) => {
+                            state.ready.push_back(pending.result(ret));
+                            state.pending.remove(&id);
+                            queue.pop_front();
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        let _ = self.update_interest(state, fd, false);
+    }
+}
+
+impl Driver for EpollDriver {
+    /// Sockets are made nonblocking, operations on them are only ever performed without
+    /// blocking.
+    fn register(&self, handle: RawHandle, _token: usize) -> Result<()> {
+        // File descriptors join the epoll set once an operation waits on them,
+        // regular files never do.
+        set_socket_nonblocking(handle)
+    }
+
+    fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()> {
+        let id = context.id();
+        let handle = context.handle();
+        let (iov_ptr, iov_len) = match &mut context.vectored {
+            Some(vectored) => (vectored.as_mut_ptr(), vectored.len()),
+            None => (null_mut(), 0),
+        };
+        let (name, name_len) = match &mut context.state {
+            OpState::Accept(accept) => accept.name(),
+            OpState::RecvFrom(from) => from.name(),
+            _ if matches!(op, Operation::Accept | Operation::RecvFrom) => {
+                return Err(Error::from(ErrorKind::InvalidInput))
+            }
+            _ => (null_mut(), null_mut()),
+        };
+        let transmit = match &mut context.state {
+            OpState::Transmit(transmit) => &mut **transmit as *mut Transmit,
+            _ if matches!(op, Operation::TransmitFile { .. }) => {
+                return Err(Error::from(ErrorKind::InvalidInput))
+            }
+            _ => null_mut(),
+        };
+        let buff_ptr = context.buff.as_mut_ptr();
+        let buff_len = context.buff.len();
+        let message = match &mut context.state {
+            OpState::RecvMsg(msg) | OpState::SendMsg(msg) => msg.msg(buff_ptr, buff_len),
+            _ if matches!(op, Operation::RecvMsg | Operation::SendMsg) => {
+                return Err(Error::from(ErrorKind::InvalidInput))
+            }
+            _ => null_mut(),
+        };
+        let pending = Pending {
+            id,
+            fd: handle,
+            token,
+            buff_ptr,
+            buff_len,
+            iov_ptr,
+            iov_len,
+            name,
+            name_len,
+            connecting: Cell::new(false),
+            transmit,
+            message,
+            op,
+        };
+
+        let readiness = match pending.readiness() {
+            Some(readiness) => readiness,
+            None => {
+                self.shared.jobs.lock().unwrap().queue.push_back(pending);
+                self.shared.job_ready.notify_one();
+                return Ok(());
+            }
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        let parked = state.parked.entry(handle).or_default();
+        let queue = if readiness == libc::EPOLLIN as u32 {
+            &mut parked.reads
+        } else {
+            &mut parked.writes
+        };
+
+        // Only the oldest operation of a direction may run, later ones keep their order.
+        if queue.is_empty() {
+            if let Some(ret) = pending.perform() {
+                state.ready.push_back(pending.result(ret));
+                let _ = self.update_interest(&mut state, handle, false);
+                drop(state);
+                self.shared.wake();
+                return Ok(());
+            }
+        }
+
+        queue.push_back(id);
+        state.pending.insert(id, pending);
+
+        if let Err(e) = self.update_interest(&mut state, handle, true) {
+            self.unpark(&mut state, handle, id);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn wait_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<OperationalResult>> {
+        if size == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; EVENTS_CAPACITY];
+        let mut timed_out = false;
+
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.ready.is_empty() {
+                    let removed = size.min(state.ready.len());
+                    let results = state.ready.drain(..removed).collect();
+
+                    // Leave the rest to the other waiting threads.
+                    if !state.ready.is_empty() {
+                        self.shared.wake();
+                    }
+                    return Ok(results);
+                }
+            }
+
+            if timed_out {
+                return Err(Error::from_raw_os_error(libc::ETIME));
+            }
+
+            let timeout_ms = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    // Round up so the wait does not end just before the deadline.
+                    remaining
+                        .as_nanos()
+                        .div_ceil(1_000_000)
+                        .min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+
+            let ret = unsafe {
+                libc::epoll_wait(
+                    self.epoll_fd,
+                    events.as_mut_ptr(),
+                    EVENTS_CAPACITY as i32,
+                    timeout_ms,
+                )
+            };
+            let count = match cvt(ret) {
+                Ok(count) => count as usize,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in &events[..count] {
+                let (data, readiness) = (event.u64, event.events);
+
+                if data == WAKE_DATA {
+                    let mut counter = 0u64;
+                    unsafe {
+                        libc::read(
+                            self.shared.event_fd,
+                            &mut counter as *mut _ as *mut _,
+                            size_of::<u64>(),
+                        )
+                    };
+                } else {
+                    self.drive(data as RawFd, readiness);
+                }
+            }
+
+            timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        }
+    }
+
+    fn post(&self, result: OperationalResult) -> Result<()> {
+        self.shared.complete(result);
+        Ok(())
+    }
+
+    /// Cancel an operation that has not been performed yet, it completes with `ECANCELED`.
+    fn cancel(&self, context: &Context) -> Result<()> {
+        let id = context.id();
+        let mut state = self.shared.state.lock().unwrap();
+        let fd = state.pending.get(&id).map(|pending| pending.fd);
+        if let Some(pending) = fd.and_then(|fd| self.unpark(&mut state, fd, id)) {
+            drop(state);
+            self.shared.complete(pending.result(-libc::ECANCELED));
+            return Ok(());
+        }
+        drop(state);
+
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        if let Some(index) = jobs.queue.iter().position(|pending| pending.id == id) {
+            let pending = jobs.queue.remove(index).unwrap();
+            drop(jobs);
+            self.shared.complete(pending.result(-libc::ECANCELED));
+        }
+
+        Ok(())
+    }
+
+    /// Complete the operations still waiting on `handle` as cancelled and take it out of
+    /// the epoll set, they would run against the next file descriptor of that number.
+    fn deregister(&self, handle: RawHandle) -> Result<()> {
+        let mut cancelled = Vec::new();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let ids = match state.parked.get(&handle) {
+                Some(parked) => parked.reads.iter().chain(&parked.writes).copied().collect(),
+                None => Vec::new(),
+            };
+            for id in ids {
+                cancelled.extend(self.unpark(&mut state, handle, id));
+            }
+        }
+        {
+            let mut jobs = self.shared.jobs.lock().unwrap();
+            while let Some(index) = jobs.queue.iter().position(|pending| pending.fd == handle) {
+                cancelled.extend(jobs.queue.remove(index));
+            }
+        }
+
+        for pending in cancelled {
+            self.shared.complete(pending.result(-libc::ECANCELED));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EpollDriver {
+    fn drop(&mut self) {
+        self.shared.jobs.lock().unwrap().shutdown = true;
+        self.shared.job_ready.notify_all();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        unsafe { libc::close(self.epoll_fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write,
+        net::{TcpListener, TcpStream},
+        thread::spawn,
+    };
+
+    use crate::{fs::FileExt, net::TcpStreamExt, CompletionPort, OperationalResult};
+
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (stream, peer)
+    }
+
+    #[test]
+    fn parked_read() {
+        let cmp = CompletionPort::with_epoll(1).unwrap();
+        let (mut stream, mut peer) = connected();
+        cmp.add(1, &stream).unwrap();
+
+        TcpStreamExt::read(&mut stream, vec![0; 10]).unwrap();
+        let join = spawn(move || {
+            peer.write_all(b"hello").unwrap();
+            peer
+        });
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 1);
+        assert_eq!(completion.data(), b"hello");
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn cancel_parked() {
+        let cmp = CompletionPort::with_epoll(1).unwrap();
+        let (mut stream, _peer) = connected();
+        cmp.add(1, &stream).unwrap();
+
+        let id = TcpStreamExt::read(&mut stream, vec![0; 10]).unwrap();
+        cmp.cancel(id).unwrap();
+
+        let completion = cmp.get_many(1, None).unwrap().remove(0);
+        assert_eq!(completion.token(), 1);
+        assert_eq!(completion.id(), Some(id));
+        assert!(completion.result().is_cancelled());
+        assert_eq!(
+            completion.status().unwrap_err().raw_os_error(),
+            Some(libc::ECANCELED)
+        );
+    }
+
+    #[test]
+    fn remove_cancels_parked() {
+        let cmp = CompletionPort::with_epoll(1).unwrap();
+        let (mut stream, _peer) = connected();
+        cmp.add(1, &stream).unwrap();
+
+        let id = TcpStreamExt::read(&mut stream, vec![0; 10]).unwrap();
+        cmp.remove(&stream).unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert!(completion.result().is_cancelled());
+        let error = TcpStreamExt::read(&mut stream, vec![0; 10]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn file_through_helper_threads() {
+        let path = std::env::temp_dir().join(format!("iocp-rs-{}-epoll.txt", std::process::id()));
+        let cmp = CompletionPort::with_epoll(2).unwrap();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        cmp.add(3, &file).unwrap();
+
+        file.write_at(b"123".to_vec(), 0).unwrap();
+        assert_eq!(cmp.get(None).unwrap().bytes_used(), 3);
+
+        file.read_at(vec![0; 10], 1).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 3);
+        assert_eq!(completion.data(), b"23");
+
+        drop(file);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn post_wakes_waiter() {
+        let cmp = std::sync::Arc::new(CompletionPort::with_epoll(1).unwrap());
+        let waiter = cmp.clone();
+        let join = spawn(move || waiter.get(None).unwrap().token());
+
+        cmp.post(OperationalResult::new(9, 0, 0, 0)).unwrap();
+        assert_eq!(join.join().unwrap(), 9);
+    }
+}
@@ -0,0 +1,2 @@
+pub(crate) mod epoll;
+pub(crate) mod ring;
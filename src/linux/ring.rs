@@ -0,0 +1,388 @@
+use io_uring::{
+    opcode,
+    squeue::Entry,
+    types::{Fd, SubmitArgs, Timespec},
+    IoUring,
+};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    mem::zeroed,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    context::{OpState, OperationId},
+    driver::{Driver, Operation},
+    net::{transmit::Transmit, SocketAddrCRepr},
+    Context, OperationalResult, RawHandle,
+};
+
+/// `user_data` of the cancellation requests, their own completions are never reported.
+const CANCEL_DATA: u64 = u64::MAX;
+
+/// Set in the `user_data` of posted results, operations use their raw `OperationId`.
+const POSTED_BIT: u64 = 1 << 63;
+
+/// Bookkeeping of an operation the kernel has not completed yet.
+struct InFlight {
+    /// The operation, `None` for a posted result.
+    id: Option<OperationId>,
+    token: usize,
+    offset: u64,
+    /// The byte count and raw OS error given to `post`, which a `Nop` cannot carry itself.
+    posted: Option<(u32, i32)>,
+    /// The destination address of a `SendTo` or `Connect`, the kernel reads it after
+    /// submission.
+    _addr: Option<Box<SocketAddrCRepr>>,
+    /// The `msghdr` of a `SendToVectored`, which points at `_addr`.
+    _msg: Option<Box<Msg>>,
+    /// A transmit waiting for its socket to be writable.
+    transmit: Option<Transmitting>,
+}
+
+impl InFlight {
+    /// The completion of the operation, from a byte count or a negated errno.
+    fn result(&self, ret: i32) -> OperationalResult {
+        let (bytes_used, status) = match self.posted {
+            Some(posted) => posted,
+            None if ret < 0 => (0, -ret),
+            None => (ret as u32, 0),
+        };
+
+        let result = OperationalResult::new(self.token, self.offset, bytes_used, status);
+        match self.id {
+            Some(id) => result.with_id(id),
+            None => result,
+        }
+    }
+}
+
+struct Msg(libc::msghdr);
+
+// Points into the Context of the operation and `InFlight::_addr`, both outlive it.
+unsafe impl Send for Msg {}
+
+/// The socket of a transmit and its state in the Context. The ring only polls the socket,
+/// the transmit sends once it is writable.
+struct Transmitting {
+    fd: RawHandle,
+    transmit: *mut Transmit,
+}
+
+// Points into the Context of the operation, which outlives it.
+unsafe impl Send for Transmitting {}
+
+/// A driver that issues every operation as an SQE of an io_uring instance.
+pub struct UringDriver {
+    uring: IoUring,
+    sq_lock: Mutex<()>,
+    cq_lock: Mutex<()>,
+    next_posted: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+}
+
+impl UringDriver {
+    /// The submission queue size `CompletionPort::new` creates the ring with.
+    pub const DEFAULT_ENTRIES: u32 = 1024;
+
+    /// Create an io_uring instance with `entries` submission queue entries.
+    pub fn new(entries: u32) -> Result<Self> {
+        Ok(Self {
+            uring: IoUring::new(entries)?,
+            sq_lock: Mutex::new(()),
+            cq_lock: Mutex::new(()),
+            next_posted: AtomicU64::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Queue an SQE and hand it to the kernel. An error means the SQE was never queued.
+    fn push(&self, entry: &Entry) -> Result<()> {
+        loop {
+            {
+                let _guard = self.sq_lock.lock().unwrap();
+                let mut sq = unsafe { self.uring.submission_shared() };
+
+                if unsafe { sq.push(entry) }.is_ok() {
+                    break;
+                }
+            }
+
+            // The submission queue is full, flush it to make room.
+            self.enter()?;
+        }
+
+        // Once queued the SQE goes out with a later `io_uring_enter` if this one fails, so
+        // the operation is in flight and its CQE reports how it went.
+        let _ = self.enter();
+        Ok(())
+    }
+
+    fn enter(&self) -> Result<()> {
+        match self.uring.submit() {
+            Ok(_) => Ok(()),
+            // The entries stay queued and go out with the next `io_uring_enter`.
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EBUSY | libc::EAGAIN | libc::EINTR)
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn push_in_flight(&self, entry: Entry, in_flight: InFlight) -> Result<()> {
+        let data = match in_flight.id {
+            Some(id) => id.as_raw(),
+            None => POSTED_BIT | self.next_posted.fetch_add(1, Ordering::Relaxed),
+        };
+        self.in_flight.lock().unwrap().insert(data, in_flight);
+
+        match self.push(&entry.user_data(data)) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.in_flight.lock().unwrap().remove(&data);
+                Err(e)
+            }
+        }
+    }
+
+    /// Take at most `size` completions off the completion queue without waiting.
+    fn reap(&self, size: usize) -> Vec<OperationalResult> {
+        let completed = {
+            let _guard = self.cq_lock.lock().unwrap();
+            let cq = unsafe { self.uring.completion_shared() };
+
+            cq.filter(|cqe| cqe.user_data() != CANCEL_DATA)
+                .take(size)
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut results = Vec::with_capacity(completed.len());
+        let mut unfinished = Vec::new();
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            for (id, ret) in completed {
+                let entry = match in_flight.remove(&id) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let ret = match &entry.transmit {
+                    // The socket is writable, send what it takes.
+                    Some(transmitting) if ret >= 0 => {
+                        match unsafe { (*transmitting.transmit).perform(transmitting.fd) } {
+                            Some(ret) => ret,
+                            None => {
+                                unfinished.push(entry);
+                                continue;
+                            }
+                        }
+                    }
+                    // Cancelled, or the poll failed.
+                    Some(transmitting) => {
+                        unsafe { (*transmitting.transmit).finish() };
+                        ret
+                    }
+                    None => ret,
+                };
+                results.push(entry.result(ret));
+            }
+        }
+
+        // Poll again for the transmits the socket did not take in full.
+        for entry in unfinished {
+            let mut failed = entry.result(0);
+            let fd = entry
+                .transmit
+                .as_ref()
+                .map_or(-1, |transmitting| transmitting.fd);
+            let poll = opcode::PollAdd::new(Fd(fd), libc::POLLOUT as u32).build();
+            if let Err(e) = self.push_in_flight(poll, entry) {
+                failed.set_result(Err(e));
+                results.push(failed);
+            }
+        }
+
+        results
+    }
+}
+
+impl Driver for UringDriver {
+    fn register(&self, _handle: RawHandle, _token: usize) -> Result<()> {
+        // Every SQE carries its own file descriptor, there is nothing to associate up front.
+        Ok(())
+    }
+
+    fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()> {
+        let fd = Fd(context.handle());
+        let buff_len = context.buff.len();
+        let buff_ptr = context.buff.as_mut_ptr();
+
+        let mut in_flight = InFlight {
+            id: Some(context.id()),
+            token,
+            offset: op.offset(),
+            posted: None,
+            _addr: None,
+            _msg: None,
+            transmit: None,
+        };
+        let (iov_ptr, iov_len) = match &mut context.vectored {
+            Some(vectored) => (vectored.as_mut_ptr(), vectored.len()),
+            None => (null_mut(), 0),
+        };
+
+        let entry = match op {
+            Operation::Read { offset } => opcode::Read::new(fd, buff_ptr, buff_len)
+                .offset(offset)
+                .build(),
+            Operation::Write { offset } => opcode::Write::new(fd, buff_ptr, buff_len)
+                .offset(offset)
+                .build(),
+            Operation::Recv => opcode::Recv::new(fd, buff_ptr, buff_len).build(),
+            Operation::Send => opcode::Send::new(fd, buff_ptr, buff_len).build(),
+            Operation::SendTo(addr) => {
+                let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+                let addr = Box::new(addr);
+                let entry = opcode::Send::new(fd, buff_ptr, buff_len)
+                    .dest_addr(addr.as_ptr())
+                    .dest_addr_len(addr_len)
+                    .build();
+                in_flight._addr = Some(addr);
+                entry
+            }
+            Operation::RecvFrom => match &mut context.state {
+                OpState::RecvFrom(from) => {
+                    opcode::RecvMsg::new(fd, from.msg(buff_ptr, buff_len)).build()
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::RecvVectored => opcode::Readv::new(fd, iov_ptr, iov_len).build(),
+            Operation::SendVectored => opcode::Writev::new(fd, iov_ptr, iov_len).build(),
+            Operation::SendToVectored(addr) => {
+                let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+                let addr = Box::new(addr);
+                let mut msg = Box::new(Msg(unsafe { zeroed() }));
+                msg.0.msg_name = addr.as_ptr() as *mut libc::c_void;
+                msg.0.msg_namelen = addr_len;
+                msg.0.msg_iov = iov_ptr;
+                msg.0.msg_iovlen = iov_len as _;
+                let entry = opcode::SendMsg::new(fd, &msg.0).build();
+                in_flight._addr = Some(addr);
+                in_flight._msg = Some(msg);
+                entry
+            }
+            Operation::Accept => match &mut context.state {
+                OpState::Accept(accept) => {
+                    let (addr, addr_len) = accept.name();
+                    opcode::Accept::new(fd, addr, addr_len)
+                        .flags(libc::SOCK_CLOEXEC)
+                        .build()
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::Connect(addr) => {
+                let (addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+                let addr = Box::new(addr);
+                let entry = opcode::Connect::new(fd, addr.as_ptr(), addr_len).build();
+                in_flight._addr = Some(addr);
+                entry
+            }
+            Operation::Disconnect { .. } => opcode::Shutdown::new(fd, libc::SHUT_RDWR).build(),
+            Operation::TransmitFile { .. } => match &mut context.state {
+                OpState::Transmit(transmit) => {
+                    in_flight.transmit = Some(Transmitting {
+                        fd: fd.0,
+                        transmit: &mut **transmit,
+                    });
+                    opcode::PollAdd::new(fd, libc::POLLOUT as u32).build()
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::RecvMsg => match &mut context.state {
+                OpState::RecvMsg(msg) => {
+                    opcode::RecvMsg::new(fd, msg.msg(buff_ptr, buff_len)).build()
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::SendMsg => match &mut context.state {
+                OpState::SendMsg(msg) => {
+                    opcode::SendMsg::new(fd, msg.msg(buff_ptr, buff_len)).build()
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+        };
+
+        self.push_in_flight(entry, in_flight)
+    }
+
+    fn wait_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<OperationalResult>> {
+        if size == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+        loop {
+            let results = self.reap(size);
+            if !results.is_empty() {
+                return Ok(results);
+            }
+
+            let ret = match deadline {
+                Some(deadline) => {
+                    let timespec =
+                        Timespec::from(deadline.saturating_duration_since(Instant::now()));
+                    let args = SubmitArgs::new().timespec(&timespec);
+                    self.uring.submitter().submit_with_args(1, &args)
+                }
+                None => self.uring.submitter().submit_and_wait(1),
+            };
+
+            match ret {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::ETIME) => {
+                    let results = self.reap(size);
+                    return if results.is_empty() {
+                        Err(e)
+                    } else {
+                        Ok(results)
+                    };
+                }
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn post(&self, result: OperationalResult) -> Result<()> {
+        let in_flight = InFlight {
+            id: result.id(),
+            token: result.token(),
+            offset: result.offset(),
+            posted: Some((result.bytes_used(), result.status())),
+            _addr: None,
+            _msg: None,
+            transmit: None,
+        };
+
+        self.push_in_flight(opcode::Nop::new().build(), in_flight)
+    }
+
+    fn cancel(&self, context: &Context) -> Result<()> {
+        let entry = opcode::AsyncCancel::new(context.id().as_raw())
+            .build()
+            .user_data(CANCEL_DATA);
+        self.push(&entry)
+    }
+}
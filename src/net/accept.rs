@@ -0,0 +1,230 @@
+use std::{
+    io::{Error, Result},
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+#[cfg(windows)]
+use std::{
+    os::windows::io::{AsRawSocket, FromRawSocket, RawSocket},
+    ptr::{null, null_mut},
+};
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{
+    getsockname, setsockopt, GetAcceptExSockaddrs, WSAGetLastError, WSASocketW, INVALID_SOCKET,
+    IPPROTO_TCP, SOCKADDR, SOCKADDR_STORAGE, SOCKET, SOCK_STREAM, SOL_SOCKET,
+    SO_UPDATE_ACCEPT_CONTEXT, WSAEAFNOSUPPORT, WSA_FLAG_OVERLAPPED,
+};
+
+#[cfg(target_os = "linux")]
+use crate::cvt;
+#[cfg(windows)]
+use crate::net::cvt_for_socket;
+use crate::{net::SocketAddrCRepr, RawHandle};
+
+use super::{OwnedSocket as Socket, SocketPool};
+
+/// A connection accepted by `TcpListenerExt::accept`, see `Completion::into_accepted`.
+#[derive(Debug)]
+pub struct Accepted {
+    socket: Socket,
+    token: Option<usize>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl Accepted {
+    /// The address the connection was accepted on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The token the socket is still registered under when it was taken from a
+    /// `SocketPool`, `None` for a new socket.
+    pub fn token(&self) -> Option<usize> {
+        self.token
+    }
+
+    /// The connected socket as a `T`, like `std::net::TcpStream`.
+    /// Unless it has a `token`, it has to be added to the CompletionPort before operations
+    /// are issued on it.
+    pub fn into_stream<T: From<Socket>>(self) -> T {
+        T::from(self.socket)
+    }
+}
+
+/// How many bytes `AcceptEx` writes for each address, 16 more than the largest one.
+#[cfg(windows)]
+const ADDR_LEN: u32 = size_of::<SOCKADDR_STORAGE>() as u32 + 16;
+
+/// The socket `AcceptEx` accepts into and the buffer it writes both addresses to.
+#[cfg(windows)]
+pub(crate) struct AcceptState {
+    socket: Socket,
+    token: Option<usize>,
+    addrs: [u8; 2 * ADDR_LEN as usize],
+}
+
+#[cfg(windows)]
+impl AcceptState {
+    /// Take the socket to accept into from `pool` or create it, of the address family
+    /// of `listener`.
+    pub(crate) fn new(listener: RawHandle, pool: Option<&SocketPool>) -> Result<Self> {
+        let mut addr = unsafe { zeroed::<SOCKADDR_STORAGE>() };
+        let mut addr_len = size_of::<SOCKADDR_STORAGE>() as i32;
+        cvt_for_socket(unsafe {
+            getsockname(
+                listener as SOCKET,
+                &mut addr as *mut _ as *mut SOCKADDR,
+                &mut addr_len,
+            )
+        })?;
+
+        let pooled = pool.and_then(|pool| pool.take_family(addr.ss_family as i32));
+        if let Some((token, socket)) = pooled {
+            return Ok(Self {
+                socket,
+                token: Some(token),
+                addrs: [0; 2 * ADDR_LEN as usize],
+            });
+        }
+
+        let socket = unsafe {
+            WSASocketW(
+                addr.ss_family as i32,
+                SOCK_STREAM,
+                IPPROTO_TCP,
+                null(),
+                0,
+                WSA_FLAG_OVERLAPPED,
+            )
+        };
+        if socket == INVALID_SOCKET {
+            return Err(Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+        }
+
+        Ok(Self {
+            socket: unsafe { Socket::from_raw_socket(socket as RawSocket) },
+            token: None,
+            addrs: [0; 2 * ADDR_LEN as usize],
+        })
+    }
+
+    /// The accept socket and output buffer to hand to `AcceptEx`.
+    pub(crate) fn accept_ex_args(&mut self) -> (SOCKET, *mut u8, u32) {
+        let socket = self.socket.as_raw_socket() as SOCKET;
+        (socket, self.addrs.as_mut_ptr(), ADDR_LEN)
+    }
+
+    /// Make the socket a full connection once `AcceptEx` completed and read its addresses.
+    pub(crate) fn finish(self, listener: RawHandle, _ret: u32) -> Result<Accepted> {
+        let listener = listener as SOCKET;
+        cvt_for_socket(unsafe {
+            setsockopt(
+                self.socket.as_raw_socket() as SOCKET,
+                SOL_SOCKET,
+                SO_UPDATE_ACCEPT_CONTEXT,
+                &listener as *const SOCKET as *const u8,
+                size_of::<SOCKET>() as i32,
+            )
+        })?;
+
+        let (mut local, mut local_len) = (null_mut::<SOCKADDR>(), 0);
+        let (mut peer, mut peer_len) = (null_mut::<SOCKADDR>(), 0);
+        unsafe {
+            GetAcceptExSockaddrs(
+                self.addrs.as_ptr() as *const _,
+                0,
+                ADDR_LEN,
+                ADDR_LEN,
+                &mut local,
+                &mut local_len,
+                &mut peer,
+                &mut peer_len,
+            )
+        };
+
+        let addrs = unsafe {
+            (
+                SocketAddrCRepr::ptrs_to_socket_addr(local, local_len),
+                SocketAddrCRepr::ptrs_to_socket_addr(peer, peer_len),
+            )
+        };
+        match addrs {
+            (Some(local_addr), Some(peer_addr)) => Ok(Accepted {
+                socket: self.socket,
+                token: self.token,
+                local_addr,
+                peer_addr,
+            }),
+            _ => Err(Error::from_raw_os_error(WSAEAFNOSUPPORT)),
+        }
+    }
+}
+
+/// Where the kernel writes the peer address of the connection it accepts.
+#[cfg(target_os = "linux")]
+pub(crate) struct AcceptState {
+    addr: libc::sockaddr_storage,
+    addr_len: libc::socklen_t,
+}
+
+#[cfg(target_os = "linux")]
+impl AcceptState {
+    /// The connection socket is created by the kernel, there is nothing to prepare and
+    /// no socket to take from a pool.
+    pub(crate) fn new(_listener: RawHandle, _pool: Option<&SocketPool>) -> Result<Self> {
+        Ok(Self {
+            addr: unsafe { zeroed() },
+            addr_len: size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+        })
+    }
+
+    /// The address arguments of `accept4`.
+    pub(crate) fn name(&mut self) -> (*mut libc::sockaddr, *mut libc::socklen_t) {
+        (
+            &mut self.addr as *mut _ as *mut libc::sockaddr,
+            &mut self.addr_len,
+        )
+    }
+
+    /// Take ownership of the file descriptor `ret` the accept completed with.
+    pub(crate) fn finish(self, _listener: RawHandle, ret: u32) -> Result<Accepted> {
+        let socket = unsafe { Socket::from_raw_fd(ret as RawFd) };
+
+        let mut local = unsafe { zeroed::<libc::sockaddr_storage>() };
+        let mut local_len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        cvt(unsafe {
+            libc::getsockname(
+                socket.as_raw_fd(),
+                &mut local as *mut _ as *mut libc::sockaddr,
+                &mut local_len,
+            )
+        })?;
+
+        let addrs = unsafe {
+            (
+                SocketAddrCRepr::ptrs_to_socket_addr(&local as *const _ as *const _, local_len),
+                SocketAddrCRepr::ptrs_to_socket_addr(
+                    &self.addr as *const _ as *const _,
+                    self.addr_len,
+                ),
+            )
+        };
+        match addrs {
+            (Some(local_addr), Some(peer_addr)) => Ok(Accepted {
+                socket,
+                token: None,
+                local_addr,
+                peer_addr,
+            }),
+            _ => Err(Error::from_raw_os_error(libc::EAFNOSUPPORT)),
+        }
+    }
+}
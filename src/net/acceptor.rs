@@ -0,0 +1,306 @@
+use std::{
+    collections::HashSet,
+    io::{ErrorKind, Result},
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{AsHandle, Completion, CompletionPort, OperationId};
+
+use super::{OwnedSocket, SocketPool, TcpListenerExt};
+
+/// What a `ListenerAcceptor` has accepted so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AcceptorStats {
+    /// Connections accepted and registered with the port.
+    pub accepted: u64,
+    /// Accepts that completed with an error, or whose connection could not be registered.
+    pub failed: u64,
+    /// Accepts that completed while no other accept was pending on the listener, so
+    /// connections arriving until it was re-armed waited in the kernel backlog.
+    /// Raise the number of outstanding accepts if this keeps growing.
+    pub starved: u64,
+    /// Accepts that could not be issued again after one completed.
+    pub rearm_failed: u64,
+    /// Accepts pending right now.
+    pub pending: usize,
+    /// How long the acceptor has been running.
+    pub uptime: Duration,
+}
+
+impl AcceptorStats {
+    /// Connections accepted per second over the lifetime of the acceptor.
+    pub fn accept_rate(&self) -> f64 {
+        match self.uptime.as_secs_f64() {
+            secs if secs > 0.0 => self.accepted as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Keeps a number of accepts pending on a listener, so connections do not wait in the
+/// kernel backlog for the next accept to be issued.
+///
+/// The completions of the accepts come out of the CompletionPort like any other and go
+/// to `on_completion`, which issues the next accept right away and registers the
+/// connection with the port under a new token, counting up from the listener's and
+/// skipping the tokens other handles are added to the port under.
+/// With a `SocketPool` the accepts take their sockets from it, such a connection keeps
+/// the token its socket is registered under.
+/// Accepts still pending when the acceptor is dropped are abandoned, the connections
+/// they may have accepted are closed.
+pub struct ListenerAcceptor<L: TcpListenerExt, S> {
+    port: Arc<CompletionPort>,
+    listener: L,
+    token: usize,
+    next_token: usize,
+    outstanding: usize,
+    pending: HashSet<OperationId>,
+    pool: Option<SocketPool>,
+    stats: AcceptorStats,
+    started: Instant,
+    _stream: PhantomData<fn() -> S>,
+}
+
+impl<L, S> ListenerAcceptor<L, S>
+where
+    L: TcpListenerExt,
+    S: From<OwnedSocket> + AsHandle,
+{
+    /// Add `listener` to `port` under `token` and issue `outstanding` accepts on it.
+    pub fn new(
+        port: Arc<CompletionPort>,
+        listener: L,
+        token: usize,
+        outstanding: usize,
+    ) -> Result<Self> {
+        port.add(token, &listener)?;
+
+        let mut acceptor = Self {
+            port,
+            listener,
+            token,
+            next_token: token.wrapping_add(1),
+            outstanding,
+            pending: HashSet::new(),
+            pool: None,
+            stats: AcceptorStats::default(),
+            started: Instant::now(),
+            _stream: PhantomData,
+        };
+        acceptor.top_up()?;
+
+        Ok(acceptor)
+    }
+
+    /// The token the listener was added under.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    pub fn listener(&self) -> &L {
+        &self.listener
+    }
+
+    /// Keep `outstanding` accepts pending from now on. More are issued right away, fewer
+    /// take effect as the pending ones complete.
+    pub fn set_outstanding(&mut self, outstanding: usize) -> Result<usize> {
+        self.outstanding = outstanding;
+        self.top_up()
+    }
+
+    /// Accept into sockets kept by `pool` from now on, see `TcpListenerExt::accept_pooled`.
+    pub fn set_pool(&mut self, pool: SocketPool) {
+        self.pool = Some(pool);
+    }
+
+    /// Issue accepts until `outstanding` are pending, returns how many were issued.
+    /// `on_completion` does this itself, call it after an accept could not be re-armed.
+    pub fn top_up(&mut self) -> Result<usize> {
+        let mut issued = 0;
+        while self.pending.len() < self.outstanding {
+            let id = match &self.pool {
+                Some(pool) => self.listener.accept_pooled(pool)?,
+                None => TcpListenerExt::accept(&self.listener)?,
+            };
+            self.pending.insert(id);
+            issued += 1;
+        }
+
+        Ok(issued)
+    }
+
+    /// Take a completion from the port. An accept of this acceptor is re-armed and its
+    /// connection registered, which comes back as a stream with its token, or the error
+    /// the accept failed with. Any other completion is handed back.
+    pub fn on_completion(
+        &mut self,
+        completion: Completion,
+    ) -> std::result::Result<Result<(usize, S)>, Completion> {
+        match completion.id() {
+            Some(id) if self.pending.remove(&id) => {}
+            _ => return Err(completion),
+        }
+
+        if self.pending.is_empty() {
+            self.stats.starved += 1;
+        }
+        if self.top_up().is_err() {
+            self.stats.rearm_failed += 1;
+        }
+
+        Ok(self.register(completion))
+    }
+
+    pub fn stats(&self) -> AcceptorStats {
+        AcceptorStats {
+            pending: self.pending.len(),
+            uptime: self.started.elapsed(),
+            ..self.stats
+        }
+    }
+
+    fn register(&mut self, completion: Completion) -> Result<(usize, S)> {
+        let accepted = match completion.into_accepted() {
+            Ok(accepted) => accepted,
+            Err(completion) => {
+                self.stats.failed += 1;
+                // Only a failed accept completes without a connection.
+                let e = completion.status().err();
+                return Err(e.unwrap_or_else(|| ErrorKind::InvalidData.into()));
+            }
+        };
+        let recycled = accepted.token();
+        let stream = accepted.into_stream::<S>();
+
+        // A socket from the pool is registered with the port already.
+        let token = match recycled {
+            Some(token) => token,
+            None => {
+                while self.port.has_token(self.next_token) {
+                    self.next_token = self.next_token.wrapping_add(1);
+                }
+                let token = self.next_token;
+                if let Err(e) = self.port.add(token, &stream) {
+                    self.stats.failed += 1;
+                    return Err(e);
+                }
+                self.next_token = self.next_token.wrapping_add(1);
+                token
+            }
+        };
+        self.stats.accepted += 1;
+
+        Ok((token, stream))
+    }
+}
+
+impl<L: TcpListenerExt, S> Drop for ListenerAcceptor<L, S> {
+    fn drop(&mut self) {
+        for id in self.pending.drain() {
+            let _ = self.port.abandon(id);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+    };
+
+    use crate::{net::TcpStreamExt, CompletionPort};
+
+    use super::ListenerAcceptor;
+
+    /// Connect `clients` one after the other, each accepted before the next connects.
+    fn accept_in_turn(cmp: CompletionPort, outstanding: usize, clients: usize) {
+        let cmp = Arc::new(cmp);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut acceptor =
+            ListenerAcceptor::<_, TcpStream>::new(cmp.clone(), listener, 100, outstanding).unwrap();
+        assert_eq!(acceptor.stats().pending, outstanding);
+
+        for n in 0..clients {
+            let mut client = TcpStream::connect(addr).unwrap();
+            let completion = cmp.get(None).unwrap();
+            let (token, stream) = acceptor.on_completion(completion).ok().unwrap().unwrap();
+            assert_eq!(token, 101 + n);
+            assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+
+            // Registered under its token, ready for operations.
+            TcpStreamExt::write(&stream, b"hi".to_vec()).unwrap();
+            assert_eq!(cmp.get(None).unwrap().token(), token);
+            let mut buff = [0; 2];
+            client.read_exact(&mut buff).unwrap();
+            assert_eq!(&buff, b"hi");
+        }
+
+        let stats = acceptor.stats();
+        assert_eq!(stats.accepted, clients as u64);
+        assert_eq!(stats.pending, outstanding);
+        assert_eq!(stats.failed, 0);
+        // With one accept pending every connection found none left behind it.
+        let starved = if outstanding == 1 { clients as u64 } else { 0 };
+        assert_eq!(stats.starved, starved);
+        assert!(stats.accept_rate() > 0.0);
+
+        // Other completions are handed back untouched.
+        cmp.post_message(7, "other").unwrap();
+        let other = acceptor
+            .on_completion(cmp.get(None).unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(other.into_message::<&str>().ok().unwrap(), "other");
+    }
+
+    #[test]
+    fn keeps_accepts_pending_uring() {
+        accept_in_turn(CompletionPort::new(1).unwrap(), 4, 6);
+        accept_in_turn(CompletionPort::new(1).unwrap(), 1, 3);
+    }
+
+    #[test]
+    fn keeps_accepts_pending_epoll() {
+        accept_in_turn(CompletionPort::with_epoll(1).unwrap(), 4, 6);
+        accept_in_turn(CompletionPort::with_epoll(1).unwrap(), 1, 3);
+    }
+
+    #[test]
+    fn skips_tokens_in_use() {
+        let cmp = Arc::new(CompletionPort::new(1).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other = TcpStream::connect(addr).unwrap();
+        cmp.add(11, &other).unwrap();
+        let mut acceptor =
+            ListenerAcceptor::<_, TcpStream>::new(cmp.clone(), listener, 10, 1).unwrap();
+
+        // The connection of `other` and one more, 11 is taken by `other` itself.
+        let _client = TcpStream::connect(addr).unwrap();
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            let completion = cmp.get(None).unwrap();
+            let (token, _) = acceptor.on_completion(completion).ok().unwrap().unwrap();
+            tokens.push(token);
+        }
+        assert_eq!(tokens, [12, 13]);
+    }
+
+    #[test]
+    fn more_outstanding_later() {
+        let cmp = Arc::new(CompletionPort::new(1).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut acceptor =
+            ListenerAcceptor::<_, TcpStream>::new(cmp.clone(), listener, 1, 2).unwrap();
+
+        assert_eq!(acceptor.set_outstanding(8).unwrap(), 6);
+        assert_eq!(acceptor.stats().pending, 8);
+        assert_eq!(acceptor.set_outstanding(2).unwrap(), 0);
+        assert_eq!(acceptor.stats().pending, 8);
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ptr::{read_unaligned, write_unaligned},
+    time::SystemTime,
+};
+
+#[cfg(target_os = "linux")]
+use std::time::{Duration, UNIX_EPOCH};
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{
+    setsockopt, AF_INET6, CMSGHDR, IN6_ADDR, IN6_ADDR_0, IN6_PKTINFO, IN_ADDR, IN_ADDR_0,
+    IN_PKTINFO, IPPROTO_IP, IPPROTO_IPV6, IPV6_ECN, IPV6_HOPLIMIT, IPV6_PKTINFO, IPV6_RECVECN,
+    IPV6_RECVTCLASS, IPV6_TCLASS, IP_ECN, IP_HOPLIMIT, IP_PKTINFO, IP_RECVECN, IP_RECVTOS,
+    IP_RECVTTL, IP_TOS, IP_TTL, SOCKET,
+};
+
+#[cfg(target_os = "linux")]
+use crate::cvt;
+#[cfg(windows)]
+use crate::net::cvt_for_socket;
+use crate::RawHandle;
+
+use super::socket_pool::family;
+
+/// The ECN codepoint of a datagram, the low two bits of its traffic class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ecn {
+    /// Not ECN-capable transport.
+    NotEct,
+    /// ECN-capable transport, ECT(1).
+    Ect1,
+    /// ECN-capable transport, ECT(0).
+    Ect0,
+    /// Congestion experienced.
+    Ce,
+}
+
+impl Ecn {
+    /// The codepoint in the low two bits of `traffic_class`.
+    pub fn from_traffic_class(traffic_class: u8) -> Self {
+        match traffic_class & 0b11 {
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            0b11 => Ecn::Ce,
+            _ => Ecn::NotEct,
+        }
+    }
+
+    /// The two bits of the codepoint.
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+/// The address a datagram was sent to and the index of the interface it arrived on, or
+/// the source address and the interface to send one from. An interface of 0 leaves the
+/// choice to the routing table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub addr: IpAddr,
+    pub interface: u32,
+}
+
+/// The control messages of a datagram: what came along with one `recv_msg` received,
+/// or what `send_msg` sends one with, see `UdpSocketExt`.
+///
+/// A receive fills in what `UdpSocketExt::set_recv_ancillary` asked for and the system
+/// delivered. A send leaves out what is `None`; on Windows it carries the packet info and
+/// the ECN codepoint only and fails with `ErrorKind::Unsupported` for anything else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ancillary {
+    /// The destination of a received datagram, the source of a sent one. Sending a reply
+    /// with the packet info of the request sends it from the address the request
+    /// arrived on.
+    pub packet_info: Option<PacketInfo>,
+    /// The TOS byte of IPv4 or the traffic class of IPv6, ECN codepoint included.
+    pub traffic_class: Option<u8>,
+    /// The ECN codepoint, which takes the place of the one in `traffic_class` on a send.
+    pub ecn: Option<Ecn>,
+    /// The TTL of IPv4 or the hop limit of IPv6.
+    pub hop_limit: Option<u8>,
+    /// When the system received the datagram, Linux only. Sends ignore it.
+    pub timestamp: Option<SystemTime>,
+}
+
+impl Ancillary {
+    /// The traffic class a send goes out with, `ecn` in its low two bits.
+    fn send_traffic_class(&self) -> Option<u8> {
+        match (self.traffic_class, self.ecn) {
+            (None, None) => None,
+            (traffic_class, None) => traffic_class,
+            (traffic_class, Some(ecn)) => Some(traffic_class.unwrap_or(0) & !0b11 | ecn.bits()),
+        }
+    }
+}
+
+/// Which control messages `UdpSocketExt::set_recv_ancillary` has the system attach to
+/// received datagrams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AncillaryOptions {
+    pub packet_info: bool,
+    /// The traffic class along with the ECN codepoint.
+    pub traffic_class: bool,
+    pub hop_limit: bool,
+    /// Receive timestamps, `ErrorKind::Unsupported` on Windows.
+    pub timestamp: bool,
+}
+
+/// Room for all the control messages this module reads or writes, headers included.
+const CONTROL_LEN: usize = 256;
+
+#[cfg(windows)]
+type CmsgHdr = CMSGHDR;
+#[cfg(target_os = "linux")]
+type CmsgHdr = libc::cmsghdr;
+
+/// Control message headers and their data are aligned to a `usize`, like `CMSG_ALIGN`
+/// and `WSA_CMSGHDR_ALIGN` do.
+const fn align(len: usize) -> usize {
+    (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+}
+
+/// Where the data of a control message starts.
+const DATA_OFFSET: usize = align(size_of::<CmsgHdr>());
+
+/// The control buffer of a message, aligned for the headers in it.
+#[repr(C, align(8))]
+pub(crate) struct Control([u8; CONTROL_LEN]);
+
+impl Control {
+    pub(crate) fn new() -> Self {
+        Self([0; CONTROL_LEN])
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.0.as_mut_ptr()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        CONTROL_LEN
+    }
+
+    /// Write the control messages of a send of `ancillary`, to an IPv6 address if `v6`
+    /// is set. Returns how many bytes they take.
+    pub(crate) fn encode(&mut self, ancillary: &Ancillary, v6: bool) -> Result<usize> {
+        let mut writer = Writer {
+            buf: &mut self.0,
+            len: 0,
+        };
+        encode(&mut writer, ancillary, v6)?;
+        Ok(writer.len)
+    }
+
+    /// The control messages in the first `len` bytes, as a receive left them.
+    pub(crate) fn decode(&self, len: usize) -> Ancillary {
+        let mut ancillary = Ancillary::default();
+        for (level, kind, data) in messages(&self.0[..len.min(CONTROL_LEN)]) {
+            decode(&mut ancillary, level, kind, data);
+        }
+        ancillary
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push<T>(&mut self, level: i32, kind: i32, value: T) -> Result<()> {
+        let start = self.len;
+        let end = start + DATA_OFFSET + align(size_of::<T>());
+        if end > self.buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many control messages",
+            ));
+        }
+
+        let mut header = unsafe { zeroed::<CmsgHdr>() };
+        header.cmsg_len = (DATA_OFFSET + size_of::<T>()) as _;
+        header.cmsg_level = level;
+        header.cmsg_type = kind;
+        unsafe {
+            write_unaligned(self.buf[start..].as_mut_ptr() as *mut CmsgHdr, header);
+            write_unaligned(
+                self.buf[start + DATA_OFFSET..].as_mut_ptr() as *mut T,
+                value,
+            );
+        }
+
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// The level, type and data of each control message in `control`.
+fn messages(control: &[u8]) -> impl Iterator<Item = (i32, i32, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = control.get(offset..offset + size_of::<CmsgHdr>())?;
+        let header = unsafe { read_unaligned(header.as_ptr() as *const CmsgHdr) };
+        let len = header.cmsg_len as usize;
+        let data = control.get(offset + DATA_OFFSET..offset + len)?;
+        offset += align(len);
+        Some((header.cmsg_level, header.cmsg_type, data))
+    })
+}
+
+fn read<T>(data: &[u8]) -> Option<T> {
+    match data.len() >= size_of::<T>() {
+        true => Some(unsafe { read_unaligned(data.as_ptr() as *const T) }),
+        false => None,
+    }
+}
+
+/// An integer control message, an `int` or, like `IP_TOS` on Linux, a single byte.
+fn int(data: &[u8]) -> Option<u8> {
+    match data.len() {
+        1 => Some(data[0]),
+        _ => read::<i32>(data).map(|value| value as u8),
+    }
+}
+
+/// The packet info as the family of the destination wants it, a v4-mapped address for
+/// an IPv4 source on an IPv6 socket.
+fn packet_addr(info: &PacketInfo, v6: bool) -> Result<IpAddr> {
+    match (info.addr, v6) {
+        (IpAddr::V4(addr), true) => Ok(IpAddr::V6(addr.to_ipv6_mapped())),
+        (IpAddr::V6(_), false) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "an IPv6 source for an IPv4 destination",
+        )),
+        (addr, _) => Ok(addr),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn encode(writer: &mut Writer, ancillary: &Ancillary, v6: bool) -> Result<()> {
+    if let Some(info) = &ancillary.packet_info {
+        match packet_addr(info, v6)? {
+            IpAddr::V4(addr) => writer.push(
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                libc::in_pktinfo {
+                    ipi_ifindex: info.interface as i32,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from(addr).to_be(),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                },
+            )?,
+            IpAddr::V6(addr) => writer.push(
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: addr.octets(),
+                    },
+                    ipi6_ifindex: info.interface,
+                },
+            )?,
+        }
+    }
+
+    let (level, traffic_class, hop_limit) = match v6 {
+        true => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS, libc::IPV6_HOPLIMIT),
+        false => (libc::IPPROTO_IP, libc::IP_TOS, libc::IP_TTL),
+    };
+    if let Some(value) = ancillary.send_traffic_class() {
+        writer.push(level, traffic_class, value as i32)?;
+    }
+    if let Some(value) = ancillary.hop_limit {
+        writer.push(level, hop_limit, value as i32)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn encode(writer: &mut Writer, ancillary: &Ancillary, v6: bool) -> Result<()> {
+    let traffic_class = ancillary.send_traffic_class();
+    if ancillary.hop_limit.is_some() || traffic_class.is_some_and(|value| value & !0b11 != 0) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "only the packet info and the ECN codepoint can be sent on Windows",
+        ));
+    }
+
+    if let Some(info) = &ancillary.packet_info {
+        match packet_addr(info, v6)? {
+            IpAddr::V4(addr) => writer.push(
+                IPPROTO_IP,
+                IP_PKTINFO,
+                IN_PKTINFO {
+                    ipi_addr: IN_ADDR {
+                        S_un: IN_ADDR_0 {
+                            S_addr: u32::from(addr).to_be(),
+                        },
+                    },
+                    ipi_ifindex: info.interface,
+                },
+            )?,
+            IpAddr::V6(addr) => writer.push(
+                IPPROTO_IPV6,
+                IPV6_PKTINFO,
+                IN6_PKTINFO {
+                    ipi6_addr: IN6_ADDR {
+                        u: IN6_ADDR_0 {
+                            Byte: addr.octets(),
+                        },
+                    },
+                    ipi6_ifindex: info.interface,
+                },
+            )?,
+        }
+    }
+
+    if let Some(value) = traffic_class {
+        match v6 {
+            true => writer.push(IPPROTO_IPV6, IPV6_ECN, value as i32)?,
+            false => writer.push(IPPROTO_IP, IP_ECN, value as i32)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn decode(ancillary: &mut Ancillary, level: i32, kind: i32, data: &[u8]) {
+    match (level, kind) {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+            if let Some(info) = read::<libc::in_pktinfo>(data) {
+                ancillary.packet_info = Some(PacketInfo {
+                    addr: IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr))),
+                    interface: info.ipi_ifindex as u32,
+                });
+            }
+        }
+        (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+            if let Some(info) = read::<libc::in6_pktinfo>(data) {
+                ancillary.packet_info = Some(PacketInfo {
+                    addr: IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)),
+                    interface: info.ipi6_ifindex,
+                });
+            }
+        }
+        (libc::IPPROTO_IP, libc::IP_TOS) | (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+            ancillary.traffic_class = int(data);
+            ancillary.ecn = ancillary.traffic_class.map(Ecn::from_traffic_class);
+        }
+        (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+            ancillary.hop_limit = int(data);
+        }
+        (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+            ancillary.timestamp = read::<libc::timespec>(data)
+                .map(|time| UNIX_EPOCH + Duration::new(time.tv_sec as u64, time.tv_nsec as u32));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(windows)]
+fn decode(ancillary: &mut Ancillary, level: i32, kind: i32, data: &[u8]) {
+    match (level, kind) {
+        (IPPROTO_IP, IP_PKTINFO) => {
+            if let Some(info) = read::<IN_PKTINFO>(data) {
+                let addr = unsafe { info.ipi_addr.S_un.S_addr };
+                ancillary.packet_info = Some(PacketInfo {
+                    addr: IpAddr::V4(Ipv4Addr::from(u32::from_be(addr))),
+                    interface: info.ipi_ifindex,
+                });
+            }
+        }
+        (IPPROTO_IPV6, IPV6_PKTINFO) => {
+            if let Some(info) = read::<IN6_PKTINFO>(data) {
+                let addr = unsafe { info.ipi6_addr.u.Byte };
+                ancillary.packet_info = Some(PacketInfo {
+                    addr: IpAddr::V6(Ipv6Addr::from(addr)),
+                    interface: info.ipi6_ifindex,
+                });
+            }
+        }
+        (IPPROTO_IP, IP_TOS) | (IPPROTO_IPV6, IPV6_TCLASS) => {
+            ancillary.traffic_class = int(data);
+            ancillary.ecn = ancillary.traffic_class.map(Ecn::from_traffic_class);
+        }
+        (IPPROTO_IP, IP_ECN) | (IPPROTO_IPV6, IPV6_ECN) => {
+            ancillary.ecn = int(data).map(Ecn::from_traffic_class);
+        }
+        // `IP_RECVTTL` delivers the TTL as `IP_TTL` or as `IP_HOPLIMIT`, the same as
+        // `IPV6_HOPLIMIT`.
+        (IPPROTO_IP, IP_TTL) | (IPPROTO_IP, IP_HOPLIMIT) | (IPPROTO_IPV6, IPV6_HOPLIMIT) => {
+            ancillary.hop_limit = int(data);
+        }
+        _ => {}
+    }
+}
+
+/// Have the system attach the control messages of `options` to received datagrams, and
+/// stop attaching the others.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_recv(handle: RawHandle, options: &AncillaryOptions) -> Result<()> {
+    let (level, packet_info, traffic_class, hop_limit) = match family(handle)? {
+        libc::AF_INET6 => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVPKTINFO,
+            libc::IPV6_RECVTCLASS,
+            libc::IPV6_RECVHOPLIMIT,
+        ),
+        _ => (
+            libc::IPPROTO_IP,
+            libc::IP_PKTINFO,
+            libc::IP_RECVTOS,
+            libc::IP_RECVTTL,
+        ),
+    };
+    let settings = [
+        (level, packet_info, options.packet_info),
+        (level, traffic_class, options.traffic_class),
+        (level, hop_limit, options.hop_limit),
+        (libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, options.timestamp),
+    ];
+
+    for (level, name, on) in settings {
+        let value = on as i32;
+        cvt(unsafe {
+            libc::setsockopt(
+                handle,
+                level,
+                name,
+                &value as *const i32 as *const libc::c_void,
+                size_of::<i32>() as libc::socklen_t,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Have the system attach the control messages of `options` to received datagrams, and
+/// stop attaching the others.
+#[cfg(windows)]
+pub(crate) fn set_recv(handle: RawHandle, options: &AncillaryOptions) -> Result<()> {
+    if options.timestamp {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "no receive timestamps on Windows",
+        ));
+    }
+
+    let (level, packet_info, traffic_class, ecn, hop_limit) = match family(handle)? {
+        family if family == AF_INET6 as i32 => (
+            IPPROTO_IPV6,
+            IPV6_PKTINFO,
+            IPV6_RECVTCLASS,
+            IPV6_RECVECN,
+            IPV6_HOPLIMIT,
+        ),
+        _ => (IPPROTO_IP, IP_PKTINFO, IP_RECVTOS, IP_RECVECN, IP_RECVTTL),
+    };
+    let settings = [
+        (packet_info, options.packet_info),
+        (traffic_class, options.traffic_class),
+        (ecn, options.traffic_class),
+        (hop_limit, options.hop_limit),
+    ];
+
+    for (name, on) in settings {
+        let value = on as u32;
+        cvt_for_socket(unsafe {
+            setsockopt(
+                handle as SOCKET,
+                level,
+                name,
+                &value as *const u32 as *const u8,
+                size_of::<u32>() as i32,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{Ancillary, Control, Ecn, PacketInfo};
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        let sent = Ancillary {
+            packet_info: Some(PacketInfo {
+                addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                interface: 1,
+            }),
+            ecn: Some(Ecn::Ect0),
+            ..Ancillary::default()
+        };
+
+        let mut control = Control::new();
+        let len = control.encode(&sent, true).unwrap();
+        let decoded = control.decode(len);
+        assert_eq!(decoded.packet_info, sent.packet_info);
+        assert_eq!(decoded.ecn, Some(Ecn::Ect0));
+        assert_eq!(control.decode(0), Ancillary::default());
+
+        // An IPv4 source goes out v4-mapped on an IPv6 socket, not the other way around.
+        let v4 = Ancillary {
+            packet_info: Some(PacketInfo {
+                addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                interface: 0,
+            }),
+            ..Ancillary::default()
+        };
+        let len = control.encode(&v4, true).unwrap();
+        let mapped = control.decode(len).packet_info.unwrap();
+        assert_eq!(
+            mapped.addr,
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped())
+        );
+        assert!(control.encode(&sent, false).is_err());
+    }
+}
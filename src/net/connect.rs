@@ -0,0 +1,195 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::{FromRawFd, RawFd};
+#[cfg(windows)]
+use std::{
+    mem::{size_of, zeroed},
+    net::{Ipv4Addr, Ipv6Addr},
+    os::windows::io::{FromRawSocket, RawSocket},
+    ptr::{null, null_mut},
+    sync::OnceLock,
+};
+#[cfg(windows)]
+use windows_sys::{
+    core::GUID,
+    Win32::Networking::WinSock::{
+        bind, getsockname, setsockopt, WSAGetLastError, WSAIoctl, WSASocketW, AF_INET, AF_INET6,
+        INVALID_SOCKET, IPPROTO_TCP, LPFN_CONNECTEX, SIO_GET_EXTENSION_FUNCTION_POINTER, SOCKADDR,
+        SOCKADDR_STORAGE, SOCKET, SOCK_STREAM, SOL_SOCKET, SO_UPDATE_CONNECT_CONTEXT, WSAEINVAL,
+        WSAID_CONNECTEX, WSA_FLAG_OVERLAPPED,
+    },
+};
+
+#[cfg(target_os = "linux")]
+use crate::cvt;
+#[cfg(windows)]
+use crate::net::{cvt_for_socket, SocketAddrCRepr};
+use crate::{
+    buf::Buffer,
+    context::{IOType, OpState},
+    driver::Operation,
+    Context, IoBuf, RawHandle,
+};
+
+use super::OwnedSocket;
+
+/// The Context and operation of a connect to `addr` that sends `payload` along.
+pub(crate) fn context<B: IoBuf, A: ToSocketAddrs>(
+    handle: RawHandle,
+    addr: A,
+    payload: B,
+) -> Result<(Context, Operation)> {
+    let addr = addr.to_socket_addrs()?.next().ok_or(Error::new(
+        ErrorKind::InvalidInput,
+        "no addresses to connect to",
+    ))?;
+    prepare(handle, &addr)?;
+
+    let mut context = Context::with_buffer(handle, Buffer::send(payload), IOType::Write);
+    context.state = OpState::Connect;
+    Ok((context, Operation::Connect(addr)))
+}
+
+/// An unconnected TCP socket of the address family of `addr`, to be turned into a stream
+/// type with `From<OwnedSocket>`, added to a CompletionPort and connected with
+/// `TcpStreamExt::connect`.
+#[cfg(windows)]
+pub fn tcp_socket(addr: &SocketAddr) -> Result<OwnedSocket> {
+    let family = match addr {
+        SocketAddr::V4(_) => AF_INET,
+        SocketAddr::V6(_) => AF_INET6,
+    };
+    let socket = unsafe {
+        WSASocketW(
+            family as i32,
+            SOCK_STREAM,
+            IPPROTO_TCP,
+            null(),
+            0,
+            WSA_FLAG_OVERLAPPED,
+        )
+    };
+    if socket == INVALID_SOCKET {
+        return Err(Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+    }
+
+    Ok(unsafe { OwnedSocket::from_raw_socket(socket as RawSocket) })
+}
+
+/// An unconnected TCP socket of the address family of `addr`, to be turned into a stream
+/// type with `From<OwnedSocket>`, added to a CompletionPort and connected with
+/// `TcpStreamExt::connect`.
+#[cfg(target_os = "linux")]
+pub fn tcp_socket(addr: &SocketAddr) -> Result<OwnedSocket> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = cvt(unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+
+    Ok(unsafe { OwnedSocket::from_raw_fd(fd as RawFd) })
+}
+
+/// `ConnectEx` only takes bound sockets, bind to an ephemeral port unless it is.
+#[cfg(windows)]
+pub(crate) fn prepare(handle: RawHandle, addr: &SocketAddr) -> Result<()> {
+    let socket = handle as SOCKET;
+    let mut name = unsafe { zeroed::<SOCKADDR_STORAGE>() };
+    let mut name_len = size_of::<SOCKADDR_STORAGE>() as i32;
+    let ret = unsafe { getsockname(socket, &mut name as *mut _ as *mut SOCKADDR, &mut name_len) };
+    match cvt_for_socket(ret) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.raw_os_error() == Some(WSAEINVAL) => {}
+        Err(e) => return Err(e),
+    }
+
+    let any = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let (any, any_len) = SocketAddrCRepr::socket_addr_to_ptrs(&any);
+    cvt_for_socket(unsafe { bind(socket, any.as_ptr(), any_len) }).map(|_| ())
+}
+
+/// The kernel connects any socket, there is nothing to prepare.
+#[cfg(target_os = "linux")]
+pub(crate) fn prepare(_handle: RawHandle, _addr: &SocketAddr) -> Result<()> {
+    Ok(())
+}
+
+/// `ConnectEx`, looked up through `WSAIoctl` the first time it is needed.
+#[cfg(windows)]
+pub(crate) fn connect_ex(socket: SOCKET) -> Result<LPFN_CONNECTEX> {
+    static CONNECT_EX: OnceLock<LPFN_CONNECTEX> = OnceLock::new();
+
+    if let Some(connect_ex) = CONNECT_EX.get() {
+        return Ok(*connect_ex);
+    }
+
+    let connect_ex = unsafe { extension::<LPFN_CONNECTEX>(socket, WSAID_CONNECTEX)? };
+    Ok(*CONNECT_EX.get_or_init(|| connect_ex))
+}
+
+/// Look up a Winsock extension function by its GUID.
+#[cfg(windows)]
+pub(crate) unsafe fn extension<F: Copy>(socket: SOCKET, guid: GUID) -> Result<F> {
+    let mut function = zeroed::<F>();
+    let mut bytes = 0;
+    cvt_for_socket(WSAIoctl(
+        socket,
+        SIO_GET_EXTENSION_FUNCTION_POINTER,
+        &guid as *const _ as *const _,
+        size_of::<GUID>() as u32,
+        &mut function as *mut _ as *mut _,
+        size_of::<F>() as u32,
+        &mut bytes,
+        null_mut(),
+        None,
+    ))?;
+
+    Ok(function)
+}
+
+/// Bring the socket up to date once `ConnectEx` completed, `ret` is what it sent along.
+#[cfg(windows)]
+pub(crate) fn finish(handle: RawHandle, _payload: &[u8], ret: u32) -> Result<u32> {
+    cvt_for_socket(unsafe {
+        setsockopt(
+            handle as SOCKET,
+            SOL_SOCKET,
+            SO_UPDATE_CONNECT_CONTEXT,
+            null(),
+            0,
+        )
+    })?;
+
+    Ok(ret)
+}
+
+/// Send the first bytes once the connection is up. They go out without waiting, what
+/// does not fit into the send buffer of the fresh connection is left to the caller.
+/// The connect succeeded either way, a send that fails sends nothing and the next
+/// operation on the socket reports why.
+#[cfg(target_os = "linux")]
+pub(crate) fn finish(handle: RawHandle, payload: &[u8], _ret: u32) -> Result<u32> {
+    if payload.is_empty() {
+        return Ok(0);
+    }
+
+    let ret = unsafe {
+        libc::send(
+            handle,
+            payload.as_ptr() as *const _,
+            payload.len(),
+            libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+        )
+    };
+    match ret {
+        -1 => Ok(0),
+        sent => Ok(sent.min(u32::MAX as isize) as u32),
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::Result;
+
+#[cfg(target_os = "linux")]
+use std::mem::{size_of, zeroed};
+#[cfg(windows)]
+use std::sync::OnceLock;
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{
+    LPFN_DISCONNECTEX, SOCKET, TF_REUSE_SOCKET, WSAID_DISCONNECTEX,
+};
+
+#[cfg(target_os = "linux")]
+use crate::cvt;
+#[cfg(windows)]
+use crate::net::connect::extension;
+use crate::{
+    context::{IOType, OpState},
+    driver::Operation,
+    Context, RawHandle,
+};
+
+/// The Context and operation of a disconnect, which leaves the socket open to be
+/// connected again if `reuse` is set.
+pub(crate) fn context(handle: RawHandle, reuse: bool) -> (Context, Operation) {
+    let state = OpState::Disconnect { reuse };
+    let context = Context::with_state(handle, state, IOType::Write);
+    (context, Operation::Disconnect { reuse })
+}
+
+/// `DisconnectEx`, looked up through `WSAIoctl` the first time it is needed.
+#[cfg(windows)]
+pub(crate) fn disconnect_ex(socket: SOCKET) -> Result<LPFN_DISCONNECTEX> {
+    static DISCONNECT_EX: OnceLock<LPFN_DISCONNECTEX> = OnceLock::new();
+
+    if let Some(disconnect_ex) = DISCONNECT_EX.get() {
+        return Ok(*disconnect_ex);
+    }
+
+    let disconnect_ex = unsafe { extension::<LPFN_DISCONNECTEX>(socket, WSAID_DISCONNECTEX)? };
+    Ok(*DISCONNECT_EX.get_or_init(|| disconnect_ex))
+}
+
+/// The flags of `DisconnectEx`.
+#[cfg(windows)]
+pub(crate) fn flags(reuse: bool) -> u32 {
+    if reuse {
+        TF_REUSE_SOCKET
+    } else {
+        0
+    }
+}
+
+/// `DisconnectEx` with `TF_REUSE_SOCKET` leaves the socket ready for `AcceptEx` or
+/// `ConnectEx`, there is nothing left to do.
+#[cfg(windows)]
+pub(crate) fn finish(_handle: RawHandle, _reuse: bool, ret: u32) -> Result<u32> {
+    Ok(ret)
+}
+
+/// Dissolve the association of the shut down socket by connecting it to `AF_UNSPEC`,
+/// which puts it back into the closed state to be connected again.
+#[cfg(target_os = "linux")]
+pub(crate) fn finish(handle: RawHandle, reuse: bool, ret: u32) -> Result<u32> {
+    if !reuse {
+        return Ok(ret);
+    }
+
+    let mut unspec = unsafe { zeroed::<libc::sockaddr>() };
+    unspec.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+    cvt(unsafe {
+        libc::connect(
+            handle,
+            &unspec,
+            size_of::<libc::sockaddr>() as libc::socklen_t,
+        )
+    })?;
+
+    Ok(ret)
+}
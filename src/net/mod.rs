@@ -1,32 +1,61 @@
+mod accept;
+mod acceptor;
+mod ancillary;
+pub(crate) mod connect;
+pub(crate) mod disconnect;
+pub(crate) mod msg;
+mod recv_from;
+mod socket_pool;
+pub(crate) mod transmit;
 mod tcp;
 mod udp;
 
-pub use tcp::TcpStreamExt;
+pub(crate) use accept::AcceptState;
+pub use accept::Accepted;
+pub use acceptor::{AcceptorStats, ListenerAcceptor};
+pub use ancillary::{Ancillary, AncillaryOptions, Ecn, PacketInfo};
+pub use connect::tcp_socket;
+pub(crate) use recv_from::RecvFromState;
+pub use socket_pool::{SocketPool, SocketPoolStats};
+pub use tcp::{TcpListenerExt, TcpStreamExt};
 pub use udp::UdpSocketExt;
 
+#[cfg(windows)]
 use std::io::{Error, Result};
-use std::mem::zeroed;
 use std::mem::{size_of, size_of_val};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::sync::OnceLock;
+#[cfg(windows)]
 use windows_sys::Win32::Networking::WinSock::{
-    WSACleanup, WSAGetLastError, WSAStartup, AF_INET, AF_INET6, IN6_ADDR, IN6_ADDR_0, IN_ADDR,
-    IN_ADDR_0, SOCKADDR, SOCKADDR_IN, SOCKADDR_IN6, SOCKADDR_IN6_0, SOCKET_ERROR, WSADATA,
+    WSAGetLastError, AF_INET, AF_INET6, IN6_ADDR, IN6_ADDR_0, IN_ADDR, IN_ADDR_0, SOCKADDR,
+    SOCKADDR_IN, SOCKADDR_IN6, SOCKADDR_IN6_0, SOCKET_ERROR,
 };
 
+/// The raw socket accessor the `*Ext` traits are built on, `AsRawFd` outside of Windows.
+#[cfg(windows)]
+pub(crate) use std::os::windows::io::AsRawSocket;
+#[cfg(target_os = "linux")]
+pub(crate) use std::os::fd::AsRawFd as AsRawSocket;
 
+/// An owned socket, what an accepted connection comes as before it is made a stream.
+#[cfg(windows)]
+pub use std::os::windows::io::OwnedSocket;
+#[cfg(target_os = "linux")]
+pub use std::os::fd::OwnedFd as OwnedSocket;
+
+#[cfg(windows)]
 pub(crate) union SocketAddrCRepr {
     v4: SOCKADDR_IN,
     v6: SOCKADDR_IN6,
 }
 
+#[cfg(windows)]
 impl SocketAddrCRepr {
     pub(crate) fn socket_addr_to_ptrs(addr: &SocketAddr) -> (Self, i32) {
         match *addr {
             SocketAddr::V4(ref v4) => {
                 let sockaddr_in = SOCKADDR_IN {
                     sin_family: AF_INET,
-                    sin_port: v4.port(),
+                    sin_port: v4.port().to_be(),
                     sin_addr: IN_ADDR {
                         S_un: IN_ADDR_0 {
                             S_addr: u32::from_ne_bytes(v4.ip().octets()),
@@ -42,7 +71,7 @@ impl SocketAddrCRepr {
             SocketAddr::V6(ref v6) => {
                 let sockaddr_in = SOCKADDR_IN6 {
                     sin6_family: AF_INET6,
-                    sin6_port: v6.port(),
+                    sin6_port: v6.port().to_be(),
                     sin6_addr: IN6_ADDR {
                         u: IN6_ADDR_0 {
                             Byte: v6.ip().octets(),
@@ -79,7 +108,7 @@ impl SocketAddrCRepr {
                     (ip >> 8) as u8,
                     ip as u8,
                 );
-                Some(SocketAddr::V4(SocketAddrV4::new(ip, b.sin_port.to_be())))
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(b.sin_port))))
             }
             AF_INET6 if len as usize >= size_of::<SOCKADDR_IN6>() => {
                 let b = &*(ptr as *const SOCKADDR_IN6);
@@ -96,9 +125,9 @@ impl SocketAddrCRepr {
                 );
                 let addr = SocketAddrV6::new(
                     ip,
-                    b.sin6_port.to_be(),
-                    b.sin6_flowinfo.to_be(),
-                    b.Anonymous.sin6_scope_id.to_be(),
+                    u16::from_be(b.sin6_port),
+                    b.sin6_flowinfo,
+                    b.Anonymous.sin6_scope_id,
                 );
                 Some(SocketAddr::V6(addr))
             }
@@ -107,6 +136,7 @@ impl SocketAddrCRepr {
     }
 }
 
+#[cfg(windows)]
 pub(crate) fn cvt_for_socket(ret: i32) -> Result<i32> {
     if ret == SOCKET_ERROR {
         let code = unsafe { WSAGetLastError() };
@@ -115,3 +145,98 @@ pub(crate) fn cvt_for_socket(ret: i32) -> Result<i32> {
         Ok(ret)
     }
 }
+
+#[cfg(target_os = "linux")]
+pub(crate) union SocketAddrCRepr {
+    v4: libc::sockaddr_in,
+    v6: libc::sockaddr_in6,
+}
+
+#[cfg(target_os = "linux")]
+impl SocketAddrCRepr {
+    pub(crate) fn socket_addr_to_ptrs(addr: &SocketAddr) -> (Self, u32) {
+        match *addr {
+            SocketAddr::V4(ref v4) => {
+                let sockaddr_in = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: v4.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(v4.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+
+                let sockaddr_in_size = size_of_val(&sockaddr_in) as u32;
+
+                (Self { v4: sockaddr_in }, sockaddr_in_size)
+            }
+            SocketAddr::V6(ref v6) => {
+                let sockaddr_in = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: v6.port().to_be(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: v6.ip().octets(),
+                    },
+                    sin6_flowinfo: v6.flowinfo(),
+                    sin6_scope_id: v6.scope_id(),
+                };
+
+                let sockaddr_in_size = size_of_val(&sockaddr_in) as u32;
+
+                (Self { v6: sockaddr_in }, sockaddr_in_size)
+            }
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const libc::sockaddr {
+        self as *const _ as *const _
+    }
+
+    pub(crate) unsafe fn ptrs_to_socket_addr(
+        ptr: *const libc::sockaddr,
+        len: u32,
+    ) -> Option<SocketAddr> {
+        if (len as usize) < size_of::<libc::sa_family_t>() {
+            return None;
+        }
+        match (*ptr).sa_family as i32 {
+            libc::AF_INET if len as usize >= size_of::<libc::sockaddr_in>() => {
+                let b = &*(ptr as *const libc::sockaddr_in);
+                let ip = Ipv4Addr::from(b.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(b.sin_port))))
+            }
+            libc::AF_INET6 if len as usize >= size_of::<libc::sockaddr_in6>() => {
+                let b = &*(ptr as *const libc::sockaddr_in6);
+                let addr = SocketAddrV6::new(
+                    Ipv6Addr::from(b.sin6_addr.s6_addr),
+                    u16::from_be(b.sin6_port),
+                    b.sin6_flowinfo,
+                    b.sin6_scope_id,
+                );
+                Some(SocketAddr::V6(addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, slice};
+
+    use super::SocketAddrCRepr;
+
+    #[test]
+    fn port_in_network_order() {
+        for addr in ["127.0.0.1:8080", "[::1]:8080"] {
+            let addr = addr.parse::<SocketAddr>().unwrap();
+            let (repr, len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+
+            // The family comes first, the port right after it.
+            let bytes = unsafe { slice::from_raw_parts(repr.as_ptr() as *const u8, len as usize) };
+            assert_eq!(bytes[2..4], 8080u16.to_be_bytes());
+            let decoded = unsafe { SocketAddrCRepr::ptrs_to_socket_addr(repr.as_ptr(), len) };
+            assert_eq!(decoded, Some(addr));
+        }
+    }
+}
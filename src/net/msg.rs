@@ -0,0 +1,208 @@
+use std::{
+    io::Result,
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    ptr::copy_nonoverlapping,
+};
+
+#[cfg(windows)]
+use std::sync::OnceLock;
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{
+    LPFN_WSARECVMSG, SOCKADDR, SOCKADDR_STORAGE, SOCKET, WSABUF, WSAID_WSARECVMSG, WSAMSG,
+};
+
+#[cfg(windows)]
+use crate::net::connect::extension;
+use crate::{
+    buf::Buffer,
+    context::{IOType, OpState},
+    net::{ancillary::Control, Ancillary, SocketAddrCRepr},
+    Context, IoBuf, IoBufMut, RawHandle,
+};
+
+/// The Context of a receive of a datagram into `buff`, which keeps the sender address
+/// and the control messages that came along.
+pub(crate) fn recv_context<B: IoBufMut>(handle: RawHandle, buff: B) -> Context {
+    let mut context = Context::with_buffer(handle, Buffer::recv(buff), IOType::Read);
+    context.state = OpState::RecvMsg(Box::new(MsgState::new()));
+    context
+}
+
+/// The Context of a send of `buff` as one datagram to `addr` with the control messages of
+/// `ancillary`.
+pub(crate) fn send_context<B: IoBuf>(
+    handle: RawHandle,
+    buff: B,
+    addr: &SocketAddr,
+    ancillary: &Ancillary,
+) -> Result<Context> {
+    let state = MsgState::send(addr, ancillary)?;
+    let mut context = Context::with_buffer(handle, Buffer::send(buff), IOType::Write);
+    context.state = OpState::SendMsg(Box::new(state));
+    Ok(context)
+}
+
+/// What a datagram received with `recv_msg` came with.
+pub(crate) struct Message {
+    pub(crate) source: Option<SocketAddr>,
+    pub(crate) ancillary: Ancillary,
+}
+
+/// The `WSAMSG` of `WSARecvMsg` or `WSASendMsg` along with the address and the control
+/// buffer it points at.
+#[cfg(windows)]
+pub(crate) struct MsgState {
+    addr: SOCKADDR_STORAGE,
+    wsa_buf: WSABUF,
+    msg: WSAMSG,
+    control: Control,
+}
+
+/// The `msghdr` of `IORING_OP_RECVMSG` or `IORING_OP_SENDMSG` along with the address and
+/// the control buffer it points at.
+#[cfg(target_os = "linux")]
+pub(crate) struct MsgState {
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+    control: Control,
+}
+
+// The message points into the state itself and into the buffer of its Context.
+unsafe impl Send for MsgState {}
+
+impl MsgState {
+    /// A state for the address of `addr` and the control messages of `ancillary` to go out
+    /// with a send.
+    fn send(addr: &SocketAddr, ancillary: &Ancillary) -> Result<Self> {
+        let mut state = Self::new();
+        let control_len = state.control.encode(ancillary, addr.is_ipv6())?;
+
+        let (repr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(addr);
+        unsafe {
+            copy_nonoverlapping(
+                &repr as *const _ as *const u8,
+                &mut state.addr as *mut _ as *mut u8,
+                addr_len as usize,
+            );
+        }
+        state.set_lens(addr_len as usize, control_len);
+
+        Ok(state)
+    }
+}
+
+#[cfg(windows)]
+impl MsgState {
+    fn new() -> Self {
+        let mut state = Self {
+            addr: unsafe { zeroed() },
+            wsa_buf: WSABUF {
+                len: 0,
+                buf: std::ptr::null_mut(),
+            },
+            msg: unsafe { zeroed() },
+            control: Control::new(),
+        };
+        state.set_lens(size_of::<SOCKADDR_STORAGE>(), state.control.capacity());
+        state
+    }
+
+    fn set_lens(&mut self, addr_len: usize, control_len: usize) {
+        self.msg.namelen = addr_len as i32;
+        self.msg.Control.len = control_len as u32;
+    }
+
+    /// The `WSAMSG` over `buff_ptr`. The state has to stay where it is until the
+    /// operation completes.
+    pub(crate) fn msg(&mut self, buff_ptr: *mut u8, buff_len: u32) -> *mut WSAMSG {
+        self.wsa_buf = WSABUF {
+            len: buff_len,
+            buf: buff_ptr,
+        };
+        self.msg.name = &mut self.addr as *mut _ as *mut SOCKADDR;
+        self.msg.lpBuffers = &mut self.wsa_buf;
+        self.msg.dwBufferCount = 1;
+        self.msg.Control.buf = self.control.as_mut_ptr();
+        &mut self.msg
+    }
+
+    /// The sender address and the control messages, once the receive completed.
+    pub(crate) fn finish(&self) -> Message {
+        Message {
+            source: unsafe {
+                SocketAddrCRepr::ptrs_to_socket_addr(
+                    &self.addr as *const _ as *const SOCKADDR,
+                    self.msg.namelen,
+                )
+            },
+            ancillary: self.control.decode(self.msg.Control.len as usize),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl MsgState {
+    fn new() -> Self {
+        let mut state = Self {
+            addr: unsafe { zeroed() },
+            iov: libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            },
+            msg: unsafe { zeroed() },
+            control: Control::new(),
+        };
+        state.set_lens(
+            size_of::<libc::sockaddr_storage>(),
+            state.control.capacity(),
+        );
+        state
+    }
+
+    fn set_lens(&mut self, addr_len: usize, control_len: usize) {
+        self.msg.msg_namelen = addr_len as libc::socklen_t;
+        self.msg.msg_controllen = control_len as _;
+    }
+
+    /// The `msghdr` over `buff_ptr`. The state has to stay where it is until the
+    /// operation completes.
+    pub(crate) fn msg(&mut self, buff_ptr: *mut u8, buff_len: u32) -> *mut libc::msghdr {
+        self.iov = libc::iovec {
+            iov_base: buff_ptr as *mut libc::c_void,
+            iov_len: buff_len as usize,
+        };
+        self.msg.msg_name = &mut self.addr as *mut _ as *mut libc::c_void;
+        self.msg.msg_iov = &mut self.iov;
+        self.msg.msg_iovlen = 1;
+        self.msg.msg_control = self.control.as_mut_ptr() as *mut libc::c_void;
+        &mut self.msg
+    }
+
+    /// The sender address and the control messages, once the receive completed.
+    pub(crate) fn finish(&self) -> Message {
+        Message {
+            source: unsafe {
+                SocketAddrCRepr::ptrs_to_socket_addr(
+                    &self.addr as *const _ as *const libc::sockaddr,
+                    self.msg.msg_namelen,
+                )
+            },
+            ancillary: self.control.decode(self.msg.msg_controllen as _),
+        }
+    }
+}
+
+/// `WSARecvMsg`, looked up through `WSAIoctl` the first time it is needed.
+#[cfg(windows)]
+pub(crate) fn wsa_recv_msg(socket: SOCKET) -> Result<LPFN_WSARECVMSG> {
+    static WSA_RECV_MSG: OnceLock<LPFN_WSARECVMSG> = OnceLock::new();
+
+    if let Some(wsa_recv_msg) = WSA_RECV_MSG.get() {
+        return Ok(*wsa_recv_msg);
+    }
+
+    let wsa_recv_msg = unsafe { extension::<LPFN_WSARECVMSG>(socket, WSAID_WSARECVMSG)? };
+    Ok(*WSA_RECV_MSG.get_or_init(|| wsa_recv_msg))
+}
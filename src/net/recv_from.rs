@@ -0,0 +1,117 @@
+use std::{
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+};
+
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{SOCKADDR, SOCKADDR_STORAGE};
+
+use crate::{
+    buf::Buffer,
+    context::{IOType, OpState},
+    net::SocketAddrCRepr,
+    Context, IoBufMut, RawHandle,
+};
+
+/// The Context of a receive-from into `buff`, which keeps the sender address.
+pub(crate) fn context<B: IoBufMut>(handle: RawHandle, buff: B) -> Context {
+    let mut context = Context::with_buffer(handle, Buffer::recv(buff), IOType::Read);
+    context.state = OpState::RecvFrom(Box::new(RecvFromState::new()));
+    context
+}
+
+/// Where the kernel writes the address a received datagram came from.
+#[cfg(windows)]
+pub(crate) struct RecvFromState {
+    addr: SOCKADDR_STORAGE,
+    addr_len: i32,
+}
+
+#[cfg(windows)]
+impl RecvFromState {
+    pub(crate) fn new() -> Self {
+        Self {
+            addr: unsafe { zeroed() },
+            addr_len: size_of::<SOCKADDR_STORAGE>() as i32,
+        }
+    }
+
+    /// The address arguments of `WSARecvFrom`, written to when the datagram arrives.
+    pub(crate) fn name(&mut self) -> (*mut SOCKADDR, *mut i32) {
+        (
+            &mut self.addr as *mut _ as *mut SOCKADDR,
+            &mut self.addr_len,
+        )
+    }
+
+    /// The sender address, once the receive completed.
+    pub(crate) fn finish(&self) -> Option<SocketAddr> {
+        unsafe {
+            SocketAddrCRepr::ptrs_to_socket_addr(
+                &self.addr as *const _ as *const SOCKADDR,
+                self.addr_len,
+            )
+        }
+    }
+}
+
+/// Where the kernel writes the address a received datagram came from, along with the
+/// `msghdr` `IORING_OP_RECVMSG` takes, whose `msg_namelen` ends up as its length.
+#[cfg(target_os = "linux")]
+pub(crate) struct RecvFromState {
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+// The `msghdr` points into the state itself and into the buffer of its Context.
+#[cfg(target_os = "linux")]
+unsafe impl Send for RecvFromState {}
+
+#[cfg(target_os = "linux")]
+impl RecvFromState {
+    pub(crate) fn new() -> Self {
+        let mut msg = unsafe { zeroed::<libc::msghdr>() };
+        msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        Self {
+            addr: unsafe { zeroed() },
+            iov: libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            },
+            msg,
+        }
+    }
+
+    /// The address arguments of `recvfrom`, written to when the datagram arrives.
+    pub(crate) fn name(&mut self) -> (*mut libc::sockaddr, *mut libc::socklen_t) {
+        (
+            &mut self.addr as *mut _ as *mut libc::sockaddr,
+            &mut self.msg.msg_namelen,
+        )
+    }
+
+    /// The `msghdr` of `IORING_OP_RECVMSG` receiving into `buff_ptr`. The state has to
+    /// stay where it is until the receive completes.
+    pub(crate) fn msg(&mut self, buff_ptr: *mut u8, buff_len: u32) -> *mut libc::msghdr {
+        self.iov = libc::iovec {
+            iov_base: buff_ptr as *mut libc::c_void,
+            iov_len: buff_len as usize,
+        };
+        self.msg.msg_name = &mut self.addr as *mut _ as *mut libc::c_void;
+        self.msg.msg_iov = &mut self.iov;
+        self.msg.msg_iovlen = 1;
+        &mut self.msg
+    }
+
+    /// The sender address, once the receive completed.
+    pub(crate) fn finish(&self) -> Option<SocketAddr> {
+        unsafe {
+            SocketAddrCRepr::ptrs_to_socket_addr(
+                &self.addr as *const _ as *const libc::sockaddr,
+                self.msg.msg_namelen,
+            )
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::VecDeque,
+    io::Result,
+    mem::size_of,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+#[cfg(windows)]
+use std::{mem::zeroed, os::windows::io::AsRawSocket};
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::HANDLE;
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{
+    getsockopt, AF_INET, AF_INET6, SOCKET, SOL_SOCKET, SO_PROTOCOL_INFOW, WSAPROTOCOL_INFOW,
+};
+
+#[cfg(target_os = "linux")]
+use crate::cvt;
+#[cfg(windows)]
+use crate::net::cvt_for_socket;
+use crate::{completion_port::deregister, RawHandle};
+
+use super::{tcp_socket, OwnedSocket};
+
+/// What a `SocketPool` has kept and handed out so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketPoolStats {
+    /// Sockets waiting to be reused.
+    pub idle: usize,
+    /// Sockets handed out again.
+    pub reused: u64,
+    /// Requests that found no socket of their address family.
+    pub misses: u64,
+    /// Sockets closed because they were idle longer than the maximum age.
+    pub expired: u64,
+    /// Sockets closed to make room for newer ones, or whose address family could not
+    /// be told.
+    pub discarded: u64,
+}
+
+struct Idle {
+    socket: OwnedSocket,
+    token: usize,
+    family: i32,
+    since: Instant,
+}
+
+struct State {
+    /// Oldest first.
+    idle: VecDeque<Idle>,
+    max_sockets: usize,
+    max_age: Duration,
+    stats: SocketPoolStats,
+}
+
+impl State {
+    fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.idle.len();
+        while let Some(idle) = self.idle.pop_front() {
+            if now.duration_since(idle.since) < self.max_age {
+                self.idle.push_front(idle);
+                break;
+            }
+            close(idle.socket);
+        }
+
+        let expired = before - self.idle.len();
+        self.stats.expired += expired as u64;
+        expired
+    }
+
+    fn take(&mut self, family: i32) -> Option<(usize, OwnedSocket)> {
+        self.expire();
+        // The most recently disconnected socket first.
+        match self.idle.iter().rposition(|idle| idle.family == family) {
+            Some(index) => {
+                let idle = self.idle.remove(index)?;
+                self.stats.reused += 1;
+                Some((idle.token, idle.socket))
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+}
+
+/// Disconnected sockets kept to be connected again, which spares creating a socket and
+/// registering it with the CompletionPort for every short-lived connection.
+///
+/// A socket goes into the pool once `TcpStreamExt::disconnect` completed on it with
+/// `reuse` set, and stays registered with its CompletionPort under the token it had.
+/// The pool keeps at most `max_sockets`, closing the oldest to make room, and closes
+/// sockets idle longer than `max_age`.
+///
+/// Sockets come back out through `tcp_socket` for a connect, and on Windows through
+/// `TcpListenerExt::accept_pooled` to accept into. Linux accepts into sockets of its
+/// own, pool the sockets of connects there.
+#[derive(Clone)]
+pub struct SocketPool {
+    state: Arc<Mutex<State>>,
+}
+
+impl SocketPool {
+    /// A pool keeping at most `max_sockets`, each for at most `max_age`.
+    pub fn new(max_sockets: usize, max_age: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                idle: VecDeque::new(),
+                max_sockets,
+                max_age,
+                stats: SocketPoolStats::default(),
+            })),
+        }
+    }
+
+    /// Keep `socket`, registered with its CompletionPort under `token`, after a disconnect
+    /// with `reuse` completed on it. Returns whether it was kept.
+    pub fn put(&self, token: usize, socket: OwnedSocket) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let family = match family(raw_handle(&socket)) {
+            Ok(family) if state.max_sockets > 0 => family,
+            _ => {
+                state.stats.discarded += 1;
+                close(socket);
+                return false;
+            }
+        };
+
+        state.expire();
+        while state.idle.len() >= state.max_sockets {
+            if let Some(idle) = state.idle.pop_front() {
+                close(idle.socket);
+            }
+            state.stats.discarded += 1;
+        }
+        state.idle.push_back(Idle {
+            socket,
+            token,
+            family,
+            since: Instant::now(),
+        });
+
+        true
+    }
+
+    /// A kept socket of the address family of `addr` and the token it is registered under.
+    pub fn take(&self, addr: &SocketAddr) -> Option<(usize, OwnedSocket)> {
+        self.state.lock().unwrap().take(family_of(addr))
+    }
+
+    /// A socket to connect to `addr`: a kept one with the token it is still registered
+    /// under, or a new one from `net::tcp_socket` that has to be added to the port.
+    pub fn tcp_socket(&self, addr: &SocketAddr) -> Result<(Option<usize>, OwnedSocket)> {
+        match self.take(addr) {
+            Some((token, socket)) => Ok((Some(token), socket)),
+            None => Ok((None, tcp_socket(addr)?)),
+        }
+    }
+
+    /// A kept socket to accept into on a listener of `family`.
+    #[cfg(windows)]
+    pub(crate) fn take_family(&self, family: i32) -> Option<(usize, OwnedSocket)> {
+        self.state.lock().unwrap().take(family)
+    }
+
+    /// Close the sockets idle longer than the maximum age, returns how many.
+    /// `put` and `take` do so as well.
+    pub fn expire(&self) -> usize {
+        self.state.lock().unwrap().expire()
+    }
+
+    pub fn stats(&self) -> SocketPoolStats {
+        let state = self.state.lock().unwrap();
+        SocketPoolStats {
+            idle: state.idle.len(),
+            ..state.stats
+        }
+    }
+}
+
+#[cfg(windows)]
+fn family_of(addr: &SocketAddr) -> i32 {
+    match addr {
+        SocketAddr::V4(_) => AF_INET as i32,
+        SocketAddr::V6(_) => AF_INET6 as i32,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn family_of(addr: &SocketAddr) -> i32 {
+    match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    }
+}
+
+/// Close a socket the pool does not keep, forgetting it on its CompletionPort first so a
+/// socket that gets the same handle is not mistaken for it.
+fn close(socket: OwnedSocket) {
+    let _ = deregister(raw_handle(&socket));
+}
+
+#[cfg(windows)]
+fn raw_handle(socket: &OwnedSocket) -> RawHandle {
+    socket.as_raw_socket() as HANDLE
+}
+
+#[cfg(target_os = "linux")]
+fn raw_handle(socket: &OwnedSocket) -> RawHandle {
+    socket.as_raw_fd()
+}
+
+/// The address family the socket was created with, it may not be bound any longer.
+#[cfg(windows)]
+pub(crate) fn family(handle: RawHandle) -> Result<i32> {
+    let mut info = unsafe { zeroed::<WSAPROTOCOL_INFOW>() };
+    let mut info_len = size_of::<WSAPROTOCOL_INFOW>() as i32;
+    cvt_for_socket(unsafe {
+        getsockopt(
+            handle as SOCKET,
+            SOL_SOCKET,
+            SO_PROTOCOL_INFOW,
+            &mut info as *mut _ as *mut u8,
+            &mut info_len,
+        )
+    })?;
+
+    Ok(info.iAddressFamily)
+}
+
+/// The address family the socket was created with, it may not be bound any longer.
+#[cfg(target_os = "linux")]
+pub(crate) fn family(handle: RawHandle) -> Result<i32> {
+    let mut family = 0;
+    let mut family_len = size_of::<i32>() as libc::socklen_t;
+    cvt(unsafe {
+        libc::getsockopt(
+            handle,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut family as *mut _ as *mut libc::c_void,
+            &mut family_len,
+        )
+    })?;
+
+    Ok(family)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, thread::sleep, time::Duration};
+
+    use crate::net::tcp_socket;
+
+    use super::{SocketPool, SocketPoolStats};
+
+    #[test]
+    fn bounded_by_size_and_age() {
+        let v4: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        let pool = SocketPool::new(2, Duration::from_millis(200));
+
+        assert!(pool.put(1, tcp_socket(&v4).unwrap()));
+        assert!(pool.put(2, tcp_socket(&v4).unwrap()));
+        // Full, the oldest makes room.
+        assert!(pool.put(3, tcp_socket(&v4).unwrap()));
+        assert_eq!(pool.take(&v4).unwrap().0, 3);
+        assert!(pool.take(&v6).is_none());
+        assert_eq!(pool.take(&v4).unwrap().0, 2);
+        assert!(pool.take(&v4).is_none());
+
+        assert!(pool.put(4, tcp_socket(&v4).unwrap()));
+        sleep(Duration::from_millis(250));
+        assert_eq!(pool.expire(), 1);
+        assert_eq!(
+            pool.stats(),
+            SocketPoolStats {
+                idle: 0,
+                reused: 2,
+                misses: 2,
+                expired: 1,
+                discarded: 1,
+            }
+        );
+
+        // Nothing kept, a new socket to add to the port.
+        let (token, _) = pool.tcp_socket(&v4).unwrap();
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn keeps_nothing_without_room() {
+        let pool = SocketPool::new(0, Duration::from_secs(60));
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(!pool.put(1, tcp_socket(&addr).unwrap()));
+        assert_eq!(pool.stats().discarded, 1);
+    }
+}
@@ -1,115 +1,227 @@
 use std::io::Result;
+use std::net::ToSocketAddrs;
+use std::ops::Range;
+use std::time::Instant;
 
-use std::os::windows::prelude::AsRawSocket;
+use crate::buf::{Buffer, Vectored};
+use crate::completion_port::{submit, submit_awaited, submit_context, submit_context_awaited};
+use crate::context::{IOType, OpState};
+use crate::driver::Operation;
+use crate::{AsHandle, Context, IoBuf, IoBufMut, OpFuture, OperationId};
 
+use super::{connect, disconnect, transmit, AcceptState, AsRawSocket, SocketPool};
 
-use windows_sys::Win32::Networking::WinSock::{WSARecv, WSASend, WSA_IO_PENDING};
-use windows_sys::Win32::{
-    Foundation::HANDLE,
-    Networking::WinSock::{SOCKET, WSABUF},
-};
-
-use crate::context::IOType;
-
-use crate::len;
-use crate::{AsHandle, Context};
+/// Addtional method for the `TcpListener` type.
+pub trait TcpListenerExt: AsHandle + AsRawSocket {
+    /// Accept a connection without a thread blocking on it.
+    /// This issues `AcceptEx` on Windows and `IORING_OP_ACCEPT` on Linux, the connection
+    /// and its addresses come out of `Completion::into_accepted`.
+    fn accept(&self) -> Result<OperationId> {
+        let state = OpState::Accept(Box::new(AcceptState::new(self.as_handle(), None)?));
+        let context = Context::with_state(self.as_handle(), state, IOType::Read);
+        submit_context(context, Operation::Accept, None)
+    }
 
-use super::cvt_for_socket;
+    /// Like `accept`, accepting into a socket from `pool` if it keeps one of the address
+    /// family of the listener, see `Accepted::token`.
+    /// Linux accepts into a new socket either way.
+    fn accept_pooled(&self, pool: &SocketPool) -> Result<OperationId> {
+        let state = AcceptState::new(self.as_handle(), Some(pool))?;
+        let state = OpState::Accept(Box::new(state));
+        let context = Context::with_state(self.as_handle(), state, IOType::Read);
+        submit_context(context, Operation::Accept, None)
+    }
 
-pub trait TcpListenerExt<T>: AsHandle + AsRawSocket {
-    fn accept(&self) -> Result<(T, Context)>;
+    /// Like `accept`, as a future that resolves to the completion.
+    fn accept_async(&self) -> OpFuture {
+        match AcceptState::new(self.as_handle(), None) {
+            Ok(state) => {
+                let state = OpState::Accept(Box::new(state));
+                let context = Context::with_state(self.as_handle(), state, IOType::Read);
+                submit_context_awaited(context, Operation::Accept)
+            }
+            Err(e) => OpFuture::new(Err(e)),
+        }
+    }
 }
 
 /// Addtional method for the `TcpStream` type.
 pub trait TcpStreamExt: AsHandle + AsRawSocket {
 
     /// Execute an ovelapped read I/O on this TCP stream.
-    /// This function will issue an overlapped I/O write (via `WSARecv`) on this
-    /// socket.
-    fn read(&mut self, mut buff: Vec<u8>) -> Result<Context> {
-        let socket = self.as_raw_socket() as SOCKET;
-        let buff_len = len(&buff);
-        let wsa_buff = WSABUF {
-            len: buff_len,
-            buf: buff.as_mut_ptr(),
-        };
-        let handle = self.as_handle();
-        let mut context = Context::new(handle, buff, IOType::Read);
-        let mut bytes_used = 0;
-        let mut flags = 0;
-
-        let ret = unsafe {
-            WSARecv(
-                socket,
-                &wsa_buff,
-                1,
-                &mut bytes_used,
-                &mut flags,
-                context.over_lapped_ptr(),
-                None,
-            )
-        };
-
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok(context),
-            Err(ref e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(context),
-            Err(e) => Err(e),
-        }
+    /// This issues `WSARecv` on Windows and `IORING_OP_RECV` on Linux.
+    fn read<B: IoBufMut>(&mut self, buff: B) -> Result<OperationId> {
+        self.read_with_deadline(buff, None)
+    }
+
+    /// Like `read`, but past `deadline` the read is cancelled and completes with
+    /// `ErrorKind::TimedOut`, see `OperationalResult::is_timed_out`.
+    fn read_with_deadline<B: IoBufMut>(&mut self, buff: B, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Recv, deadline)
     }
 
     /// Execute an ovelapped write I/O on this TCP stream.
-    /// This function will issue an overlapped I/O write (via `WSASend`) on this
-    /// socket.
-    fn write(&self, mut buff: Vec<u8>) -> Result<Context> {
-        let socket = self.as_raw_socket() as SOCKET;
-        let buff_len = len(&buff);
-
-        let wsa_buff = WSABUF {
-            len: buff_len,
-            buf: buff.as_mut_ptr(),
-        };
-        let handle = self.as_handle();
-        let mut context = Context::new(handle, buff, IOType::Write);
-        let mut bytes_used = 0;
-
-        let ret = unsafe {
-            WSASend(
-                socket,
-                &wsa_buff,
-                1,
-                &mut bytes_used,
-                0,
-                context.over_lapped_ptr(),
-                None,
-            )
-        };
-
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok(context),
-            Err(ref e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(context),
-            Err(e) => Err(e),
+    /// This issues `WSASend` on Windows and `IORING_OP_SEND` on Linux.
+    fn write<B: IoBuf>(&self, buff: B) -> Result<OperationId> {
+        self.write_with_deadline(buff, None)
+    }
+
+    /// Like `write`, but past `deadline` the write is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn write_with_deadline<B: IoBuf>(&self, buff: B, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Send, deadline)
+    }
+
+    /// Receive into several buffers with one call, filling them in order.
+    /// This issues `WSARecv` with a `WSABUF` per buffer on Windows and `IORING_OP_READV`
+    /// on Linux, the buffers come back from `Completion::into_bufs`.
+    fn read_vectored<B: IoBufMut>(&mut self, bufs: Vec<B>) -> Result<OperationId> {
+        self.read_vectored_with_deadline(bufs, None)
+    }
+
+    /// Like `read_vectored`, but past `deadline` the read is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn read_vectored_with_deadline<B: IoBufMut>(
+        &mut self,
+        bufs: Vec<B>,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let vectored = Vectored::new(bufs.into_iter().map(Buffer::recv).collect());
+        let context = Context::with_vectored(self.as_handle(), vectored, IOType::Read);
+        submit_context(context, Operation::RecvVectored, deadline)
+    }
+
+    /// Send several buffers with one call, one after the other.
+    /// This issues `WSASend` with a `WSABUF` per buffer on Windows and `IORING_OP_WRITEV`
+    /// on Linux.
+    fn write_vectored<B: IoBuf>(&self, bufs: Vec<B>) -> Result<OperationId> {
+        self.write_vectored_with_deadline(bufs, None)
+    }
+
+    /// Like `write_vectored`, but past `deadline` the write is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn write_vectored_with_deadline<B: IoBuf>(
+        &self,
+        bufs: Vec<B>,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let vectored = Vectored::new(bufs.into_iter().map(Buffer::send).collect());
+        let context = Context::with_vectored(self.as_handle(), vectored, IOType::Write);
+        submit_context(context, Operation::SendVectored, deadline)
+    }
+
+    /// Connect this unconnected socket to `addr` without a thread blocking on it, sending
+    /// `first_payload` along, an empty buffer sends nothing. The completion carries how
+    /// many bytes of the payload were sent, see `tcp_socket` for a socket to connect.
+    /// This issues `ConnectEx` on Windows, binding the socket first unless it is bound,
+    /// and `IORING_OP_CONNECT` on Linux, which sends the payload once connected.
+    fn connect<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        first_payload: B,
+    ) -> Result<OperationId> {
+        self.connect_with_deadline(addr, first_payload, None)
+    }
+
+    /// Like `connect`, but past `deadline` the connect is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn connect_with_deadline<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        first_payload: B,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let (context, op) = connect::context(self.as_handle(), addr, first_payload)?;
+        submit_context(context, op, deadline)
+    }
+
+    /// Like `connect`, as a future that resolves to the completion.
+    fn connect_async<B: IoBuf, A: ToSocketAddrs>(&self, addr: A, first_payload: B) -> OpFuture {
+        match connect::context(self.as_handle(), addr, first_payload) {
+            Ok((context, op)) => submit_context_awaited(context, op),
+            Err(e) => OpFuture::new(Err(e)),
         }
     }
+
+    /// Close the connection without a thread blocking on it. With `reuse` the socket stays
+    /// open to be connected again, see `SocketPool` to keep it until then.
+    /// This issues `DisconnectEx` on Windows, with `TF_REUSE_SOCKET` for `reuse`, and
+    /// `IORING_OP_SHUTDOWN` on Linux, which then dissolves the connection for `reuse`.
+    /// That resets a connection the peer has not closed yet, data it has not
+    /// acknowledged is lost.
+    fn disconnect(&self, reuse: bool) -> Result<OperationId> {
+        let (context, op) = disconnect::context(self.as_handle(), reuse);
+        submit_context(context, op, None)
+    }
+
+    /// Like `disconnect`, as a future that resolves to the completion.
+    fn disconnect_async(&self, reuse: bool) -> OpFuture {
+        let (context, op) = disconnect::context(self.as_handle(), reuse);
+        submit_context_awaited(context, op)
+    }
+
+    /// Send `range` of `file` on this stream between `head` and `tail` without copying it
+    /// through user space, the completion carries how many bytes were sent in all.
+    /// `file` has to stay open until the transmit completes, a file that ends before the
+    /// range does completes it short, without the tail.
+    /// This issues `TransmitFile` on Windows and `sendfile` on Linux whenever the socket
+    /// is writable, Linux makes a blocking socket nonblocking until the transmit completes.
+    fn transmit_file<F: AsHandle, H: IoBuf, T: IoBuf>(
+        &self,
+        file: &F,
+        range: Range<u64>,
+        head: H,
+        tail: T,
+    ) -> Result<OperationId> {
+        let (context, op) = transmit::context(self.as_handle(), file, range, head, tail)?;
+        submit_context(context, op, None)
+    }
+
+    /// Like `transmit_file`, as a future that resolves to the completion.
+    fn transmit_file_async<F: AsHandle, H: IoBuf, T: IoBuf>(
+        &self,
+        file: &F,
+        range: Range<u64>,
+        head: H,
+        tail: T,
+    ) -> OpFuture {
+        match transmit::context(self.as_handle(), file, range, head, tail) {
+            Ok((context, op)) => submit_context_awaited(context, op),
+            Err(e) => OpFuture::new(Err(e)),
+        }
+    }
+
+    /// Like `read`, as a future that resolves to the completion, see `OpFuture`.
+    fn read_async<B: IoBufMut>(&mut self, buff: B) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Recv)
+    }
+
+    /// Like `write`, as a future that resolves to the completion.
+    fn write_async<B: IoBuf>(&self, buff: B) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Send)
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, windows))]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::HashSet;
     use std::fs::{OpenOptions};
     use std::io::Write as StdWrite;
-    use std::os::windows::prelude::{AsRawSocket, OpenOptionsExt};
+    use std::os::windows::prelude::{AsRawSocket, OpenOptionsExt, OwnedSocket};
     use std::{
         net::{TcpListener, TcpStream},
         thread::spawn,
+        time::Duration,
     };
     use windows_sys::Win32::Foundation::HANDLE;
     use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
 
     use crate::fs::FileExt;
+    use crate::net::{tcp_socket, SocketPool};
     use crate::AsHandle;
     use crate::CompletionPort;
 
-    use super::TcpStreamExt;
+    use super::{TcpListenerExt, TcpStreamExt};
 
     impl AsHandle for TcpStream {
 
@@ -139,29 +251,23 @@ mod tests {
         cmp.add(1, &stream).unwrap();
         cmp.add(2, &file).unwrap();
 
-        let mut map = HashMap::new();
-        map.insert(1, stream.read(vec![0; 10]).unwrap());
-        map.insert(2, file.read(vec![0; 3]).unwrap());
+        let mut pending = HashSet::new();
+        pending.insert(stream.read(vec![0; 10]).unwrap());
+        pending.insert(file.read(vec![0; 3]).unwrap());
 
         loop {
-            if map.is_empty() {
+            if pending.is_empty() {
                 break;
             } else {
-                let result_list = cmp.get_many(map.len(), None).unwrap();
-
-                for result in result_list {
-                    if let Some(context) = map.remove(&result.token()) {
-                        if result.token() == 1 {
-                            assert_eq!(
-                                &context.get_buff()[..result.bytes_used() as usize],
-                                b"hello".as_slice()
-                            );
+                let completion_list = cmp.get_many(pending.len(), None).unwrap();
+
+                for completion in completion_list {
+                    if completion.id().is_some_and(|id| pending.remove(&id)) {
+                        if completion.token() == 1 {
+                            assert_eq!(completion.data(), b"hello".as_slice());
                         }
-                        if result.token() == 2 {
-                            assert_eq!(
-                                &context.get_buff()[..result.bytes_used() as usize],
-                                b"123".as_slice()
-                            );
+                        if completion.token() == 2 {
+                            assert_eq!(completion.data(), b"123".as_slice());
                         }
                     }
                 }
@@ -170,4 +276,416 @@ mod tests {
 
         join.join().unwrap();
     }
+
+    impl AsHandle for TcpListener {
+        fn as_handle(&self) -> HANDLE {
+            self.as_raw_socket() as HANDLE
+        }
+    }
+
+    impl TcpListenerExt for TcpListener {}
+
+    #[test]
+    fn accept() {
+        let cmp = CompletionPort::new(1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        cmp.add(1, &listener).unwrap();
+
+        let id = TcpListenerExt::accept(&listener).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        let accepted = completion.into_accepted().ok().unwrap();
+        assert_eq!(accepted.local_addr(), listener.local_addr().unwrap());
+        assert_eq!(accepted.peer_addr(), client.local_addr().unwrap());
+
+        let stream = accepted.into_stream::<TcpStream>();
+        assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn connect() {
+        let cmp = CompletionPort::new(1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Not bound yet, `connect` binds it for `ConnectEx`.
+        let stream = TcpStream::from(tcp_socket(&addr).unwrap());
+        cmp.add(1, &stream).unwrap();
+        let id = stream.connect(addr, b"hello".to_vec()).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.status().unwrap(), 5);
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buff = [0; 5];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"hello");
+    }
+
+    #[test]
+    fn disconnect_and_reuse() {
+        let cmp = CompletionPort::new(1).unwrap();
+        let pool = SocketPool::new(4, Duration::from_secs(60));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = TcpStream::from(tcp_socket(&addr).unwrap());
+        cmp.add(1, &stream).unwrap();
+        stream.connect(addr, Vec::new()).unwrap();
+        cmp.get(None).unwrap().status().unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        drop(peer);
+
+        stream.disconnect(true).unwrap();
+        cmp.get(None).unwrap().status().unwrap();
+        assert!(pool.put(1, OwnedSocket::from(stream)));
+
+        // `ConnectEx` takes the socket again, it is still associated with the port.
+        let (token, socket) = pool.tcp_socket(&addr).unwrap();
+        assert_eq!(token, Some(1));
+        let stream = TcpStream::from(socket);
+        stream.connect(addr, b"again".to_vec()).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 1);
+        assert_eq!(completion.status().unwrap(), 5);
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn transmit_file() {
+        let path = std::env::temp_dir().join(format!("iocp-rs-{}-transmit.bin", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        let cmp = CompletionPort::new(1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        stream
+            .transmit_file(&file, 2..8, b"HEAD".to_vec(), b"TAIL".to_vec())
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 14);
+        let mut buff = [0; 14];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"HEAD234567TAIL");
+
+        // An empty range sends none of the file, nothing at all completes right away.
+        stream
+            .transmit_file(&file, 5..5, b"HEAD".to_vec(), b"TAIL".to_vec())
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 8);
+        let mut buff = [0; 8];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"HEADTAIL");
+        stream.transmit_file(&file, 5..5, Vec::new(), Vec::new()).unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 0);
+
+        drop(file);
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::io::{ErrorKind, Write as StdWrite};
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::{
+        fs::File,
+        net::{TcpListener, TcpStream},
+        thread::spawn,
+        time::{Duration, Instant},
+    };
+
+    use crate::net::{tcp_socket, SocketPool};
+    use crate::{AsHandle, CompletionPort, RawHandle};
+
+    use super::{TcpListenerExt, TcpStreamExt};
+
+    impl AsHandle for TcpStream {
+        fn as_handle(&self) -> RawHandle {
+            self.as_raw_fd()
+        }
+    }
+
+    impl TcpStreamExt for TcpStream {}
+
+    #[test]
+    fn tcp_read_write() {
+        let cmp = CompletionPort::new(1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let join = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            StdWrite::write_all(&mut stream, b"hello").unwrap();
+            stream
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        let id = stream.read(vec![0; 10]).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 1);
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.data(), b"hello".as_slice());
+
+        let mut peer = join.join().unwrap();
+        TcpStreamExt::write(&stream, b"world".to_vec()).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.bytes_used(), 5);
+
+        let mut buff = [0; 5];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"world");
+    }
+
+    fn vectored(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+
+        // A header and a payload in one send, without copying them together.
+        stream
+            .write_vectored(vec![&b"len:5 "[..], &b"hello"[..]])
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().bytes_used(), 11);
+        let mut buff = [0; 11];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"len:5 hello");
+
+        StdWrite::write_all(&mut peer, b"len:5 world").unwrap();
+        stream.read_vectored(vec![[0; 6], [0; 6]]).unwrap();
+        let completion = cmp.get(None).unwrap();
+        let n = completion.bytes_used() as usize;
+        let bufs = completion.into_bufs::<[u8; 6]>().ok().unwrap();
+        assert_eq!(&bufs.concat()[..n], &b"len:5 world"[..n]);
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        stream
+            .read_vectored_with_deadline(vec![[0; 6]], Some(deadline))
+            .unwrap();
+        assert!(cmp.get(None).unwrap().result().is_timed_out());
+    }
+
+    #[test]
+    fn vectored_uring() {
+        vectored(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn vectored_epoll() {
+        vectored(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    impl AsHandle for TcpListener {
+        fn as_handle(&self) -> RawHandle {
+            self.as_raw_fd()
+        }
+    }
+
+    impl TcpListenerExt for TcpListener {}
+
+    fn accept(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        cmp.add(1, &listener).unwrap();
+
+        // Issued before anyone connects, the listener is blocking.
+        let id = TcpListenerExt::accept(&listener).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.status().unwrap(), 0);
+        let accepted = completion.into_accepted().ok().unwrap();
+        assert_eq!(accepted.local_addr(), listener.local_addr().unwrap());
+        assert_eq!(accepted.peer_addr(), client.local_addr().unwrap());
+
+        let stream = accepted.into_stream::<TcpStream>();
+        cmp.add(2, &stream).unwrap();
+        TcpStreamExt::write(&stream, b"welcome".to_vec()).unwrap();
+        assert_eq!(cmp.get(None).unwrap().bytes_used(), 7);
+        let mut buff = [0; 7];
+        std::io::Read::read_exact(&mut client, &mut buff).unwrap();
+        assert_eq!(&buff, b"welcome");
+    }
+
+    #[test]
+    fn accept_uring() {
+        accept(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn accept_epoll() {
+        accept(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    fn connect(cmp: CompletionPort) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = TcpStream::from(tcp_socket(&addr).unwrap());
+        cmp.add(1, &stream).unwrap();
+        let id = stream.connect(addr, b"hello".to_vec()).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.status().unwrap(), 5);
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buff = [0; 5];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"hello");
+
+        // Nothing listens on the port once the listener is gone.
+        drop(listener);
+        let stream = TcpStream::from(tcp_socket(&addr).unwrap());
+        cmp.add(2, &stream).unwrap();
+        stream.connect(addr, Vec::new()).unwrap();
+        let error = cmp.get(None).unwrap().status().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn connect_uring() {
+        connect(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn connect_epoll() {
+        connect(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    fn disconnect_and_reuse(cmp: CompletionPort) {
+        let pool = SocketPool::new(4, Duration::from_secs(60));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (token, socket) = pool.tcp_socket(&addr).unwrap();
+        assert_eq!(token, None);
+        let stream = TcpStream::from(socket);
+        cmp.add(1, &stream).unwrap();
+        stream.connect(addr, b"first".to_vec()).unwrap();
+        cmp.get(None).unwrap().status().unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buff = [0; 5];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+
+        let id = stream.disconnect(true).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        completion.status().unwrap();
+        // Closed or reset, the peer reads nothing more.
+        let closed = std::io::Read::read(&mut peer, &mut buff);
+        assert!(matches!(closed, Ok(0) | Err(_)));
+        assert!(pool.put(1, OwnedFd::from(stream)));
+
+        // The same socket connects again, still registered under its token.
+        let (token, socket) = pool.tcp_socket(&addr).unwrap();
+        assert_eq!(token, Some(1));
+        let stream = TcpStream::from(socket);
+        stream.connect(addr, b"again".to_vec()).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.token(), 1);
+        assert_eq!(completion.status().unwrap(), 5);
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buff = [0; 5];
+        std::io::Read::read_exact(&mut peer, &mut buff).unwrap();
+        assert_eq!(&buff, b"again");
+
+        // Without reuse the socket is only shut down.
+        stream.disconnect(false).unwrap();
+        cmp.get(None).unwrap().status().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(pool.stats().reused, 1);
+    }
+
+    #[test]
+    fn disconnect_uring() {
+        disconnect_and_reuse(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn disconnect_epoll() {
+        disconnect_and_reuse(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    fn transmit(cmp: CompletionPort, name: &str) {
+        let path = std::env::temp_dir().join(format!(
+            "iocp-rs-{}-transmit-{}.bin",
+            std::process::id(),
+            name
+        ));
+        // More than the socket buffers take, the transmit waits for the peer to read.
+        let contents = (0..4 << 20).map(|n| (n % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &contents).unwrap();
+        let file = File::open(&path).unwrap();
+        let len = contents.len() as u64;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        cmp.add(1, &stream).unwrap();
+        let reader = spawn(move || {
+            let mut buff = Vec::new();
+            std::io::Read::read_to_end(&mut peer, &mut buff).unwrap();
+            buff
+        });
+        let flags = || unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_GETFL) };
+        let before = flags();
+
+        let id = stream
+            .transmit_file(&file, 100..len - 100, b"HEAD".to_vec(), b"TAIL".to_vec())
+            .unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.result().offset(), 100);
+        assert_eq!(completion.status().unwrap() as u64, len - 200 + 8);
+        // The socket is left the way it was.
+        assert_eq!(flags(), before);
+
+        stream
+            .transmit_file(&file, 50..50, Vec::new(), Vec::new())
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 0);
+
+        // The file ends first, the tail is left out.
+        stream
+            .transmit_file(&file, len - 10..len + 10, Vec::new(), b"TAIL".to_vec())
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 10);
+
+        let (start, end) = (10, 5);
+        let error = stream
+            .transmit_file(&file, start..end, Vec::new(), Vec::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        drop(stream);
+        let received = reader.join().unwrap();
+        let expected = [
+            &b"HEAD"[..],
+            &contents[100..contents.len() - 100],
+            b"TAIL",
+            &contents[contents.len() - 10..],
+        ]
+        .concat();
+        assert!(received == expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn transmit_file_uring() {
+        transmit(CompletionPort::new(1).unwrap(), "uring");
+    }
+
+    #[test]
+    fn transmit_file_epoll() {
+        transmit(CompletionPort::with_epoll(1).unwrap(), "epoll");
+    }
 }
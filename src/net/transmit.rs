@@ -0,0 +1,210 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    ops::Range,
+};
+
+#[cfg(windows)]
+use windows_sys::Win32::{Foundation::HANDLE, Networking::WinSock::TRANSMIT_FILE_BUFFERS};
+
+use crate::{
+    buf::Buffer,
+    context::{IOType, OpState},
+    driver::Operation,
+    AsHandle, Context, IoBuf, RawHandle,
+};
+
+/// The most one transmit sends, head and tail included.
+const MAX_TRANSMIT: u64 = i32::MAX as u64 - 1;
+
+/// What a transmit sends: `head`, `len` bytes of `file` from `offset` on, then `tail`.
+pub(crate) struct Transmit {
+    file: RawHandle,
+    /// Windows takes the offset from the `OVERLAPPED` instead.
+    #[cfg(target_os = "linux")]
+    offset: u64,
+    len: u64,
+    head: Buffer,
+    tail: Buffer,
+    /// How much of it went out so far.
+    #[cfg(target_os = "linux")]
+    sent: u64,
+    /// The socket if `context` made it nonblocking, it is made blocking again once the
+    /// transmit is done.
+    #[cfg(target_os = "linux")]
+    blocking: Option<RawHandle>,
+    /// `head` and `tail` the way `TransmitFile` takes them.
+    #[cfg(windows)]
+    buffers: TRANSMIT_FILE_BUFFERS,
+}
+
+// The buffers point into `head` and `tail`, which it owns.
+#[cfg(windows)]
+unsafe impl Send for Transmit {}
+
+/// The Context and operation of a transmit of `range` of `file` between `head` and `tail`.
+pub(crate) fn context<F: AsHandle, H: IoBuf, T: IoBuf>(
+    handle: RawHandle,
+    file: &F,
+    range: Range<u64>,
+    head: H,
+    tail: T,
+) -> Result<(Context, Operation)> {
+    if range.start > range.end {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the range ends before it starts",
+        ));
+    }
+
+    let (head, tail) = (Buffer::send(head), Buffer::send(tail));
+    let len = range.end - range.start;
+    if len + head.len() as u64 + tail.len() as u64 > MAX_TRANSMIT {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "too much to transmit in one operation",
+        ));
+    }
+
+    // `sendfile` takes no flag to keep it from blocking, the socket itself has to be
+    // nonblocking while the transmit is in flight.
+    #[cfg(target_os = "linux")]
+    let blocking = {
+        let flags = crate::cvt(unsafe { libc::fcntl(handle, libc::F_GETFL) })?;
+        if flags & libc::O_NONBLOCK == 0 {
+            crate::set_nonblocking(handle, true)?;
+            Some(handle)
+        } else {
+            None
+        }
+    };
+
+    let transmit = Transmit {
+        file: file.as_handle(),
+        #[cfg(target_os = "linux")]
+        offset: range.start,
+        len,
+        head,
+        tail,
+        #[cfg(target_os = "linux")]
+        sent: 0,
+        #[cfg(target_os = "linux")]
+        blocking,
+        #[cfg(windows)]
+        buffers: unsafe { std::mem::zeroed() },
+    };
+    let state = OpState::Transmit(Box::new(transmit));
+    let context = Context::with_state(handle, state, IOType::Write);
+    let op = Operation::TransmitFile {
+        offset: range.start,
+    };
+
+    Ok((context, op))
+}
+
+#[cfg(windows)]
+impl Transmit {
+    /// Whether there is nothing to send, not even a head or a tail.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0 && self.head.len() == 0 && self.tail.len() == 0
+    }
+
+    /// The file, how many bytes of it to send and the head and tail for `TransmitFile`,
+    /// which takes the offset from the `OVERLAPPED`.
+    /// An empty range goes without the file, `TransmitFile` sends all of it for a length
+    /// of 0.
+    pub(crate) fn transmit_file_args(&mut self) -> (HANDLE, u32, *const TRANSMIT_FILE_BUFFERS) {
+        self.buffers = TRANSMIT_FILE_BUFFERS {
+            Head: self.head.as_mut_ptr() as *mut _,
+            HeadLength: self.head.len(),
+            Tail: self.tail.as_mut_ptr() as *mut _,
+            TailLength: self.tail.len(),
+        };
+
+        let file = if self.len == 0 {
+            0
+        } else {
+            self.file as HANDLE
+        };
+        // `context` keeps a transmit under `MAX_TRANSMIT`, the length fits.
+        (file, self.len as u32, &self.buffers)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Transmit {
+    /// Send as much as the socket takes without blocking, `None` means it has to wait
+    /// until the socket is writable again. Completes with the bytes sent, short of the
+    /// tail if the file ends before the range does, or a negated errno.
+    pub(crate) fn perform(&mut self, socket: RawHandle) -> Option<i32> {
+        let head_len = self.head.len() as u64;
+        let file_end = head_len + self.len;
+        let total = file_end + self.tail.len() as u64;
+
+        while self.sent < total {
+            let ret = if self.sent < head_len {
+                let more = if total > head_len { libc::MSG_MORE } else { 0 };
+                send(socket, &self.head.as_slice()[self.sent as usize..], more)
+            } else if self.sent < file_end {
+                let offset = self.offset + (self.sent - head_len);
+                self.send_file(socket, offset, file_end - self.sent)
+            } else {
+                send(
+                    socket,
+                    &self.tail.as_slice()[(self.sent - file_end) as usize..],
+                    0,
+                )
+            };
+
+            match ret {
+                Ok(0) => break,
+                Ok(sent) => self.sent += sent as u64,
+                Err(e) => match e.raw_os_error().unwrap_or(libc::EIO) {
+                    libc::EINTR => continue,
+                    libc::EAGAIN => return None,
+                    errno => {
+                        self.finish();
+                        return Some(-errno);
+                    }
+                },
+            }
+        }
+
+        self.finish();
+        Some(self.sent as i32)
+    }
+
+    /// Make the socket blocking again if `context` made it nonblocking. Called once the
+    /// transmit completes, sent in full or not.
+    pub(crate) fn finish(&mut self) {
+        if let Some(socket) = self.blocking.take() {
+            let _ = crate::set_nonblocking(socket, false);
+        }
+    }
+
+    /// `sendfile` from `offset` on, the socket is nonblocking while the transmit is.
+    fn send_file(&self, socket: RawHandle, offset: u64, len: u64) -> Result<usize> {
+        let mut offset = offset as libc::off_t;
+        match unsafe { libc::sendfile(socket, self.file, &mut offset, len as usize) } {
+            -1 => Err(Error::last_os_error()),
+            sent => Ok(sent as usize),
+        }
+    }
+}
+
+// A transmit that never completes, one that failed to submit, leaves the socket as it
+// found it as well.
+#[cfg(target_os = "linux")]
+impl Drop for Transmit {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: RawHandle, buf: &[u8], flags: i32) -> Result<usize> {
+    let flags = flags | libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+    match unsafe { libc::send(socket, buf.as_ptr() as *const _, buf.len(), flags) } {
+        -1 => Err(Error::last_os_error()),
+        sent => Ok(sent as usize),
+    }
+}
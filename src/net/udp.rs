@@ -1,159 +1,586 @@
-use std::io::ErrorKind;
-use std::io::{Error, Result};
-use std::mem::zeroed;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::os::windows::prelude::AsRawSocket;
-use windows_sys::Win32::Foundation::HANDLE;
-use windows_sys::Win32::Networking::WinSock::WSASend;
-use windows_sys::Win32::Networking::WinSock::WSASendTo;
-use windows_sys::Win32::Networking::WinSock::{
-    WSARecv, WSARecvFrom, SOCKADDR, SOCKET, WSABUF, WSA_IO_PENDING,
-};
+use std::io::{Error, ErrorKind, Result};
+use std::net::ToSocketAddrs;
+use std::time::Instant;
 
+use crate::buf::{Buffer, Vectored};
+use crate::completion_port::{submit, submit_awaited, submit_context, submit_context_awaited};
 use crate::context::IOType;
-use crate::utils::len;
-use crate::{AsHandle, Context};
+use crate::driver::Operation;
+use crate::{AsHandle, Context, IoBuf, IoBufMut, OpFuture, OperationId};
 
-use super::cvt_for_socket;
-use super::SocketAddrCRepr;
+use super::{ancillary, msg, recv_from, Ancillary, AncillaryOptions, AsRawSocket};
 
 /// Addtional method for the `TcpStream` type.
 pub trait UdpSocketExt: AsRawSocket + AsHandle {
 
     /// Execute an ovelapped read I/O on this UDP stream.
-    /// This function will issue an overlapped I/O write (via `WSARecv`) on this
-    /// socket.
-    fn recv(&self, mut buff: Vec<u8>) -> Result<Context> {
-        let wsa_buf = WSABUF {
-            len: len(&buff),
-            buf: buff.as_mut_ptr(),
-        };
-        let mut flags = 0;
-        let mut bytes_used = 0;
-        let handle = self.as_handle();
-        let mut context = Context::new(handle, buff, IOType::Read);
-        let over_lapped_ptr = context.over_lapped_ptr();
-
-        let ret = unsafe {
-            WSARecv(
-                self.as_raw_socket() as SOCKET,
-                &wsa_buf,
-                1,
-                &mut bytes_used,
-                &mut flags,
-                over_lapped_ptr,
-                None,
-            )
-        };
+    /// This issues `WSARecv` on Windows and `IORING_OP_RECV` on Linux.
+    fn recv<B: IoBufMut>(&self, buff: B) -> Result<OperationId> {
+        self.recv_with_deadline(buff, None)
+    }
 
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok(context),
-            Err(ref e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(context),
-            Err(e) => Err(e),
-        }
+    /// Like `recv`, but past `deadline` the receive is cancelled and completes with
+    /// `ErrorKind::TimedOut`, see `OperationalResult::is_timed_out`.
+    fn recv_with_deadline<B: IoBufMut>(&self, buff: B, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Recv, deadline)
     }
 
-    /// This function will issue an overlapped I/O write (via `WSARecvFrom`) on this
-    /// socket.
-    fn recv_from(&self, mut buff: Vec<u8>) -> Result<(Context, SocketAddr)> {
-        let wsa_buf = WSABUF {
-            len: len(&buff),
-            buf: buff.as_mut_ptr(),
-        };
-        let mut byte_used = 0;
-        let mut flag = 0;
-        let handle = self.as_handle();
-        let mut context = Context::new(handle, buff, IOType::Read);
-        let over_lapped_ptr = context.over_lapped_ptr();
-        let mut socket_addr = unsafe { zeroed::<SOCKADDR>() };
-        let mut socket_addr_len = 0;
-
-        let ret = unsafe {
-            WSARecvFrom(
-                self.as_raw_socket() as SOCKET,
-                &wsa_buf,
-                1,
-                &mut byte_used,
-                &mut flag,
-                &mut socket_addr,
-                &mut socket_addr_len,
-                over_lapped_ptr,
-                None,
-            )
-        };
+    /// Receive a datagram along with the address it came from, which is only known once
+    /// it arrived and comes out of `Completion::source_addr`.
+    /// This issues `WSARecvFrom` on Windows and `IORING_OP_RECVMSG` on Linux.
+    fn recv_from<B: IoBufMut>(&self, buff: B) -> Result<OperationId> {
+        self.recv_from_with_deadline(buff, None)
+    }
+
+    /// Like `recv_from`, but past `deadline` the receive is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn recv_from_with_deadline<B: IoBufMut>(
+        &self,
+        buff: B,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        submit_context(recv_from::context(self.as_handle(), buff), Operation::RecvFrom, deadline)
+    }
 
-        let socket_addr =
-            unsafe { SocketAddrCRepr::ptrs_to_socket_addr(&socket_addr, socket_addr_len) }.unwrap();
+    /// Have the system attach the control messages of `options` to the datagrams received
+    /// with `recv_msg`, and stop attaching the others.
+    fn set_recv_ancillary(&self, options: AncillaryOptions) -> Result<()> {
+        ancillary::set_recv(self.as_handle(), &options)
+    }
 
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok((context, socket_addr)),
-            Err(e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok((context, socket_addr)),
-            Err(e) => Err(e),
-        }
+    /// Receive a datagram along with the address it came from and the control messages
+    /// `set_recv_ancillary` asked for, which come out of `Completion::source_addr` and
+    /// `Completion::ancillary`.
+    /// This issues `WSARecvMsg` on Windows and `IORING_OP_RECVMSG` on Linux.
+    fn recv_msg<B: IoBufMut>(&self, buff: B) -> Result<OperationId> {
+        self.recv_msg_with_deadline(buff, None)
     }
 
-    /// Execute an ovelapped send I/O on this TCP stream.
-    /// This function will issue an overlapped I/O write (via `WSASend`) on this
-    /// socket.
-    fn send(&self, mut buff: Vec<u8>) -> Result<Context> {
-        let wsa_buf = WSABUF {
-            len: len(&buff),
-            buf: buff.as_mut_ptr(),
-        };
-        let mut bytes_used = 0;
-        let mut context = Context::new(self.as_handle(), buff, IOType::Write);
-
-        let ret = unsafe {
-            WSASend(
-                self.as_raw_socket() as SOCKET,
-                &wsa_buf,
-                1,
-                &mut bytes_used,
-                0,
-                context.over_lapped_ptr(),
-                None,
-            )
+    /// Like `recv_msg`, but past `deadline` the receive is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn recv_msg_with_deadline<B: IoBufMut>(
+        &self,
+        buff: B,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        submit_context(msg::recv_context(self.as_handle(), buff), Operation::RecvMsg, deadline)
+    }
+
+    /// Send a datagram to `addr` with the control messages of `ancillary`. Sending a reply
+    /// with the `packet_info` of the request sends it from the address the request
+    /// arrived on.
+    /// This issues `WSASendMsg` on Windows and `IORING_OP_SENDMSG` on Linux.
+    fn send_msg<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        buff: B,
+        addr: A,
+        ancillary: &Ancillary,
+    ) -> Result<OperationId> {
+        self.send_msg_with_deadline(buff, addr, ancillary, None)
+    }
+
+    /// Like `send_msg`, but past `deadline` the send is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn send_msg_with_deadline<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        buff: B,
+        addr: A,
+        ancillary: &Ancillary,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let socket_addr = addr.to_socket_addrs()?.next().ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            "no addresses to send data to",
+        ))?;
+
+        let context = msg::send_context(self.as_handle(), buff, &socket_addr, ancillary)?;
+        submit_context(context, Operation::SendMsg, deadline)
+    }
+
+    /// Execute an ovelapped send I/O on this UDP stream.
+    /// This issues `WSASend` on Windows and `IORING_OP_SEND` on Linux.
+    fn send<B: IoBuf>(&self, buff: B) -> Result<OperationId> {
+        self.send_with_deadline(buff, None)
+    }
+
+    /// Like `send`, but past `deadline` the send is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn send_with_deadline<B: IoBuf>(&self, buff: B, deadline: Option<Instant>) -> Result<OperationId> {
+        submit(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Send, deadline)
+    }
+
+    /// Receive one datagram into several buffers, filling them in order.
+    /// This issues `WSARecv` with a `WSABUF` per buffer on Windows and `IORING_OP_READV`
+    /// on Linux, the buffers come back from `Completion::into_bufs`.
+    fn recv_vectored<B: IoBufMut>(&self, bufs: Vec<B>) -> Result<OperationId> {
+        self.recv_vectored_with_deadline(bufs, None)
+    }
+
+    /// Like `recv_vectored`, but past `deadline` the receive is cancelled and completes
+    /// with `ErrorKind::TimedOut`.
+    fn recv_vectored_with_deadline<B: IoBufMut>(
+        &self,
+        bufs: Vec<B>,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let vectored = Vectored::new(bufs.into_iter().map(Buffer::recv).collect());
+        let context = Context::with_vectored(self.as_handle(), vectored, IOType::Read);
+        submit_context(context, Operation::RecvVectored, deadline)
+    }
+
+    /// Send several buffers as one datagram.
+    /// This issues `WSASend` with a `WSABUF` per buffer on Windows and `IORING_OP_WRITEV`
+    /// on Linux.
+    fn send_vectored<B: IoBuf>(&self, bufs: Vec<B>) -> Result<OperationId> {
+        self.send_vectored_with_deadline(bufs, None)
+    }
+
+    /// Like `send_vectored`, but past `deadline` the send is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn send_vectored_with_deadline<B: IoBuf>(
+        &self,
+        bufs: Vec<B>,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let vectored = Vectored::new(bufs.into_iter().map(Buffer::send).collect());
+        let context = Context::with_vectored(self.as_handle(), vectored, IOType::Write);
+        submit_context(context, Operation::SendVectored, deadline)
+    }
+
+    /// Send several buffers as one datagram to `addr`.
+    /// This issues `WSASendTo` with a `WSABUF` per buffer on Windows and
+    /// `IORING_OP_SENDMSG` on Linux.
+    fn send_to_vectored<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        bufs: Vec<B>,
+        addr: A,
+    ) -> Result<OperationId> {
+        self.send_to_vectored_with_deadline(bufs, addr, None)
+    }
+
+    /// Like `send_to_vectored`, but past `deadline` the send is cancelled and completes
+    /// with `ErrorKind::TimedOut`.
+    fn send_to_vectored_with_deadline<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        bufs: Vec<B>,
+        addr: A,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
+        let socket_addr = addr.to_socket_addrs()?.next().ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            "no addresses to send data to",
+        ))?;
+
+        let vectored = Vectored::new(bufs.into_iter().map(Buffer::send).collect());
+        let context = Context::with_vectored(self.as_handle(), vectored, IOType::Write);
+        submit_context(context, Operation::SendToVectored(socket_addr), deadline)
+    }
+
+    /// Like `recv`, as a future that resolves to the completion, see `OpFuture`.
+    fn recv_async<B: IoBufMut>(&self, buff: B) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::recv(buff), IOType::Read, Operation::Recv)
+    }
+
+    /// Like `recv_from`, as a future that resolves to the completion.
+    fn recv_from_async<B: IoBufMut>(&self, buff: B) -> OpFuture {
+        submit_context_awaited(recv_from::context(self.as_handle(), buff), Operation::RecvFrom)
+    }
+
+    /// Like `recv_msg`, as a future that resolves to the completion.
+    fn recv_msg_async<B: IoBufMut>(&self, buff: B) -> OpFuture {
+        submit_context_awaited(msg::recv_context(self.as_handle(), buff), Operation::RecvMsg)
+    }
+
+    /// Like `send_msg`, as a future that resolves to the completion.
+    fn send_msg_async<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        buff: B,
+        addr: A,
+        ancillary: &Ancillary,
+    ) -> OpFuture {
+        let socket_addr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(socket_addr)) => socket_addr,
+            Ok(None) => {
+                return OpFuture::new(Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "no addresses to send data to",
+                )))
+            }
+            Err(e) => return OpFuture::new(Err(e)),
         };
 
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok(context),
-            Err(e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(context),
-            Err(e) => Err(e),
+        match msg::send_context(self.as_handle(), buff, &socket_addr, ancillary) {
+            Ok(context) => submit_context_awaited(context, Operation::SendMsg),
+            Err(e) => OpFuture::new(Err(e)),
         }
     }
 
-    fn send_to<A: ToSocketAddrs>(&self, mut buff: Vec<u8>, addr: A) -> Result<Context> {
-        let wsa_buf = WSABUF {
-            len: len(&buff),
-            buf: buff.as_mut_ptr(),
+    /// Like `send`, as a future that resolves to the completion.
+    fn send_async<B: IoBuf>(&self, buff: B) -> OpFuture {
+        submit_awaited(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::Send)
+    }
+
+    /// Like `send_to`, as a future that resolves to the completion.
+    fn send_to_async<B: IoBuf, A: ToSocketAddrs>(&self, buff: B, addr: A) -> OpFuture {
+        let socket_addr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(socket_addr)) => socket_addr,
+            Ok(None) => {
+                return OpFuture::new(Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "no addresses to send data to",
+                )))
+            }
+            Err(e) => return OpFuture::new(Err(e)),
         };
-        let mut bytes_used = 0;
-        let mut context = Context::new(self.as_handle(), buff, IOType::Write);
+
+        submit_awaited(self.as_handle(), Buffer::send(buff), IOType::Write, Operation::SendTo(socket_addr))
+    }
+
+    /// Execute an ovelapped send I/O to `addr` on this UDP stream.
+    /// This issues `WSASendTo` on Windows and `IORING_OP_SEND` with a destination address on Linux.
+    fn send_to<B: IoBuf, A: ToSocketAddrs>(&self, buff: B, addr: A) -> Result<OperationId> {
+        self.send_to_with_deadline(buff, addr, None)
+    }
+
+    /// Like `send_to`, but past `deadline` the send is cancelled and completes with
+    /// `ErrorKind::TimedOut`.
+    fn send_to_with_deadline<B: IoBuf, A: ToSocketAddrs>(
+        &self,
+        buff: B,
+        addr: A,
+        deadline: Option<Instant>,
+    ) -> Result<OperationId> {
         let socket_addr = addr.to_socket_addrs()?.next().ok_or(Error::new(
             ErrorKind::InvalidInput,
             "no addresses to send data to",
         ))?;
-        let (socket_addr_ptr, ptr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&socket_addr);
-
-        let ret = unsafe {
-            WSASendTo(
-                self.as_raw_socket() as SOCKET,
-                &wsa_buf,
-                1,
-                &mut bytes_used,
-                0,
-                &socket_addr_ptr as *const _ as *const _,
-                ptr_len,
-                context.over_lapped_ptr(),
-                None,
-            )
+
+        submit(
+            self.as_handle(),
+            Buffer::send(buff),
+            IOType::Write,
+            Operation::SendTo(socket_addr),
+            deadline,
+        )
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::os::windows::io::AsRawSocket;
+    use std::time::{Duration, Instant};
+
+    use windows_sys::Win32::Foundation::HANDLE;
+
+    use crate::net::{Ancillary, AncillaryOptions};
+    use crate::{AsHandle, CompletionPort};
+
+    use super::UdpSocketExt;
+
+    impl AsHandle for UdpSocket {
+        fn as_handle(&self) -> HANDLE {
+            self.as_raw_socket() as HANDLE
+        }
+    }
+
+    impl UdpSocketExt for UdpSocket {}
+
+    #[test]
+    fn recv_from() {
+        for local in ["127.0.0.1:0", "[::1]:0"] {
+            let cmp = CompletionPort::new(1).unwrap();
+            let receiver = UdpSocket::bind(local).unwrap();
+            let sender = UdpSocket::bind(local).unwrap();
+            cmp.add(1, &receiver).unwrap();
+
+            UdpSocketExt::recv_from(&receiver, Vec::with_capacity(16)).unwrap();
+            sender.send_to(b"ping", receiver.local_addr().unwrap()).unwrap();
+            let completion = cmp.get(None).unwrap();
+            assert_eq!(completion.data(), b"ping");
+            assert_eq!(completion.source_addr(), Some(sender.local_addr().unwrap()));
+
+            // The port goes out in network byte order.
+            UdpSocketExt::send_to(&receiver, b"pong".to_vec(), sender.local_addr().unwrap())
+                .unwrap();
+            cmp.get(None).unwrap().status().unwrap();
+            let mut buff = [0; 4];
+            assert_eq!(sender.recv(&mut buff).unwrap(), 4);
+
+            // Nothing arrives before the deadline.
+            let deadline = Instant::now() + Duration::from_millis(20);
+            receiver
+                .recv_from_with_deadline(Vec::with_capacity(16), Some(deadline))
+                .unwrap();
+            let completion = cmp.get(None).unwrap();
+            assert!(completion.result().is_timed_out());
+            assert_eq!(completion.source_addr(), None);
+        }
+    }
+
+    #[test]
+    fn recv_msg() {
+        let locals = [
+            ("0.0.0.0:0", IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ("[::]:0", IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ];
+        for (local, loopback) in locals {
+            let cmp = CompletionPort::new(1).unwrap();
+            let receiver = UdpSocket::bind(local).unwrap();
+            let sender = UdpSocket::bind((loopback, 0)).unwrap();
+            cmp.add(1, &receiver).unwrap();
+
+            let timestamp = AncillaryOptions {
+                timestamp: true,
+                ..AncillaryOptions::default()
+            };
+            assert!(receiver.set_recv_ancillary(timestamp).is_err());
+            receiver
+                .set_recv_ancillary(AncillaryOptions {
+                    packet_info: true,
+                    hop_limit: true,
+                    ..AncillaryOptions::default()
+                })
+                .unwrap();
+
+            receiver.recv_msg(Vec::with_capacity(16)).unwrap();
+            let dest = SocketAddr::new(loopback, receiver.local_addr().unwrap().port());
+            sender.send_to(b"ping", dest).unwrap();
+            let completion = cmp.get(None).unwrap();
+            assert_eq!(completion.data(), b"ping");
+            assert_eq!(completion.source_addr(), Some(sender.local_addr().unwrap()));
+            let ancillary = *completion.ancillary().unwrap();
+            let packet_info = ancillary.packet_info.unwrap();
+            assert_eq!(packet_info.addr, loopback);
+            assert!(ancillary.hop_limit.is_some());
+
+            // Reply from the address the request arrived on.
+            let reply = Ancillary {
+                packet_info: Some(packet_info),
+                ..Ancillary::default()
+            };
+            receiver
+                .send_msg(b"pong".to_vec(), completion.source_addr().unwrap(), &reply)
+                .unwrap();
+            cmp.get(None).unwrap().status().unwrap();
+            let mut buff = [0; 4];
+            let (_, from) = sender.recv_from(&mut buff).unwrap();
+            assert_eq!(from, dest);
+
+            // Hop limits do not go out per datagram on Windows.
+            let hop_limit = Ancillary {
+                hop_limit: Some(1),
+                ..Ancillary::default()
+            };
+            assert!(receiver.send_msg(b"pong".to_vec(), dest, &hop_limit).is_err());
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use crate::net::{Ancillary, AncillaryOptions, Ecn};
+    use crate::{AsHandle, CompletionPort, RawHandle};
+
+    use super::UdpSocketExt;
+
+    impl AsHandle for UdpSocket {
+        fn as_handle(&self) -> RawHandle {
+            self.as_raw_fd()
+        }
+    }
+
+    impl UdpSocketExt for UdpSocket {}
+
+    #[test]
+    fn udp_send_to_recv() {
+        let cmp = CompletionPort::new(1).unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        cmp.add(1, &receiver).unwrap();
+        cmp.add(2, &sender).unwrap();
+
+        let recv_id = UdpSocketExt::recv(&receiver, vec![0; 16]).unwrap();
+        UdpSocketExt::send_to(&sender, b"ping".to_vec(), receiver.local_addr().unwrap())
+            .unwrap();
+
+        let mut received = None;
+        while received.is_none() {
+            for completion in cmp.get_many(2, None).unwrap() {
+                if completion.id() == Some(recv_id) {
+                    received = completion.into_context();
+                }
+            }
+        }
+
+        assert_eq!(&received.unwrap().get_buff()[..4], b"ping");
+    }
+
+    fn vectored(cmp: CompletionPort) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        cmp.add(1, &receiver).unwrap();
+        cmp.add(2, &sender).unwrap();
+
+        let recv_id = receiver
+            .recv_vectored(vec![Vec::with_capacity(3), Vec::with_capacity(16)])
+            .unwrap();
+        let header = b"hdr".to_vec();
+        let payload = b"payload".to_vec();
+        sender
+            .send_to_vectored(vec![header, payload], receiver.local_addr().unwrap())
+            .unwrap();
+
+        let mut received = None;
+        while received.is_none() {
+            for completion in cmp.get_many(2, None).unwrap() {
+                match completion.id() == Some(recv_id) {
+                    true => received = Some(completion),
+                    false => assert_eq!(completion.status().unwrap(), 10),
+                }
+            }
+        }
+
+        let received = received.unwrap();
+        assert_eq!(received.bytes_used(), 10);
+        let bufs = received.into_bufs::<Vec<u8>>().ok().unwrap();
+        assert_eq!(bufs, [b"hdr".to_vec(), b"payload".to_vec()]);
+    }
+
+    #[test]
+    fn vectored_uring() {
+        vectored(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn vectored_epoll() {
+        vectored(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    fn recv_from(cmp: CompletionPort, local: &str) {
+        let receiver = UdpSocket::bind(local).unwrap();
+        let sender = UdpSocket::bind(local).unwrap();
+        cmp.add(1, &receiver).unwrap();
+
+        let id = UdpSocketExt::recv_from(&receiver, Vec::with_capacity(16)).unwrap();
+        sender.send_to(b"ping", receiver.local_addr().unwrap()).unwrap();
+
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.data(), b"ping");
+        assert_eq!(completion.source_addr(), Some(sender.local_addr().unwrap()));
+
+        // Reply to where it came from.
+        UdpSocketExt::send_to(&receiver, b"pong".to_vec(), completion.source_addr().unwrap())
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().source_addr(), None);
+        let mut buff = [0; 4];
+        let (_, from) = sender.recv_from(&mut buff).unwrap();
+        assert_eq!(&buff, b"pong");
+        assert_eq!(from, receiver.local_addr().unwrap());
+
+        // Nothing arrives before the deadline.
+        let deadline = Instant::now() + Duration::from_millis(20);
+        receiver
+            .recv_from_with_deadline(Vec::with_capacity(16), Some(deadline))
+            .unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert!(completion.result().is_timed_out());
+        assert_eq!(completion.source_addr(), None);
+    }
+
+    #[test]
+    fn recv_from_uring() {
+        recv_from(CompletionPort::new(1).unwrap(), "127.0.0.1:0");
+        recv_from(CompletionPort::new(1).unwrap(), "[::1]:0");
+    }
+
+    #[test]
+    fn recv_from_epoll() {
+        recv_from(CompletionPort::with_epoll(1).unwrap(), "127.0.0.1:0");
+        recv_from(CompletionPort::with_epoll(1).unwrap(), "[::1]:0");
+    }
+
+    fn recv_msg(cmp: CompletionPort, local: &str, loopback: IpAddr) {
+        let receiver = UdpSocket::bind(local).unwrap();
+        let sender = UdpSocket::bind((loopback, 0)).unwrap();
+        cmp.add(1, &receiver).unwrap();
+        cmp.add(2, &sender).unwrap();
+        receiver
+            .set_recv_ancillary(AncillaryOptions {
+                packet_info: true,
+                traffic_class: true,
+                hop_limit: true,
+                timestamp: true,
+            })
+            .unwrap();
+
+        let id = receiver.recv_msg(Vec::with_capacity(16)).unwrap();
+        let dest = SocketAddr::new(loopback, receiver.local_addr().unwrap().port());
+        let sent = Ancillary {
+            traffic_class: Some(0x20),
+            ecn: Some(Ecn::Ect0),
+            hop_limit: Some(42),
+            ..Ancillary::default()
         };
+        sender.send_msg(b"ping".to_vec(), dest, &sent).unwrap();
 
-        match cvt_for_socket(ret) {
-            Ok(_) => Ok(context),
-            Err(e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(context),
-            Err(e) => Err(e),
+        let mut received = None;
+        while received.is_none() {
+            for completion in cmp.get_many(2, None).unwrap() {
+                match completion.id() == Some(id) {
+                    true => received = Some(completion),
+                    false => assert_eq!(completion.status().unwrap(), 4),
+                }
+            }
         }
+
+        let received = received.unwrap();
+        assert_eq!(received.data(), b"ping");
+        assert_eq!(received.source_addr(), Some(sender.local_addr().unwrap()));
+        let ancillary = *received.ancillary().unwrap();
+        let packet_info = ancillary.packet_info.unwrap();
+        assert_eq!(packet_info.addr, loopback);
+        assert_ne!(packet_info.interface, 0);
+        assert_eq!(ancillary.traffic_class, Some(0x22));
+        assert_eq!(ancillary.ecn, Some(Ecn::Ect0));
+        assert_eq!(ancillary.hop_limit, Some(42));
+        assert!(ancillary.timestamp.is_some());
+
+        // The socket is bound to the unspecified address, reply from the one the request
+        // arrived on.
+        let reply = Ancillary {
+            packet_info: Some(packet_info),
+            ..Ancillary::default()
+        };
+        // Sent well before the deadline, which does not get in the way.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let source = received.source_addr().unwrap();
+        receiver
+            .send_msg_with_deadline(b"pong".to_vec(), source, &reply, Some(deadline))
+            .unwrap();
+        assert_eq!(cmp.get(None).unwrap().status().unwrap(), 4);
+        let mut buff = [0; 4];
+        let (_, from) = sender.recv_from(&mut buff).unwrap();
+        assert_eq!(&buff, b"pong");
+        assert_eq!(from, dest);
     }
 
+    #[test]
+    fn recv_msg_uring() {
+        let cmp = || CompletionPort::new(1).unwrap();
+        recv_msg(cmp(), "0.0.0.0:0", IpAddr::V4(Ipv4Addr::LOCALHOST));
+        recv_msg(cmp(), "[::]:0", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn recv_msg_epoll() {
+        let cmp = || CompletionPort::with_epoll(1).unwrap();
+        recv_msg(cmp(), "0.0.0.0:0", IpAddr::V4(Ipv4Addr::LOCALHOST));
+        recv_msg(cmp(), "[::]:0", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
 }
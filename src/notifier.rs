@@ -0,0 +1,163 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    marker::PhantomData,
+    sync::Weak,
+};
+
+use crate::completion_port::Inner;
+
+/// Sends values of type `T` through a CompletionPort, made by `CompletionPort::notifier`.
+///
+/// Each value comes back from `get`/`get_many` as a completion for the notifier's token,
+/// which makes it a way to wake threads waiting on the port and hand them commands.
+/// It does not keep the CompletionPort alive.
+///
+/// ```
+/// use std::thread::spawn;
+/// use iocp_rs::{sim::SimPort, CompletionPort};
+///
+/// enum Command {
+///     Reload,
+///     Shutdown,
+/// }
+///
+/// let cmp = CompletionPort::with_driver(SimPort::new(0));
+/// let notifier = cmp.notifier::<Command>(0);
+///
+/// spawn(move || {
+///     notifier.notify(Command::Reload).unwrap();
+///     notifier.notify(Command::Shutdown).unwrap();
+/// });
+///
+/// loop {
+///     let completion = cmp.get(None).unwrap();
+///     match completion.into_message::<Command>() {
+///         Ok(Command::Reload) => {}
+///         Ok(Command::Shutdown) => break,
+///         Err(_completion) => {}
+///     }
+/// }
+/// ```
+pub struct Notifier<T> {
+    port: Weak<Inner>,
+    token: usize,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T: Send + 'static> Notifier<T> {
+    pub(crate) fn new(port: Weak<Inner>, token: usize) -> Self {
+        Self {
+            port,
+            token,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    /// Send `message`, failing with `NotConnected` once the CompletionPort is dropped.
+    pub fn notify(&self, message: T) -> Result<()> {
+        match self.port.upgrade() {
+            Some(port) => port.post_message(self.token, Box::new(message)),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "the CompletionPort was dropped",
+            )),
+        }
+    }
+}
+
+impl<T> Clone for Notifier<T> {
+    fn clone(&self) -> Self {
+        Self {
+            port: self.port.clone(),
+            token: self.token,
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc, thread::spawn};
+
+    use crate::{sim::SimPort, CompletionPort, OperationalResult};
+
+    #[derive(Debug, PartialEq)]
+    enum Command {
+        Reload(String),
+        Shutdown,
+    }
+
+    fn wake_waiter(cmp: CompletionPort) {
+        let cmp = Arc::new(cmp);
+        let notifier = cmp.notifier::<Command>(4);
+
+        let waiter = cmp.clone();
+        let join = spawn(move || {
+            let mut commands = Vec::new();
+            while commands.len() < 2 {
+                let completion = waiter.get(None).unwrap();
+                assert_eq!(completion.token(), 4);
+                assert!(completion.is_message());
+                assert!(completion.context().is_none());
+                commands.push(completion.into_message::<Command>().ok().unwrap());
+            }
+            commands
+        });
+
+        notifier.notify(Command::Reload("a.toml".into())).unwrap();
+        notifier.clone().notify(Command::Shutdown).unwrap();
+
+        assert_eq!(
+            join.join().unwrap(),
+            [Command::Reload("a.toml".into()), Command::Shutdown]
+        );
+    }
+
+    #[test]
+    fn wake_sim() {
+        wake_waiter(CompletionPort::with_driver(SimPort::new(0)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wake_uring() {
+        wake_waiter(CompletionPort::new(1).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wake_epoll() {
+        wake_waiter(CompletionPort::with_epoll(1).unwrap());
+    }
+
+    #[test]
+    fn distinct_from_posted_results() {
+        let cmp = CompletionPort::with_driver(SimPort::new(0));
+        cmp.post(OperationalResult::new(1, 0, 0, 0)).unwrap();
+        cmp.post_message(2, 42u32).unwrap();
+
+        let posted = cmp.get(None).unwrap();
+        assert!(!posted.is_message());
+        assert!(posted.into_message::<u32>().is_err());
+
+        let message = cmp.get(None).unwrap();
+        assert_eq!(message.message::<u32>(), Some(&42));
+        let message = message.into_message::<String>().err().unwrap();
+        assert_eq!(message.into_message::<u32>().ok(), Some(42));
+    }
+
+    #[test]
+    fn port_dropped() {
+        let cmp = CompletionPort::with_driver(SimPort::new(0));
+        let notifier = cmp.notifier::<()>(0);
+        notifier.notify(()).unwrap();
+        drop(cmp);
+
+        let err = notifier.notify(()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+}
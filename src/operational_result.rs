@@ -1,34 +1,145 @@
+use std::io::{Error, Result};
 
+use crate::context::OperationId;
 
-use windows_sys::Win32::System::IO::{OVERLAPPED, OVERLAPPED_ENTRY};
+/// The raw OS error of an operation that was cancelled before it completed.
+#[cfg(target_os = "linux")]
+const CANCELLED: i32 = libc::ECANCELED;
+#[cfg(windows)]
+const CANCELLED: i32 = windows_sys::Win32::Foundation::ERROR_OPERATION_ABORTED as i32;
+
+/// The raw OS error of an operation cancelled because its deadline passed.
+#[cfg(target_os = "linux")]
+const TIMED_OUT: i32 = libc::ETIMEDOUT;
+#[cfg(windows)]
+const TIMED_OUT: i32 = windows_sys::Win32::Foundation::ERROR_TIMEOUT as i32;
+
+/// The raw OS error a result set from an error without one carries as its status.
+#[cfg(target_os = "linux")]
+const INVALID: i32 = libc::EINVAL;
+#[cfg(windows)]
+const INVALID: i32 = windows_sys::Win32::Networking::WinSock::WSAEINVAL;
 
 pub struct OperationalResult {
-    entry: OVERLAPPED_ENTRY,
+    token: usize,
+    offset: u64,
+    bytes_used: u32,
+    /// The raw OS error the operation failed with, 0 on success.
+    status: i32,
+    /// The operation this completes, `None` for posted results.
+    id: Option<OperationId>,
+    /// The error the result was set from when it has no raw OS error, which `status`
+    /// cannot hold.
+    error: Option<Error>,
 }
 
 impl OperationalResult {
-    pub fn new(entry: OVERLAPPED_ENTRY) -> Self {
-        Self { entry }
+    /// `status` is the raw OS error the operation failed with, 0 if it succeeded.
+    pub fn new(token: usize, offset: u64, bytes_used: u32, status: i32) -> Self {
+        Self {
+            token,
+            offset,
+            bytes_used,
+            status,
+            id: None,
+            error: None,
+        }
+    }
+
+    /// Mark this result as the completion of the operation `id`.
+    /// Drivers report every submitted operation this way, so the CompletionPort can hand
+    /// its Context back.
+    pub fn with_id(mut self, id: OperationId) -> Self {
+        self.id = Some(id);
+        self
     }
 
     pub fn token(&self) -> usize {
-        self.entry.lpCompletionKey
+        self.token
     }
 
     pub fn offset(&self) -> u64 {
-        let over_lapped = unsafe { &(*self.entry.lpOverlapped) };
+        self.offset
+    }
 
-        let low_offset = unsafe { over_lapped.Anonymous.Anonymous.Offset as u64 };
-        let high_offset = unsafe { over_lapped.Anonymous.Anonymous.OffsetHigh as u64 };
+    pub fn bytes_used(&self) -> u32 {
+        self.bytes_used
+    }
 
-        (high_offset << 32) | low_offset
+    pub fn id(&self) -> Option<OperationId> {
+        self.id
     }
 
-    pub fn bytes_used(&self) -> u32 {
-        self.entry.dwNumberOfBytesTransferred
+    /// The raw OS error the operation failed with, 0 if it succeeded.
+    pub(crate) fn status(&self) -> i32 {
+        self.status
+    }
+
+    /// Report a cancelled operation as timed out.
+    pub(crate) fn set_timed_out(&mut self) {
+        self.status = TIMED_OUT;
+        self.error = None;
     }
 
-    pub(crate) fn over_lapped_ptr(&self) -> *mut OVERLAPPED {
-        self.entry.lpOverlapped
+    /// Replace what the operation reported, once its completion was decoded.
+    pub(crate) fn set_result(&mut self, result: Result<u32>) {
+        match result {
+            Ok(bytes_used) => {
+                self.bytes_used = bytes_used;
+                self.status = 0;
+                self.error = None;
+            }
+            Err(e) => {
+                self.bytes_used = 0;
+                self.status = e.raw_os_error().unwrap_or(INVALID);
+                self.error = e.raw_os_error().is_none().then_some(e);
+            }
+        }
+    }
+
+    pub(crate) fn take_id(&mut self) -> Option<OperationId> {
+        self.id.take()
+    }
+
+    /// How this operation ended: the bytes it transferred, or the error it failed with.
+    /// `Ok(0)` on a read is end of file, a cancelled operation is an error, see `is_cancelled`.
+    pub fn result(&self) -> Result<u32> {
+        match &self.error {
+            _ if self.status == 0 => Ok(self.bytes_used),
+            Some(e) => Err(Error::new(e.kind(), e.to_string())),
+            None => Err(Error::from_raw_os_error(self.status)),
+        }
+    }
+
+    /// Whether the operation was cancelled, through `CompletionPort::cancel` or otherwise.
+    pub fn is_cancelled(&self) -> bool {
+        self.status == CANCELLED
+    }
+
+    /// Whether the operation was cancelled because its deadline passed, its error is
+    /// `ErrorKind::TimedOut`.
+    pub fn is_timed_out(&self) -> bool {
+        self.status == TIMED_OUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::OperationalResult;
+
+    #[test]
+    fn keeps_error_without_os_code() {
+        let mut result = OperationalResult::new(1, 0, 4, 0);
+        result.set_result(Err(Error::new(ErrorKind::UnexpectedEof, "short header")));
+
+        let e = result.result().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(e.to_string(), "short header");
+        assert!(!result.is_cancelled());
+
+        result.set_result(Err(Error::from_raw_os_error(5)));
+        assert_eq!(result.result().unwrap_err().raw_os_error(), Some(5));
     }
 }
@@ -0,0 +1,295 @@
+use std::{
+    future::Future,
+    io::{Error, Result},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{completion_port::Inner, Completion, CompletionPort, OperationId};
+
+/// The future of an operation issued by one of the `*_async` methods of the `*Ext` traits.
+///
+/// The operation is issued when the method is called, the future resolves to its
+/// completion once a thread waiting on the CompletionPort dequeues it, which wakes the
+/// task. `Err` means the operation could not be issued, a failed operation resolves to
+/// a completion whose `status` is the error.
+///
+/// Dropping the future before it resolves cancels the operation, its buffer stays with
+/// the CompletionPort until the kernel is done with it, see `CompletionPort::abandon`.
+#[must_use = "the operation is cancelled when its future is dropped"]
+pub struct OpFuture {
+    state: State,
+}
+
+enum State {
+    Submitted { inner: Arc<Inner>, id: OperationId },
+    Failed(Error),
+    Done,
+}
+
+impl OpFuture {
+    pub(crate) fn new(submitted: Result<(Arc<Inner>, OperationId)>) -> Self {
+        let state = match submitted {
+            Ok((inner, id)) => State::Submitted { inner, id },
+            Err(e) => State::Failed(e),
+        };
+
+        Self { state }
+    }
+
+    /// The operation, `None` if it could not be issued or already resolved.
+    pub fn id(&self) -> Option<OperationId> {
+        match &self.state {
+            State::Submitted { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl Future for OpFuture {
+    type Output = Result<Completion>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let completion = match &self.state {
+            State::Submitted { inner, id } => match inner.poll_awaited(*id, cx.waker()) {
+                Poll::Ready(completion) => completion,
+                Poll::Pending => return Poll::Pending,
+            },
+            State::Failed(_) => match std::mem::replace(&mut self.state, State::Done) {
+                State::Failed(e) => return Poll::Ready(Err(e)),
+                _ => unreachable!(),
+            },
+            State::Done => panic!("operation future polled after completion"),
+        };
+
+        self.state = State::Done;
+        Poll::Ready(Ok(completion))
+    }
+}
+
+impl Drop for OpFuture {
+    fn drop(&mut self) {
+        if let State::Submitted { inner, id } = &self.state {
+            inner.forget_awaited(*id);
+        }
+    }
+}
+
+/// Drives the futures of a CompletionPort's `*_async` operations.
+///
+/// Any thread waiting in `get` or `get_many` hands the completions of awaited operations
+/// to their futures and wakes them, those never come out of `get`. A reactor waits the
+/// same way but also returns when it only woke futures, so the thread running them gets
+/// to poll them again.
+///
+/// ```
+/// use iocp_rs::{fs::FileExt, sim::SimPort, CompletionPort, Reactor};
+/// use std::{future::Future, pin::pin, sync::Arc, task::{Context, Poll, Waker}};
+///
+/// let sim = SimPort::new(0);
+/// let reactor = Reactor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+/// let mut file = sim.file(b"hello".to_vec());
+/// reactor.port().add(1, &file).unwrap();
+///
+/// let mut read = pin!(file.read_at_async(vec![0; 16], 0));
+/// let mut cx = Context::from_waker(Waker::noop());
+/// let completion = loop {
+///     match read.as_mut().poll(&mut cx) {
+///         Poll::Ready(completion) => break completion.unwrap(),
+///         Poll::Pending => {
+///             reactor.turn(None).unwrap();
+///         }
+///     }
+/// };
+/// assert_eq!(completion.data(), b"hello");
+/// ```
+pub struct Reactor {
+    port: Arc<CompletionPort>,
+}
+
+impl Reactor {
+    pub fn new(port: Arc<CompletionPort>) -> Self {
+        Self { port }
+    }
+
+    pub fn port(&self) -> &Arc<CompletionPort> {
+        &self.port
+    }
+
+    /// Wait for completions like `CompletionPort::get_many` with room for 64, wake the
+    /// futures whose operations completed and return the other completions.
+    /// The list is empty when only futures were woken.
+    pub fn turn(&self, timeout: Option<Duration>) -> Result<Vec<Completion>> {
+        self.port.inner().wait(64, timeout, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+        time::Duration,
+    };
+
+    use crate::{fs::FileExt, net::TcpStreamExt, sim::SimPort, Completion, CompletionPort};
+
+    use super::Reactor;
+
+    /// Counts its wakes.
+    #[derive(Default)]
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn block_on<F: Future>(reactor: &Reactor, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Count::default()));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            reactor.turn(Some(Duration::from_secs(5))).unwrap();
+        }
+    }
+
+    #[test]
+    fn await_read_and_write() {
+        let sim = SimPort::new(1);
+        let reactor = Reactor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let (mut a, b) = sim.socket_pair();
+        reactor.port().add(1, &a).unwrap();
+        reactor.port().add(2, &b).unwrap();
+
+        let completion = block_on(&reactor, async {
+            let read = a.read_async(vec![0; 8]);
+            let written = b.write_async(b"ping".to_vec()).await.unwrap();
+            assert_eq!(written.status().unwrap(), 4);
+            read.await.unwrap()
+        });
+        assert_eq!(completion.data(), b"ping");
+
+        // Submission errors come out of the future.
+        let mut unregistered = sim.file(Vec::new());
+        let ret = block_on(&reactor, unregistered.read_at_async(vec![0; 8], 0));
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn wakes_the_task() {
+        let sim = SimPort::new(2);
+        let cmp = Arc::new(CompletionPort::with_driver(sim.clone()));
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        let count = Arc::new(Count::default());
+        let waker = Waker::from(count.clone());
+        let mut read = pin!(a.read_async(vec![0; 8]));
+        assert!(read
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+
+        // An ordinary `get` on another object hands the read to its future on the way.
+        TcpStreamExt::write(&b, b"pong".to_vec()).unwrap();
+        let mut others = Vec::<Completion>::new();
+        while count.0.load(Ordering::SeqCst) == 0 {
+            others.extend(
+                cmp.get_many(4, Some(Duration::from_millis(100)))
+                    .ok()
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+        assert!(others.iter().all(|completion| completion.token() == 2));
+
+        match read.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(completion) => assert_eq!(completion.unwrap().data(), b"pong"),
+            Poll::Pending => panic!("woken but not ready"),
+        }
+    }
+
+    #[test]
+    fn drop_cancels() {
+        let sim = SimPort::new(3);
+        let reactor = Reactor::new(Arc::new(CompletionPort::with_driver(sim.clone())));
+        let (mut a, b) = sim.socket_pair();
+        reactor.port().add(1, &a).unwrap();
+        reactor.port().add(2, &b).unwrap();
+
+        let read = a.read_async(vec![0; 8]);
+        let id = read.id().unwrap();
+        drop(read);
+
+        // The abandoned read never shows up, the next one gets the data.
+        let completion = block_on(&reactor, async {
+            let read = a.read_async(vec![0; 8]);
+            b.write_async(b"late".to_vec()).await.unwrap();
+            read.await.unwrap()
+        });
+        assert_ne!(completion.id(), Some(id));
+        assert_eq!(completion.data(), b"late");
+        assert!(reactor.turn(Some(Duration::from_millis(50))).is_err());
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod linux_tests {
+    use std::{
+        future::Future,
+        io::Write,
+        net::{TcpListener, TcpStream},
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{net::TcpStreamExt, CompletionPort};
+
+    use super::Reactor;
+
+    fn await_recv(cmp: CompletionPort) {
+        let reactor = Reactor::new(Arc::new(cmp));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        reactor.port().add(1, &stream).unwrap();
+
+        let mut read = pin!(stream.read_async(vec![0; 16]));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+
+        peer.write_all(b"hello").unwrap();
+        let completion = loop {
+            if let Poll::Ready(completion) = read.as_mut().poll(&mut cx) {
+                break completion.unwrap();
+            }
+            assert!(reactor.turn(None).unwrap().is_empty());
+        };
+        assert_eq!(completion.data(), b"hello");
+    }
+
+    #[test]
+    fn await_recv_uring() {
+        await_recv(CompletionPort::new(1).unwrap());
+    }
+
+    #[test]
+    fn await_recv_epoll() {
+        await_recv(CompletionPort::with_epoll(1).unwrap());
+    }
+}
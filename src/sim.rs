@@ -0,0 +1,656 @@
+//! A deterministic, in-memory [`Driver`] for reproducing completion orderings in tests.
+//!
+//! A [`SimPort`] owns in-memory files and loopback socket pairs. Operations on them are
+//! queued on submission and performed while the port is waited on, one at a time in an
+//! order picked by a scheduler seeded at construction. The same seed and the same
+//! submissions always produce the same completions, so an ordering that breaks protocol
+//! code can be replayed until it is fixed.
+//!
+//! ```
+//! use iocp_rs::{fs::FileExt, sim::SimPort, CompletionPort};
+//!
+//! let sim = SimPort::new(7);
+//! sim.set_reordering(true);
+//! sim.set_partial_transfers(true);
+//!
+//! let cmp = CompletionPort::with_driver(sim.clone());
+//! let mut file = sim.file(b"hello world".to_vec());
+//! cmp.add(1, &file).unwrap();
+//!
+//! file.read_at(vec![0; 5], 6).unwrap();
+//! let completion = cmp.get(None).unwrap();
+//! assert!(b"world".starts_with(completion.data()));
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, Result},
+    ptr::copy_nonoverlapping,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    context::OperationId,
+    driver::{Driver, Operation},
+    fs::FileExt,
+    net::{AsRawSocket, TcpStreamExt},
+    AsHandle, Context, OperationalResult, RawHandle,
+};
+
+#[cfg(target_os = "linux")]
+mod code {
+    pub const TIMED_OUT: i32 = libc::ETIME;
+    pub const CANCELED: i32 = libc::ECANCELED;
+    pub const BAD_HANDLE: i32 = libc::EBADF;
+    pub const PEER_CLOSED: i32 = libc::EPIPE;
+}
+
+#[cfg(windows)]
+mod code {
+    use windows_sys::Win32::{
+        Foundation::{ERROR_INVALID_HANDLE, ERROR_OPERATION_ABORTED, WAIT_TIMEOUT},
+        Networking::WinSock::WSAECONNRESET,
+    };
+
+    pub const TIMED_OUT: i32 = WAIT_TIMEOUT as i32;
+    pub const CANCELED: i32 = ERROR_OPERATION_ABORTED as i32;
+    pub const BAD_HANDLE: i32 = ERROR_INVALID_HANDLE as i32;
+    pub const PEER_CLOSED: i32 = WSAECONNRESET;
+}
+
+/// Simulated handles are drawn from one range for the whole process, far above the
+/// descriptors and handles the OS gives out, so they never collide in the registry.
+fn next_handle() -> RawHandle {
+    static NEXT: AtomicUsize = AtomicUsize::new(0x4000_0000);
+    NEXT.fetch_add(1, Ordering::Relaxed) as RawHandle
+}
+
+/// SplitMix64, small and good enough to drive a scheduler.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`, `bound` must not be 0.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next() >> 11) as f64 / (1u64 << 53) as f64 <= probability
+    }
+}
+
+enum Object {
+    File(Vec<u8>),
+    Socket {
+        peer: RawHandle,
+        /// Bytes sent by the peer and not received yet.
+        inbox: VecDeque<u8>,
+    },
+}
+
+enum Pending {
+    Op {
+        id: OperationId,
+        token: usize,
+        handle: RawHandle,
+        /// Point into the buffers owned by the operation's Context, in order.
+        slices: Vec<(*mut u8, usize)>,
+        op: Operation,
+    },
+    Posted(OperationalResult),
+}
+
+unsafe impl Send for Pending {}
+
+struct State {
+    rng: Rng,
+    reordering: bool,
+    partial_transfers: bool,
+    error_rate: f64,
+    error_code: i32,
+    objects: HashMap<RawHandle, Object>,
+    injected: HashMap<RawHandle, VecDeque<i32>>,
+    /// Everything not completed yet, in submission order.
+    pending: Vec<Pending>,
+}
+
+impl State {
+    /// Whether performing `pending` now would complete it, only receives can be blocked.
+    fn runnable(&self, pending: &Pending) -> bool {
+        match pending {
+            Pending::Op {
+                handle,
+                op: Operation::Recv | Operation::RecvVectored,
+                ..
+            } => match self.objects.get(handle) {
+                Some(Object::Socket { peer, inbox }) => {
+                    !inbox.is_empty() || !self.objects.contains_key(peer)
+                }
+                _ => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// Pick one runnable entry and complete it.
+    fn step(&mut self) -> Option<OperationalResult> {
+        let runnable = (0..self.pending.len())
+            .filter(|&i| self.runnable(&self.pending[i]))
+            .collect::<Vec<_>>();
+
+        if runnable.is_empty() {
+            return None;
+        }
+
+        let index = if self.reordering {
+            runnable[self.rng.below(runnable.len())]
+        } else {
+            runnable[0]
+        };
+
+        Some(match self.pending.remove(index) {
+            Pending::Posted(result) => result,
+            Pending::Op {
+                id,
+                token,
+                handle,
+                slices,
+                op,
+            } => {
+                let ret = self.perform(handle, &slices, &op);
+                let status = if ret < 0 { -ret } else { 0 };
+                OperationalResult::new(token, op.offset(), ret.max(0) as u32, status).with_id(id)
+            }
+        })
+    }
+
+    /// How many bytes of `len` to transfer this time.
+    fn transfer_len(&mut self, len: usize) -> usize {
+        if self.partial_transfers && len > 1 {
+            1 + self.rng.below(len)
+        } else {
+            len
+        }
+    }
+
+    /// Perform an operation, returning a byte count or a negated error code.
+    fn perform(&mut self, handle: RawHandle, slices: &[(*mut u8, usize)], op: &Operation) -> i32 {
+        if let Some(code) = self.injected.get_mut(&handle).and_then(VecDeque::pop_front) {
+            return -code;
+        }
+
+        if self.rng.chance(self.error_rate) {
+            return -self.error_code;
+        }
+
+        let total = slices.iter().map(|&(_, len)| len).sum();
+        let want = self.transfer_len(total);
+
+        match (op, self.objects.get_mut(&handle)) {
+            (Operation::Read { offset }, Some(Object::File(data))) => {
+                let start = (*offset as usize).min(data.len());
+                let count = want.min(data.len() - start);
+                scatter(slices, &data[start..start + count]);
+                count as i32
+            }
+            (Operation::Write { offset }, Some(Object::File(data))) => {
+                let start = *offset as usize;
+                if data.len() < start + want {
+                    data.resize(start + want, 0);
+                }
+                data[start..start + want].copy_from_slice(&gather(slices, want));
+                want as i32
+            }
+            (
+                Operation::Recv | Operation::RecvVectored,
+                Some(Object::Socket { inbox, .. }),
+            ) => {
+                let count = want.min(inbox.len());
+                scatter(slices, &inbox.drain(..count).collect::<Vec<_>>());
+                count as i32
+            }
+            (
+                Operation::Send
+                | Operation::SendTo(_)
+                | Operation::SendVectored
+                | Operation::SendToVectored(_),
+                Some(&mut Object::Socket { peer, .. }),
+            ) => match self.objects.get_mut(&peer) {
+                Some(Object::Socket { inbox, .. }) => {
+                    inbox.extend(gather(slices, want));
+                    want as i32
+                }
+                _ => -code::PEER_CLOSED,
+            },
+            _ => -code::BAD_HANDLE,
+        }
+    }
+}
+
+/// Copy `bytes` into the buffers, in order.
+fn scatter(slices: &[(*mut u8, usize)], mut bytes: &[u8]) {
+    for &(ptr, len) in slices {
+        let n = len.min(bytes.len());
+        unsafe { copy_nonoverlapping(bytes.as_ptr(), ptr, n) };
+        bytes = &bytes[n..];
+    }
+}
+
+/// The first `want` bytes of the buffers.
+fn gather(slices: &[(*mut u8, usize)], want: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(want);
+    for &(ptr, len) in slices {
+        let n = len.min(want - bytes.len());
+        bytes.extend_from_slice(unsafe { std::slice::from_raw_parts(ptr, n) });
+    }
+    bytes
+}
+
+struct Inner {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+/// An in-memory driver whose operations complete in a seeded, reproducible order.
+///
+/// Clones share the same files, sockets and queue, hand one to
+/// [`CompletionPort::with_driver`](crate::CompletionPort::with_driver) and keep another
+/// to create objects and steer the scheduler.
+#[derive(Clone)]
+pub struct SimPort {
+    inner: Arc<Inner>,
+}
+
+impl SimPort {
+    /// Create a port whose scheduler is seeded with `seed`.
+    /// Completions are in submission order until reordering is enabled.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    rng: Rng(seed),
+                    reordering: false,
+                    partial_transfers: false,
+                    error_rate: 0.0,
+                    error_code: 0,
+                    objects: HashMap::new(),
+                    injected: HashMap::new(),
+                    pending: Vec::new(),
+                }),
+                ready: Condvar::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    /// Complete runnable operations in a random order instead of submission order.
+    pub fn set_reordering(&self, reordering: bool) {
+        self.state().reordering = reordering;
+    }
+
+    /// Let operations transfer a random, non-zero part of their buffer.
+    pub fn set_partial_transfers(&self, partial_transfers: bool) {
+        self.state().partial_transfers = partial_transfers;
+    }
+
+    /// Fail each operation with the raw OS error `code` with the given probability.
+    pub fn set_error_rate(&self, probability: f64, code: i32) {
+        let mut state = self.state();
+        state.error_rate = probability;
+        state.error_code = code;
+    }
+
+    /// Fail the next operation performed on `io_object` with the raw OS error `code`.
+    /// Injected errors queue up and are used before any random error.
+    pub fn inject_error<A: AsHandle>(&self, io_object: &A, code: i32) {
+        self.state()
+            .injected
+            .entry(io_object.as_handle())
+            .or_default()
+            .push_back(code);
+    }
+
+    /// Create an in-memory file holding `contents`.
+    pub fn file(&self, contents: Vec<u8>) -> SimFile {
+        let handle = next_handle();
+        self.state().objects.insert(handle, Object::File(contents));
+
+        SimFile {
+            handle,
+            port: self.clone(),
+        }
+    }
+
+    /// Create a pair of connected loopback sockets, what one sends the other receives.
+    pub fn socket_pair(&self) -> (SimSocket, SimSocket) {
+        let (a, b) = (next_handle(), next_handle());
+        let mut state = self.state();
+        state.objects.insert(
+            a,
+            Object::Socket {
+                peer: b,
+                inbox: VecDeque::new(),
+            },
+        );
+        state.objects.insert(
+            b,
+            Object::Socket {
+                peer: a,
+                inbox: VecDeque::new(),
+            },
+        );
+
+        (
+            SimSocket {
+                handle: a,
+                port: self.clone(),
+            },
+            SimSocket {
+                handle: b,
+                port: self.clone(),
+            },
+        )
+    }
+
+    fn remove(&self, handle: RawHandle) {
+        let mut state = self.state();
+        state.objects.remove(&handle);
+        state.injected.remove(&handle);
+        // Receives on the peer can now complete with 0 bytes.
+        self.inner.ready.notify_all();
+    }
+}
+
+impl Driver for SimPort {
+    fn register(&self, _handle: RawHandle, _token: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()> {
+        let mut state = self.state();
+        state.pending.push(Pending::Op {
+            id: context.id(),
+            token,
+            handle: context.handle(),
+            slices: match &mut context.vectored {
+                Some(vectored) => vectored.iter_mut().collect(),
+                None => vec![(context.buff.as_mut_ptr(), context.buff.len() as usize)],
+            },
+            op,
+        });
+        self.inner.ready.notify_all();
+
+        Ok(())
+    }
+
+    fn wait_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<OperationalResult>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state();
+
+        loop {
+            let mut results = Vec::new();
+            while results.len() < size {
+                match state.step() {
+                    Some(result) => results.push(result),
+                    None => break,
+                }
+            }
+
+            if !results.is_empty() {
+                return Ok(results);
+            }
+
+            state = match deadline {
+                None => self.inner.ready.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::from_raw_os_error(code::TIMED_OUT));
+                    }
+                    self.inner
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+
+    fn post(&self, result: OperationalResult) -> Result<()> {
+        self.state().pending.push(Pending::Posted(result));
+        self.inner.ready.notify_all();
+
+        Ok(())
+    }
+
+    fn cancel(&self, context: &Context) -> Result<()> {
+        let mut state = self.state();
+        let index = state
+            .pending
+            .iter()
+            .position(|pending| matches!(pending, Pending::Op { id, .. } if *id == context.id()));
+
+        if let Some(index) = index {
+            if let Pending::Op { id, token, op, .. } = state.pending.remove(index) {
+                let result =
+                    OperationalResult::new(token, op.offset(), 0, code::CANCELED).with_id(id);
+                state.pending.insert(index, Pending::Posted(result));
+            }
+            self.inner.ready.notify_all();
+        }
+
+        Ok(())
+    }
+}
+
+/// An in-memory file of a [`SimPort`].
+pub struct SimFile {
+    handle: RawHandle,
+    port: SimPort,
+}
+
+impl SimFile {
+    /// The current contents of the file.
+    pub fn contents(&self) -> Vec<u8> {
+        match self.port.state().objects.get(&self.handle) {
+            Some(Object::File(data)) => data.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl AsHandle for SimFile {
+    fn as_handle(&self) -> RawHandle {
+        self.handle
+    }
+}
+
+impl FileExt for SimFile {}
+
+impl Drop for SimFile {
+    fn drop(&mut self) {
+        self.port.remove(self.handle);
+    }
+}
+
+/// One end of a loopback socket pair of a [`SimPort`].
+/// Dropping it closes the connection, receives on the peer then complete with 0 bytes.
+pub struct SimSocket {
+    handle: RawHandle,
+    port: SimPort,
+}
+
+impl AsHandle for SimSocket {
+    fn as_handle(&self) -> RawHandle {
+        self.handle
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawSocket for SimSocket {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.handle
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for SimSocket {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.handle as std::os::windows::io::RawSocket
+    }
+}
+
+impl TcpStreamExt for SimSocket {}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.port.remove(self.handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{fs::FileExt, net::TcpStreamExt, CompletionPort, OperationalResult};
+
+    use super::{code, SimPort};
+
+    fn order(seed: u64) -> Vec<usize> {
+        let sim = SimPort::new(seed);
+        sim.set_reordering(true);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let mut file = sim.file(vec![0; 64]);
+        cmp.add(0, &file).unwrap();
+
+        for token in 0..8 {
+            cmp.post(OperationalResult::new(token, 0, 0, 0)).unwrap();
+            file.read_at(vec![0; 4], token as u64).unwrap();
+        }
+
+        let mut tokens = Vec::new();
+        while tokens.len() < 16 {
+            for completion in cmp.get_many(3, None).unwrap() {
+                tokens.push(completion.token());
+            }
+        }
+        tokens
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        assert_eq!(order(1), order(1));
+        assert_eq!(order(99), order(99));
+        assert_ne!(order(1), order(2));
+    }
+
+    #[test]
+    fn partial_transfers() {
+        let sim = SimPort::new(3);
+        sim.set_partial_transfers(true);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let mut file = sim.file(b"0123456789".to_vec());
+        cmp.add(1, &file).unwrap();
+
+        let mut read = Vec::new();
+        while read.len() < 10 {
+            file.read_at(vec![0; 10], read.len() as u64).unwrap();
+            let completion = cmp.get(None).unwrap();
+            assert!(completion.bytes_used() > 0);
+            read.extend_from_slice(completion.data());
+        }
+        assert_eq!(read, b"0123456789");
+
+        file.write_at(b"abcdef".to_vec(), 10).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert!(completion.bytes_used() <= 6);
+        assert_eq!(file.contents().len(), 10 + completion.bytes_used() as usize);
+    }
+
+    #[test]
+    fn injected_error() {
+        let sim = SimPort::new(0);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let mut file = sim.file(b"abc".to_vec());
+        cmp.add(1, &file).unwrap();
+        sim.inject_error(&file, 5);
+
+        let id = file.read(vec![0; 3]).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert_eq!(completion.status().unwrap_err().raw_os_error(), Some(5));
+        assert!(!completion.result().is_cancelled());
+
+        file.read(vec![0; 3]).unwrap();
+        assert_eq!(cmp.get(None).unwrap().data(), b"abc");
+    }
+
+    #[test]
+    fn socket_pair() {
+        let sim = SimPort::new(0);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+        cmp.add(2, &b).unwrap();
+
+        let id = a.read(vec![0; 8]).unwrap();
+        let ret = cmp.get(Some(Duration::from_millis(10)));
+        assert_eq!(
+            ret.err().and_then(|e| e.raw_os_error()),
+            Some(code::TIMED_OUT)
+        );
+
+        TcpStreamExt::write(&b, b"ping".to_vec()).unwrap();
+        let mut tokens = Vec::new();
+        while tokens.len() < 2 {
+            for completion in cmp.get_many(2, None).unwrap() {
+                if completion.token() == 1 {
+                    assert_eq!(completion.id(), Some(id));
+                    assert_eq!(completion.data(), b"ping");
+                }
+                tokens.push(completion.token());
+            }
+        }
+        assert_eq!(tokens, [2, 1]);
+
+        drop(b);
+        a.read(vec![0; 8]).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.status().unwrap(), 0);
+        assert!(!completion.result().is_cancelled());
+    }
+
+    #[test]
+    fn cancel() {
+        let sim = SimPort::new(0);
+        let cmp = CompletionPort::with_driver(sim.clone());
+        let (mut a, _b) = sim.socket_pair();
+        cmp.add(1, &a).unwrap();
+
+        let id = a.read(vec![0; 8]).unwrap();
+        cmp.cancel(id).unwrap();
+        let completion = cmp.get(None).unwrap();
+        assert_eq!(completion.id(), Some(id));
+        assert!(completion.result().is_cancelled());
+        assert_eq!(
+            completion.status().unwrap_err().raw_os_error(),
+            Some(code::CANCELED)
+        );
+    }
+}
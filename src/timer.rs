@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Bits of the deadline each level of the wheel covers.
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+/// Six levels of 64 one millisecond slots span a little over two years, later deadlines
+/// are kept in the last level and cascade down as it turns.
+const LEVELS: usize = 6;
+const MAX_TICKS: u64 = (1 << (LEVEL_BITS as usize * LEVELS)) - 1;
+
+/// Identifies a timer from `CompletionPort::set_timer` or `CompletionPort::set_interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Timer<T> {
+    /// What the timer is for, handed back when it expires.
+    target: T,
+    /// The tick the timer fires at.
+    deadline: u64,
+    period: Option<u64>,
+    /// Where the timer is filed, `None` once it expired and waits to be delivered.
+    slot: Option<(usize, usize)>,
+}
+
+struct Level {
+    /// Bit `n` is set when slot `n` holds a timer.
+    occupied: u64,
+    slots: Vec<Vec<TimerId>>,
+}
+
+/// A hierarchical timer wheel with millisecond ticks.
+///
+/// Level `n` has 64 slots of `64^n` ticks each. A timer is filed in the lowest level whose
+/// current rotation contains its deadline and moves to lower levels as time advances,
+/// so inserting, cancelling and expiring are all constant time.
+pub(crate) struct TimerWheel<T> {
+    start: Instant,
+    /// The tick the wheel has advanced to.
+    elapsed: u64,
+    levels: Vec<Level>,
+    timers: HashMap<TimerId, Timer<T>>,
+    /// Timers that fired and were not delivered yet.
+    expired: VecDeque<TimerId>,
+    next_id: u64,
+}
+
+impl<T: Copy> TimerWheel<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| Level {
+                    occupied: 0,
+                    slots: vec![Vec::new(); SLOTS],
+                })
+                .collect(),
+            timers: HashMap::new(),
+            expired: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// The tick `instant` falls in.
+    fn tick(&self, instant: Instant) -> u64 {
+        let ms = instant.saturating_duration_since(self.start).as_millis();
+        (ms as u64).min(MAX_TICKS)
+    }
+
+    /// The number of ticks in `duration`, rounded up so a timer never fires early.
+    fn ticks(duration: Duration) -> u64 {
+        (duration.as_nanos().div_ceil(1_000_000) as u64).min(MAX_TICKS)
+    }
+
+    /// Schedule a timer for `target` that fires `after` past `now`, and every `period` after that.
+    pub(crate) fn insert(
+        &mut self,
+        now: Instant,
+        target: T,
+        after: Duration,
+        period: Option<Duration>,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        let now = self.tick(now).max(self.elapsed);
+        let deadline = now + Self::ticks(after).max(1);
+        self.timers.insert(
+            id,
+            Timer {
+                target,
+                deadline,
+                period: period.map(|period| Self::ticks(period).max(1)),
+                slot: None,
+            },
+        );
+        self.file(id);
+
+        id
+    }
+
+    /// Remove a timer, `false` if it is not scheduled any more.
+    pub(crate) fn remove(&mut self, id: TimerId) -> bool {
+        let timer = match self.timers.remove(&id) {
+            Some(timer) => timer,
+            None => return false,
+        };
+
+        match timer.slot {
+            Some((level, slot)) => self.unfile(id, level, slot),
+            None => self.expired.retain(|expired| *expired != id),
+        }
+
+        true
+    }
+
+    /// The level and slot a deadline belongs in, relative to the current tick.
+    fn slot_for(&self, deadline: u64) -> (usize, usize) {
+        let deadline = deadline.max(self.elapsed);
+        // The highest bit in which the deadline differs from now picks the level.
+        let masked = (self.elapsed ^ deadline) | (SLOTS as u64 - 1);
+        let significant = 63 - masked.leading_zeros();
+        let level = ((significant / LEVEL_BITS) as usize).min(LEVELS - 1);
+        let slot = ((deadline >> (level as u32 * LEVEL_BITS)) as usize) & (SLOTS - 1);
+
+        (level, slot)
+    }
+
+    fn file(&mut self, id: TimerId) {
+        let timer = self.timers.get_mut(&id).unwrap();
+        let deadline = timer.deadline;
+
+        if deadline <= self.elapsed {
+            timer.slot = None;
+            self.expired.push_back(id);
+            return;
+        }
+
+        let (level, slot) = self.slot_for(deadline);
+        self.timers.get_mut(&id).unwrap().slot = Some((level, slot));
+        self.levels[level].slots[slot].push(id);
+        self.levels[level].occupied |= 1 << slot;
+    }
+
+    fn unfile(&mut self, id: TimerId, level: usize, slot: usize) {
+        let entries = &mut self.levels[level].slots[slot];
+        entries.retain(|entry| *entry != id);
+        if entries.is_empty() {
+            self.levels[level].occupied &= !(1 << slot);
+        }
+    }
+
+    /// The first tick at which an occupied slot has to be processed.
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        self.levels.iter().enumerate().find_map(|(level, entries)| {
+            if entries.occupied == 0 {
+                return None;
+            }
+
+            let shift = level as u32 * LEVEL_BITS;
+            let now_slot = ((self.elapsed >> shift) as usize) & (SLOTS - 1);
+            let distance = entries
+                .occupied
+                .rotate_right(now_slot as u32)
+                .trailing_zeros() as usize;
+            let slot = (now_slot + distance) % SLOTS;
+
+            let slot_range = 1u64 << shift;
+            let level_range = slot_range << LEVEL_BITS;
+            let level_start = self.elapsed & !(level_range - 1);
+            let mut tick = level_start + slot as u64 * slot_range;
+            if tick + slot_range <= self.elapsed {
+                tick += level_range;
+            }
+
+            Some((level, slot, tick.max(self.elapsed)))
+        })
+    }
+
+    /// When the earliest timer fires, `None` without timers.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if !self.expired.is_empty() {
+            return Some(self.start + Duration::from_millis(self.elapsed));
+        }
+
+        self.next_slot()
+            .map(|(_, _, tick)| self.start + Duration::from_millis(tick))
+    }
+
+    /// Advance to `now` and take at most `max` expired timers with their targets.
+    pub(crate) fn poll(&mut self, now: Instant, max: usize) -> Vec<(TimerId, T)> {
+        let now = self.tick(now);
+
+        while let Some((level, slot, tick)) = self.next_slot() {
+            if tick > now {
+                break;
+            }
+
+            self.elapsed = tick;
+            self.levels[level].occupied &= !(1 << slot);
+            // Everything in the slot expires now or moves down to a finer level.
+            for id in std::mem::take(&mut self.levels[level].slots[slot]) {
+                self.file(id);
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+
+        let count = max.min(self.expired.len());
+        let expired = self.expired.drain(..count).collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .map(|id| {
+                let timer = self.timers.get_mut(&id).unwrap();
+                let target = timer.target;
+
+                match timer.period {
+                    Some(period) => {
+                        // Skip the periods that passed while nobody was polling.
+                        let missed = (now.saturating_sub(timer.deadline)) / period;
+                        timer.deadline += (missed + 1) * period;
+                        self.file(id);
+                    }
+                    None => {
+                        self.timers.remove(&id);
+                    }
+                }
+
+                (id, target)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TimerWheel;
+
+    #[test]
+    fn fires_in_deadline_order() {
+        let mut wheel = TimerWheel::new();
+        let start = wheel.start;
+        let far = wheel.insert(start, 3, Duration::from_secs(90), None);
+        let near = wheel.insert(start, 1, Duration::from_millis(5), None);
+        let middle = wheel.insert(start, 2, Duration::from_millis(700), None);
+
+        assert!(wheel.poll(start + Duration::from_millis(4), 10).is_empty());
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(start + Duration::from_millis(5))
+        );
+        assert_eq!(
+            wheel.poll(start + Duration::from_millis(5), 10),
+            [(near, 1)]
+        );
+        assert!(wheel
+            .poll(start + Duration::from_millis(699), 10)
+            .is_empty());
+        assert_eq!(
+            wheel.poll(start + Duration::from_millis(701), 10),
+            [(middle, 2)]
+        );
+        assert!(wheel.next_deadline().unwrap() <= start + Duration::from_secs(90));
+        assert!(wheel
+            .poll(start + Duration::from_millis(89_999), 10)
+            .is_empty());
+        assert_eq!(wheel.poll(start + Duration::from_secs(90), 10), [(far, 3)]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn never_early_and_all_fire() {
+        let mut wheel = TimerWheel::new();
+        let start = wheel.start;
+        let mut seed = 7u64;
+        let mut deadlines = Vec::new();
+        for token in 0..500 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let after = Duration::from_millis(1 + (seed >> 33) % 300_000);
+            wheel.insert(start, token, after, None);
+            deadlines.push(after);
+        }
+
+        let mut now = Duration::ZERO;
+        let mut fired = 0;
+        while fired < deadlines.len() {
+            now += Duration::from_millis(997);
+            for (_, token) in wheel.poll(start + now, usize::MAX) {
+                assert!(deadlines[token] <= now);
+                assert!(now - deadlines[token] < Duration::from_millis(997));
+                fired += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn periodic_and_remove() {
+        let mut wheel = TimerWheel::new();
+        let start = wheel.start;
+        let tick = wheel.insert(
+            start,
+            1,
+            Duration::from_millis(10),
+            Some(Duration::from_millis(10)),
+        );
+        let once = wheel.insert(start, 2, Duration::from_millis(15), None);
+
+        assert_eq!(
+            wheel.poll(start + Duration::from_millis(10), 10),
+            [(tick, 1)]
+        );
+        assert!(wheel.remove(once));
+        assert!(!wheel.remove(once));
+        assert_eq!(
+            wheel.poll(start + Duration::from_millis(20), 10),
+            [(tick, 1)]
+        );
+        // Periods missed while nobody polled fire once.
+        assert_eq!(
+            wheel.poll(start + Duration::from_millis(55), 10),
+            [(tick, 1)]
+        );
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(start + Duration::from_millis(60))
+        );
+        assert!(wheel.remove(tick));
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn poll_limit() {
+        let mut wheel = TimerWheel::new();
+        let start = wheel.start;
+        for token in 0..5 {
+            wheel.insert(start, token, Duration::from_millis(1), None);
+        }
+
+        let now = start + Duration::from_millis(2);
+        assert_eq!(wheel.poll(now, 2).len(), 2);
+        assert_eq!(wheel.next_deadline(), Some(now));
+        assert_eq!(wheel.poll(now, 10).len(), 3);
+    }
+}
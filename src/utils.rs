@@ -1,9 +1,10 @@
-use std::{
-    io::{Error, Result},
-    time::Duration,
-};
+use std::io::{Error, Result};
+#[cfg(windows)]
+use std::time::Duration;
+#[cfg(windows)]
 use windows_sys::Win32::System::Threading::INFINITE;
 
+#[cfg(windows)]
 pub(crate) fn cvt(ret: i32) -> Result<i32> {
     if ret == 0 {
         Err(Error::last_os_error())
@@ -12,6 +13,37 @@ pub(crate) fn cvt(ret: i32) -> Result<i32> {
     }
 }
 
+#[cfg(target_os = "linux")]
+pub(crate) fn cvt(ret: i32) -> Result<i32> {
+    if ret == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Make `fd` nonblocking for good if it is a socket, other file descriptors are left
+/// alone.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_socket_nonblocking(fd: i32) -> Result<()> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    cvt(unsafe { libc::fstat(fd, &mut stat) })?;
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Ok(());
+    }
+
+    set_nonblocking(fd, true)
+}
+
+/// `FIONBIO` sets the flag in one call, so it cannot undo a flag another thread changed
+/// in the meantime the way `F_GETFL` and `F_SETFL` would.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_nonblocking(fd: i32, nonblocking: bool) -> Result<()> {
+    let mut nonblocking = nonblocking as libc::c_int;
+    cvt(unsafe { libc::ioctl(fd, libc::FIONBIO, &mut nonblocking) }).map(|_| ())
+}
+
+#[cfg(windows)]
 pub(crate) fn dur_to_ms(timeout: Option<Duration>) -> u32 {
     let func = |dur: Duration| -> u32 {
         dur.as_secs()
@@ -37,6 +69,7 @@ pub(crate) fn dur_to_ms(timeout: Option<Duration>) -> u32 {
     timeout.map(func).unwrap_or(INFINITE)
 }
 
+#[cfg(windows)]
 pub(crate) fn len<T>(list: &[T]) -> u32 {
     list.len().min(u32::MAX as usize) as u32
 }
@@ -0,0 +1,375 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::zeroed,
+    ptr::null_mut,
+    time::Duration,
+};
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle, RtlNtStatusToDosError, ERROR_IO_PENDING, ERROR_NOT_FOUND, HANDLE,
+        INVALID_HANDLE_VALUE,
+    },
+    Networking::WinSock::{
+        AcceptEx, TransmitFile, WSARecv, WSARecvFrom, WSASend, WSASendMsg, WSASendTo, SOCKET,
+        WSABUF, WSA_IO_PENDING,
+    },
+    Storage::FileSystem::{ReadFile, WriteFile},
+    System::IO::{
+        CancelIoEx, CreateIoCompletionPort, GetQueuedCompletionStatusEx,
+        PostQueuedCompletionStatus, OVERLAPPED, OVERLAPPED_ENTRY,
+    },
+};
+
+use crate::{
+    context::{OpState, OperationId},
+    cvt,
+    driver::{Driver, Operation},
+    len,
+    net::{
+        connect::connect_ex,
+        cvt_for_socket,
+        disconnect::{self, disconnect_ex},
+        msg::wsa_recv_msg,
+        SocketAddrCRepr,
+    },
+    utils::dur_to_ms,
+    Context, OperationalResult, RawHandle,
+};
+
+/// Set in the OVERLAPPED pointer of posted results, the rest of the pointer is their boxed
+/// `Posted`. A Context is never at an odd address.
+const POSTED_BIT: usize = 1;
+
+/// What a posted result carries besides its token and byte count, which the completion
+/// packet has room for.
+struct Posted {
+    id: Option<OperationId>,
+    status: i32,
+}
+
+/// A driver backed by a Windows I/O completion port.
+pub struct IocpDriver {
+    handle: HANDLE,
+}
+
+unsafe impl Send for IocpDriver {}
+unsafe impl Sync for IocpDriver {}
+
+impl IocpDriver {
+    /// Create a completion port with specify then concurrent.
+    pub fn new(num_threads: u32) -> Result<Self> {
+        let ret = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0, 0, num_threads) };
+
+        if ret == 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(Self { handle: ret })
+        }
+    }
+}
+
+impl Driver for IocpDriver {
+    fn register(&self, handle: RawHandle, token: usize) -> Result<()> {
+        let ret = unsafe { CreateIoCompletionPort(handle, self.handle, token, 0) };
+
+        if ret == 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn submit(&self, token: usize, op: Operation, context: &mut Context) -> Result<()> {
+        let offset = context.offset();
+        context.over_lapped = unsafe { zeroed::<OVERLAPPED>() };
+        context.over_lapped.Anonymous.Anonymous.Offset = (offset & (u32::MAX as u64)) as u32;
+        context.over_lapped.Anonymous.Anonymous.OffsetHigh = (offset >> 32) as u32;
+
+        let handle = context.handle();
+        let socket = handle as SOCKET;
+        let wsa_buf = WSABUF {
+            len: context.buff.len(),
+            buf: context.buff.as_mut_ptr(),
+        };
+        // A vectored Context brings its own `WSABUF` array.
+        let (wsa_bufs, wsa_buf_count) = match &mut context.vectored {
+            Some(vectored) => (vectored.as_mut_ptr() as *const WSABUF, vectored.len()),
+            None => (&wsa_buf as *const WSABUF, 1),
+        };
+        let over_lapped_ptr = context.over_lapped_ptr();
+        let mut bytes_used = 0;
+        let mut flags = 0;
+
+        let ret = match op {
+            Operation::Read { .. } => cvt(unsafe {
+                ReadFile(
+                    handle,
+                    wsa_buf.buf as *mut _,
+                    wsa_buf.len,
+                    null_mut(),
+                    over_lapped_ptr,
+                )
+            }),
+            Operation::Write { .. } => cvt(unsafe {
+                WriteFile(
+                    handle,
+                    wsa_buf.buf,
+                    wsa_buf.len,
+                    null_mut(),
+                    over_lapped_ptr,
+                )
+            }),
+            Operation::Recv | Operation::RecvVectored => cvt_for_socket(unsafe {
+                WSARecv(
+                    socket,
+                    wsa_bufs,
+                    wsa_buf_count,
+                    &mut bytes_used,
+                    &mut flags,
+                    over_lapped_ptr,
+                    None,
+                )
+            }),
+            Operation::RecvFrom => match &mut context.state {
+                OpState::RecvFrom(from) => {
+                    let (name, name_len) = from.name();
+                    cvt_for_socket(unsafe {
+                        WSARecvFrom(
+                            socket,
+                            wsa_bufs,
+                            wsa_buf_count,
+                            &mut bytes_used,
+                            &mut flags,
+                            name,
+                            name_len,
+                            over_lapped_ptr,
+                            None,
+                        )
+                    })
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::Send | Operation::SendVectored => cvt_for_socket(unsafe {
+                WSASend(
+                    socket,
+                    wsa_bufs,
+                    wsa_buf_count,
+                    &mut bytes_used,
+                    0,
+                    over_lapped_ptr,
+                    None,
+                )
+            }),
+            Operation::SendTo(addr) | Operation::SendToVectored(addr) => {
+                let (socket_addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+
+                cvt_for_socket(unsafe {
+                    WSASendTo(
+                        socket,
+                        wsa_bufs,
+                        wsa_buf_count,
+                        &mut bytes_used,
+                        0,
+                        &socket_addr as *const _ as *const _,
+                        addr_len,
+                        over_lapped_ptr,
+                        None,
+                    )
+                })
+            }
+            Operation::Accept => match &mut context.state {
+                OpState::Accept(accept) => {
+                    let (accept_socket, addrs, addr_len) = accept.accept_ex_args();
+                    cvt(unsafe {
+                        AcceptEx(
+                            socket,
+                            accept_socket,
+                            addrs as *mut _,
+                            0,
+                            addr_len,
+                            addr_len,
+                            &mut bytes_used,
+                            over_lapped_ptr,
+                        )
+                    })
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::Connect(addr) => {
+                let connect_ex = connect_ex(socket)?.ok_or(Error::from(ErrorKind::Unsupported))?;
+                let (socket_addr, addr_len) = SocketAddrCRepr::socket_addr_to_ptrs(&addr);
+
+                cvt(unsafe {
+                    connect_ex(
+                        socket,
+                        socket_addr.as_ptr(),
+                        addr_len,
+                        wsa_buf.buf as *const _,
+                        wsa_buf.len,
+                        &mut bytes_used,
+                        over_lapped_ptr,
+                    )
+                })
+            }
+            Operation::Disconnect { reuse } => {
+                let disconnect_ex =
+                    disconnect_ex(socket)?.ok_or(Error::from(ErrorKind::Unsupported))?;
+
+                cvt(unsafe { disconnect_ex(socket, over_lapped_ptr, disconnect::flags(reuse), 0) })
+            }
+            Operation::TransmitFile { .. } => match &mut context.state {
+                // Nothing to send, complete it without `TransmitFile`.
+                OpState::Transmit(transmit) if transmit.is_empty() => cvt(unsafe {
+                    PostQueuedCompletionStatus(self.handle, 0, token, over_lapped_ptr)
+                }),
+                OpState::Transmit(transmit) => {
+                    let (file, len, buffers) = transmit.transmit_file_args();
+                    cvt(unsafe { TransmitFile(socket, file, len, 0, over_lapped_ptr, buffers, 0) })
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::RecvMsg => match &mut context.state {
+                OpState::RecvMsg(msg) => {
+                    let wsa_recv_msg =
+                        wsa_recv_msg(socket)?.ok_or(Error::from(ErrorKind::Unsupported))?;
+                    let msg = msg.msg(wsa_buf.buf, wsa_buf.len);
+
+                    cvt_for_socket(unsafe {
+                        wsa_recv_msg(socket, msg, &mut bytes_used, over_lapped_ptr, None)
+                    })
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+            Operation::SendMsg => match &mut context.state {
+                OpState::SendMsg(msg) => {
+                    let msg = msg.msg(wsa_buf.buf, wsa_buf.len);
+
+                    cvt_for_socket(unsafe {
+                        WSASendMsg(socket, msg, 0, &mut bytes_used, over_lapped_ptr, None)
+                    })
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidInput)),
+            },
+        };
+
+        match ret {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(WSA_IO_PENDING) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn wait_many(&self, size: usize, timeout: Option<Duration>) -> Result<Vec<OperationalResult>> {
+        let mut entries = vec![unsafe { zeroed::<OVERLAPPED_ENTRY>() }; size];
+        let mut removed = 0;
+        let timeout = dur_to_ms(timeout);
+        let len = len(&entries);
+
+        let ret = unsafe {
+            GetQueuedCompletionStatusEx(
+                self.handle,
+                entries.as_mut_ptr(),
+                len,
+                &mut removed,
+                timeout,
+                0,
+            )
+        };
+
+        if ret == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        entries.truncate(removed as usize);
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let key = entry.lpOverlapped as usize;
+                if key == 0 || key & POSTED_BIT != 0 {
+                    let posted = match key {
+                        0 => Posted { id: None, status: 0 },
+                        _ => *unsafe { Box::from_raw((key & !POSTED_BIT) as *mut Posted) },
+                    };
+                    let result = OperationalResult::new(
+                        entry.lpCompletionKey,
+                        0,
+                        entry.dwNumberOfBytesTransferred,
+                        posted.status,
+                    );
+                    return match posted.id {
+                        Some(id) => result.with_id(id),
+                        None => result,
+                    };
+                }
+
+                // Every other OVERLAPPED is the first field of the Context its operation
+                // was submitted with.
+                let context = unsafe { (entry.lpOverlapped as *const Context).as_ref() };
+                let offset = context.map_or(0, Context::offset);
+                let status = if entry.Internal == 0 {
+                    0
+                } else {
+                    unsafe { RtlNtStatusToDosError(entry.Internal as i32) as i32 }
+                };
+
+                let result = OperationalResult::new(
+                    entry.lpCompletionKey,
+                    offset,
+                    entry.dwNumberOfBytesTransferred,
+                    status,
+                );
+
+                match context {
+                    Some(context) => result.with_id(context.id()),
+                    None => result,
+                }
+            })
+            .collect())
+    }
+
+    fn post(&self, result: OperationalResult) -> Result<()> {
+        let posted = match (result.id(), result.status()) {
+            (None, 0) => None,
+            (id, status) => Some(Box::into_raw(Box::new(Posted { id, status }))),
+        };
+        let over_lapped = match posted {
+            Some(posted) => (posted as usize | POSTED_BIT) as *mut OVERLAPPED,
+            None => null_mut(),
+        };
+        let ret = unsafe {
+            PostQueuedCompletionStatus(self.handle, result.bytes_used(), result.token(), over_lapped)
+        };
+
+        if ret == 0 {
+            let e = Error::last_os_error();
+            // Nothing was queued, the record is still ours.
+            if let Some(posted) = posted {
+                drop(unsafe { Box::from_raw(posted) });
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn cancel(&self, context: &Context) -> Result<()> {
+        let ret = unsafe { CancelIoEx(context.handle(), &context.over_lapped) };
+
+        match cvt(ret) {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(ERROR_NOT_FOUND as i32) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for IocpDriver {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    io::Result,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, RwLock},
+    thread::{Builder, JoinHandle},
+};
+
+use crate::{Completion, CompletionPort};
+
+/// How many completions a worker takes from the port at once.
+const BATCH: usize = 16;
+
+/// The token shutdown messages are posted to, handlers never see them.
+const SHUTDOWN_TOKEN: usize = usize::MAX;
+
+/// Posted once per worker by `WorkerPool::stop`.
+struct Shutdown;
+
+type Handler = Arc<dyn Fn(Completion) + Send + Sync>;
+type PanicHandler = Arc<dyn Fn(usize, Box<dyn Any + Send>) + Send + Sync>;
+
+#[derive(Default)]
+struct Handlers {
+    by_token: HashMap<usize, Handler>,
+    on_panic: Option<PanicHandler>,
+}
+
+/// Worker threads that take completions from a CompletionPort with `get_many` and hand
+/// each to the handler registered for its token.
+///
+/// A handler that panics is reported to `on_panic` and the worker carries on with the
+/// next completion. Completions for tokens without a handler are dropped.
+///
+/// ```
+/// use iocp_rs::{sim::SimPort, CompletionPort, WorkerPool};
+/// use std::sync::{mpsc::channel, Arc};
+///
+/// let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(0)));
+/// let pool = WorkerPool::new(cmp.clone(), 4).unwrap();
+///
+/// let (tx, rx) = channel();
+/// pool.register(1, move |completion| {
+///     tx.send(completion.into_message::<u32>().ok().unwrap()).unwrap();
+/// });
+///
+/// cmp.post_message(1, 42u32).unwrap();
+/// assert_eq!(rx.recv().unwrap(), 42);
+/// pool.stop().unwrap();
+/// ```
+pub struct WorkerPool {
+    port: Arc<CompletionPort>,
+    handlers: Arc<RwLock<Handlers>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Spawn `num_threads` workers on `port`, usually the concurrency it was created with.
+    pub fn new(port: Arc<CompletionPort>, num_threads: usize) -> Result<Self> {
+        let mut pool = Self {
+            port,
+            handlers: Arc::new(RwLock::new(Handlers::default())),
+            workers: Vec::with_capacity(num_threads),
+        };
+
+        for n in 0..num_threads {
+            let port = pool.port.clone();
+            let handlers = pool.handlers.clone();
+            let worker = Builder::new()
+                .name(format!("iocp-worker-{n}"))
+                .spawn(move || work(&port, &handlers))?;
+            pool.workers.push(worker);
+        }
+
+        Ok(pool)
+    }
+
+    /// Run `handler` on a worker for every completion for `token`, replacing the handler
+    /// registered before.
+    pub fn register<F>(&self, token: usize, handler: F)
+    where
+        F: Fn(Completion) + Send + Sync + 'static,
+    {
+        self.handlers
+            .write()
+            .unwrap()
+            .by_token
+            .insert(token, Arc::new(handler));
+    }
+
+    /// Stop dispatching completions for `token`, returns whether it had a handler.
+    pub fn unregister(&self, token: usize) -> bool {
+        self.handlers
+            .write()
+            .unwrap()
+            .by_token
+            .remove(&token)
+            .is_some()
+    }
+
+    /// Call `report` with the token and the panic payload whenever a handler panics.
+    pub fn on_panic<F>(&self, report: F)
+    where
+        F: Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.handlers.write().unwrap().on_panic = Some(Arc::new(report));
+    }
+
+    /// Let every worker finish the completions it has taken and wait for them to exit.
+    /// The workers stop at shutdown messages posted behind the completions already
+    /// queued, anything completing after those is left in the port.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        for _ in 0..self.workers.len() {
+            self.port.post_message(SHUTDOWN_TOKEN, Shutdown)?;
+        }
+
+        for worker in self.workers.drain(..) {
+            // Handler panics are caught, a worker only ends by returning.
+            let _ = worker.join();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+fn work(port: &CompletionPort, handlers: &RwLock<Handlers>) {
+    loop {
+        let completions = match port.get_many(BATCH, None) {
+            Ok(completions) => completions,
+            // Without a timeout only a broken port fails the wait.
+            Err(_) => return,
+        };
+
+        let mut shutdowns = 0;
+        for completion in completions {
+            if completion.token() == SHUTDOWN_TOKEN && completion.message::<Shutdown>().is_some() {
+                shutdowns += 1;
+                continue;
+            }
+
+            dispatch(handlers, completion);
+        }
+
+        if shutdowns > 0 {
+            // The ones meant for the other workers go back to the port.
+            for _ in 1..shutdowns {
+                let _ = port.post_message(SHUTDOWN_TOKEN, Shutdown);
+            }
+            return;
+        }
+    }
+}
+
+fn dispatch(handlers: &RwLock<Handlers>, completion: Completion) {
+    let token = completion.token();
+    let (handler, on_panic) = {
+        let handlers = handlers.read().unwrap();
+        (
+            handlers.by_token.get(&token).cloned(),
+            handlers.on_panic.clone(),
+        )
+    };
+
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return,
+    };
+
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(|| handler(completion))) {
+        if let Some(on_panic) = on_panic {
+            on_panic(token, payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread::sleep,
+        time::Duration,
+    };
+
+    use crate::{sim::SimPort, CompletionPort, OperationalResult};
+
+    use super::WorkerPool;
+
+    #[test]
+    fn dispatch_by_token() {
+        let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(1)));
+        let pool = WorkerPool::new(cmp.clone(), 3).unwrap();
+        let (tx, rx) = channel();
+
+        for token in [1, 2] {
+            let tx = tx.clone();
+            pool.register(token, move |completion| {
+                tx.send((token, completion.token(), completion.bytes_used()))
+                    .unwrap();
+            });
+        }
+
+        for n in 0..10 {
+            cmp.post(OperationalResult::new(1 + n % 2, 0, n as u32, 0))
+                .unwrap();
+        }
+        // Nobody handles token 3.
+        cmp.post(OperationalResult::new(3, 0, 0, 0)).unwrap();
+
+        let mut seen = (0..10)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        seen.sort();
+        for (n, (handler, token, _)) in seen.into_iter().enumerate() {
+            assert_eq!(handler, token);
+            assert_eq!(token, 1 + n / 5);
+        }
+
+        pool.stop().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(2)));
+        let pool = WorkerPool::new(cmp.clone(), 1).unwrap();
+        let (tx, rx) = channel();
+
+        pool.register(1, |completion| {
+            if completion.bytes_used() == 0 {
+                panic!("handler failed");
+            }
+        });
+        {
+            let tx = tx.clone();
+            pool.register(2, move |_| tx.send("handled").unwrap());
+        }
+        pool.on_panic(move |token, payload| {
+            assert_eq!(token, 1);
+            assert_eq!(*payload.downcast::<&str>().unwrap(), "handler failed");
+            tx.send("reported").unwrap();
+        });
+
+        cmp.post(OperationalResult::new(1, 0, 0, 0)).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("reported"));
+
+        // The only worker survived the panic.
+        cmp.post(OperationalResult::new(2, 0, 0, 0)).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("handled"));
+    }
+
+    #[test]
+    fn stop_after_queued_completions() {
+        let cmp = Arc::new(CompletionPort::with_driver(SimPort::new(3)));
+        let pool = WorkerPool::new(cmp.clone(), 2).unwrap();
+        let (tx, rx) = channel();
+
+        pool.register(1, move |_| {
+            sleep(Duration::from_millis(20));
+            tx.send(()).unwrap();
+        });
+
+        for _ in 0..4 {
+            cmp.post(OperationalResult::new(1, 0, 0, 0)).unwrap();
+        }
+        pool.stop().unwrap();
+
+        // Every worker exited, each after finishing what it had taken.
+        assert_eq!(rx.try_iter().count(), 4);
+    }
+}